@@ -0,0 +1,380 @@
+//! Reading the volumes of Amazon Machine Images (AMIs) and registering AMIs from virtual
+//! disks.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use aws_sdk_ec2::client::Waiters;
+use aws_sdk_ec2::config::Region;
+use aws_sdk_ec2::error::ProvideErrorMetadata;
+use aws_sdk_ec2::types::{
+    ArchitectureValues, BlockDeviceMapping, BootModeValues, EbsBlockDevice, Image, ImageState,
+    VolumeType,
+};
+use tokio::runtime::Handle;
+use tracing::{debug, info};
+
+use crate::cleanup::{CleanupGuard, Resource, OWNER_TAG};
+use crate::disk::{DiskWriter, VirtualDisk};
+use crate::ebs::{write_snapshot, EbsClient, SnapshotOptions, DEFAULT_CONCURRENCY};
+
+mod copy;
+
+pub use copy::{copy_ami, share, CopyOptions, SOURCE_TAG};
+
+// Snapshots written through the EBS direct APIs still take a while to become usable.
+const SNAPSHOT_COMPLETION_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// An EBS volume of an AMI, from one of its block device mappings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmiVolume {
+    /// Device name the volume is attached at, e.g. `/dev/xvda`.
+    pub device_name: String,
+    pub snapshot_id: String,
+    /// Whether this is the root volume the AMI boots from.
+    pub root: bool,
+}
+
+impl AmiVolume {
+    /// The device name without `/dev/`, e.g. `xvda`.
+    pub fn short_name(&self) -> &str {
+        self.device_name
+            .strip_prefix("/dev/")
+            .unwrap_or(&self.device_name)
+    }
+}
+
+/// Selects one of the EBS volumes of an AMI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VolumeSelector {
+    /// Position among the AMI's EBS volumes, counting from 0.
+    Index(usize),
+    /// Device name of the mapping, with or without `/dev/`.
+    Device(String),
+}
+
+impl FromStr for VolumeSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(index) => VolumeSelector::Index(index),
+            Err(_) => VolumeSelector::Device(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for VolumeSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VolumeSelector::Index(index) => write!(f, "volume {index}"),
+            VolumeSelector::Device(name) => write!(f, "device {name}"),
+        }
+    }
+}
+
+/// The EBS volumes of `image` in mapping order. Instance store volumes, suppressed mappings
+/// and mappings without a snapshot have no data to read and are skipped.
+pub fn image_volumes(image: &Image) -> Vec<AmiVolume> {
+    let root_device_name = image.root_device_name();
+    image
+        .block_device_mappings()
+        .iter()
+        .filter_map(|mapping| {
+            let device_name = mapping.device_name()?;
+            let Some(snapshot_id) = mapping.ebs().and_then(|ebs| ebs.snapshot_id()) else {
+                debug!("skipping {device_name}, which has no EBS snapshot");
+                return None;
+            };
+            Some(AmiVolume {
+                device_name: device_name.to_string(),
+                snapshot_id: snapshot_id.to_string(),
+                root: root_device_name == Some(device_name),
+            })
+        })
+        .collect()
+}
+
+/// Pick the volume chosen by `selector` among `volumes`, or the root volume without one.
+pub fn select_volume<'a>(
+    volumes: &'a [AmiVolume],
+    selector: Option<&VolumeSelector>,
+) -> Result<&'a AmiVolume> {
+    let found = match selector {
+        None => volumes.iter().find(|v| v.root).or(volumes.first()),
+        Some(VolumeSelector::Index(index)) => volumes.get(*index),
+        Some(VolumeSelector::Device(name)) => {
+            let name = name.strip_prefix("/dev/").unwrap_or(name);
+            volumes.iter().find(|v| v.short_name() == name)
+        }
+    };
+    match (found, selector) {
+        (Some(volume), _) => Ok(volume),
+        (None, Some(selector)) if !volumes.is_empty() => {
+            let available: Vec<&str> = volumes.iter().map(|v| v.device_name.as_str()).collect();
+            bail!("{selector} not found among {}", available.join(", "))
+        }
+        (None, _) => bail!("no EBS volumes found"),
+    }
+}
+
+/// Check that `ami_id` looks like an AMI ID: `ami-` and 8 or 17 lowercase hex digits.
+pub fn validate_ami_id(ami_id: &str) -> Result<()> {
+    let digits = ami_id.strip_prefix("ami-").unwrap_or_default();
+    ensure!(
+        matches!(digits.len(), 8 | 17)
+            && digits
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)),
+        "invalid AMI ID {ami_id:?}, expected ami- followed by 8 or 17 hexadecimal digits"
+    );
+    Ok(())
+}
+
+/// Describe `ami_id`, which must be available in the client's region.
+///
+/// Fails with an explanation for deregistered, disabled, pending or failed AMIs, and for
+/// AMIs found in another region than the client's.
+pub async fn describe_ami(ec2_client: &aws_sdk_ec2::Client, ami_id: &str) -> Result<Image> {
+    validate_ami_id(ami_id)?;
+    let Some(image) = find_image(ec2_client, ami_id).await? else {
+        return Err(not_found(ec2_client, ami_id).await);
+    };
+    let reason = image
+        .state_reason()
+        .and_then(|r| r.message())
+        .map(|message| format!(": {message}"))
+        .unwrap_or_default();
+    match image.state() {
+        Some(ImageState::Available) | None => Ok(image),
+        Some(ImageState::Deregistered) => bail!("AMI {ami_id} has been deregistered"),
+        Some(ImageState::Disabled) => {
+            bail!("AMI {ami_id} is disabled; re-enable it with EnableImage to use it")
+        }
+        Some(ImageState::Pending) => {
+            bail!("AMI {ami_id} is still being created; try again once it is available")
+        }
+        Some(state) => bail!("AMI {ami_id} is {state}{reason}"),
+    }
+}
+
+async fn find_image(ec2_client: &aws_sdk_ec2::Client, ami_id: &str) -> Result<Option<Image>> {
+    let result = ec2_client
+        .describe_images()
+        .image_ids(ami_id)
+        .include_disabled(true)
+        .send()
+        .await;
+    match result {
+        Ok(output) => Ok(output.images.unwrap_or_default().into_iter().next()),
+        Err(e) if e.code() == Some("InvalidAMIID.NotFound") => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to describe {ami_id}")),
+    }
+}
+
+/// Why `ami_id` is not in the client's region: AMIs are regional, so look for it in the
+/// other enabled regions.
+async fn not_found(ec2_client: &aws_sdk_ec2::Client, ami_id: &str) -> anyhow::Error {
+    let config = ec2_client.config();
+    let region = config
+        .region()
+        .map_or("this region".to_string(), |r| r.to_string());
+    let regions = match ec2_client.describe_regions().send().await {
+        Ok(output) => output.regions.unwrap_or_default(),
+        Err(e) => {
+            debug!("failed to list regions: {e}");
+            Vec::new()
+        }
+    };
+    for other in regions.iter().filter_map(|r| r.region_name()) {
+        if other == region {
+            continue;
+        }
+        let conf = config
+            .to_builder()
+            .region(Region::new(other.to_string()))
+            .build();
+        if let Ok(Some(_)) = find_image(&aws_sdk_ec2::Client::from_conf(conf), ami_id).await {
+            return anyhow!(
+                "AMI {ami_id} is in {other}, not {region}; AMIs are regional, so set \
+                 AWS_REGION={other} or copy it to {region} with `vmi copy ami`"
+            );
+        }
+    }
+    anyhow!(
+        "AMI {ami_id} not found in {region}; it may have been deregistered or not be shared \
+         with this account"
+    )
+}
+
+/// The EBS volumes of `ami_id`, as listed by [`image_volumes`].
+pub async fn describe_ami_volumes(
+    ec2_client: &aws_sdk_ec2::Client,
+    ami_id: &str,
+) -> Result<Vec<AmiVolume>> {
+    Ok(image_volumes(&describe_ami(ec2_client, ami_id).await?))
+}
+
+/// CPU architecture of a registered AMI.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Architecture {
+    #[default]
+    X86_64,
+    Arm64,
+}
+
+/// Firmware a registered AMI boots with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    LegacyBios,
+    Uefi,
+    /// UEFI on instance types that support it, legacy BIOS otherwise.
+    UefiPreferred,
+}
+
+/// Options of an AMI registered from a disk, whose name is given as the output path.
+#[derive(Debug, Clone)]
+pub struct AmiOptions {
+    pub architecture: Architecture,
+    /// Boot mode of the AMI, or the instance type's default.
+    pub boot_mode: Option<BootMode>,
+    /// Enable Elastic Network Adapter (ENA) networking.
+    pub ena_support: bool,
+    /// Enable enhanced networking with the Intel 82599 VF interface (SR-IOV).
+    pub sriov_net_support: bool,
+    pub root_device_name: String,
+    pub description: Option<String>,
+    /// Endpoint of the EBS direct APIs, instead of the regional one.
+    pub ebs_endpoint: Option<String>,
+    /// Number of snapshot blocks uploaded in parallel.
+    pub concurrency: usize,
+}
+
+impl Default for AmiOptions {
+    fn default() -> Self {
+        AmiOptions {
+            architecture: Architecture::default(),
+            boot_mode: None,
+            ena_support: true,
+            sriov_net_support: false,
+            root_device_name: "/dev/xvda".to_string(),
+            description: None,
+            ebs_endpoint: None,
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+}
+
+impl DiskWriter for AmiOptions {
+    fn write_disk(&self, disk: &mut dyn VirtualDisk, path: &Path) -> Result<()> {
+        let name = path.to_str().context("AMI name is not valid UTF-8")?;
+        write_ami(disk, name, self)?;
+        Ok(())
+    }
+}
+
+/// Upload the guest disk exposed by `disk` to a new EBS snapshot and register it as the
+/// root volume of a new AMI called `name`, returning the AMI ID.
+///
+/// The snapshot carries the `vmi` ownership tag until the AMI is registered, and is deleted
+/// if registration fails. Like [`write_snapshot`], this must be called outside of async
+/// code on a tokio runtime.
+pub fn write_ami<D: VirtualDisk + ?Sized>(
+    disk: &mut D,
+    name: &str,
+    options: &AmiOptions,
+) -> Result<String> {
+    let runtime = Handle::try_current().context("writing AMIs requires a tokio runtime")?;
+    let config = runtime.block_on(aws_config::load_from_env());
+    let client = EbsClient::from_conf(&config, options.ebs_endpoint.as_deref())?;
+    let snapshot_options = SnapshotOptions {
+        description: Some(
+            options
+                .description
+                .clone()
+                .unwrap_or_else(|| format!("Root volume of {name}")),
+        ),
+        tags: vec![(OWNER_TAG.to_string(), name.to_string())],
+        concurrency: options.concurrency,
+    };
+    let snapshot_id = write_snapshot(disk, &client, &snapshot_options)?;
+    let ec2_client = aws_sdk_ec2::Client::new(&config);
+    let cleanup = CleanupGuard::new(ec2_client.clone());
+    cleanup.track(Resource::Snapshot(snapshot_id.clone()));
+    runtime.block_on(async {
+        match register_ami(&ec2_client, &snapshot_id, name, options).await {
+            Ok(image_id) => {
+                cleanup.keep().await?;
+                Ok(image_id)
+            }
+            Err(e) => {
+                cleanup.cleanup().await;
+                Err(e)
+            }
+        }
+    })
+}
+
+/// Register an AMI called `name` whose root volume is created from `snapshot_id`, once the
+/// snapshot is complete.
+pub async fn register_ami(
+    ec2_client: &aws_sdk_ec2::Client,
+    snapshot_id: &str,
+    name: &str,
+    options: &AmiOptions,
+) -> Result<String> {
+    info!(
+        "waiting up-to {} seconds for snapshot {snapshot_id} to complete",
+        SNAPSHOT_COMPLETION_TIMEOUT.as_secs()
+    );
+    ec2_client
+        .wait_until_snapshot_completed()
+        .snapshot_ids(snapshot_id)
+        .wait(SNAPSHOT_COMPLETION_TIMEOUT)
+        .await
+        .with_context(|| format!("snapshot {snapshot_id} did not complete"))?;
+
+    let root = BlockDeviceMapping::builder()
+        .device_name(&options.root_device_name)
+        .ebs(
+            EbsBlockDevice::builder()
+                .snapshot_id(snapshot_id)
+                .volume_type(VolumeType::Gp3)
+                .delete_on_termination(true)
+                .build(),
+        )
+        .build();
+    let mut request = ec2_client
+        .register_image()
+        .name(name)
+        .architecture(match options.architecture {
+            Architecture::X86_64 => ArchitectureValues::X8664,
+            Architecture::Arm64 => ArchitectureValues::Arm64,
+        })
+        .virtualization_type("hvm")
+        .root_device_name(&options.root_device_name)
+        .block_device_mappings(root)
+        .ena_support(options.ena_support)
+        .set_description(options.description.clone());
+    if let Some(boot_mode) = options.boot_mode {
+        request = request.boot_mode(match boot_mode {
+            BootMode::LegacyBios => BootModeValues::LegacyBios,
+            BootMode::Uefi => BootModeValues::Uefi,
+            BootMode::UefiPreferred => BootModeValues::UefiPreferred,
+        });
+    }
+    if options.sriov_net_support {
+        request = request.sriov_net_support("simple");
+    }
+    let image_id = request
+        .send()
+        .await
+        .with_context(|| format!("failed to register AMI {name}"))?
+        .image_id
+        .context("RegisterImage returned no image ID")?;
+    info!("registered {image_id} ({name}) from snapshot {snapshot_id}");
+    Ok(image_id)
+}
@@ -0,0 +1,284 @@
+//! Copying AMIs to other regions and sharing the copies with other accounts.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context, Result};
+use aws_sdk_ec2::config::Region;
+use aws_sdk_ec2::types::{
+    Filter, Image, ImageState, LaunchPermission, LaunchPermissionModifications, OperationType,
+    ResourceType, SnapshotAttributeName, Tag, TagSpecification,
+};
+use tokio::task::JoinSet;
+use tracing::info;
+
+use super::{describe_ami, image_volumes};
+
+/// Tag marking a copy with the region and ID of its source, e.g. `us-east-1/ami-12345678`.
+/// Copies are found by it when a copy is resumed.
+pub const SOURCE_TAG: &str = "vmi:source-image";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Options of copies of an AMI.
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    /// Name of the copies, instead of the source's.
+    pub name: Option<String>,
+    /// KMS key the snapshots of the copies are encrypted with, as available in each region.
+    pub kms_key_id: Option<String>,
+    /// Copy the user-defined tags of the source to the copies.
+    pub copy_tags: bool,
+    /// Accounts allowed to launch the copies and create volumes from their snapshots.
+    pub share_with: Vec<String>,
+    /// How long to wait for a copy to become available.
+    pub timeout: Duration,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            name: None,
+            kms_key_id: None,
+            copy_tags: true,
+            share_with: Vec::new(),
+            timeout: Duration::from_secs(6 * 60 * 60),
+        }
+    }
+}
+
+/// Copy `ami_id`, from the region of `ec2_client`, to each of `regions`, returning the IDs
+/// of the copies in the same order.
+///
+/// All copies are started before waiting for them, and they are shared once available. A
+/// region already holding a copy of the same source, complete or not, reuses it, so an
+/// interrupted run picks up where it left off.
+pub async fn copy_ami(
+    ec2_client: &aws_sdk_ec2::Client,
+    ami_id: &str,
+    regions: &[String],
+    options: &CopyOptions,
+) -> Result<Vec<String>> {
+    let source = describe_ami(ec2_client, ami_id).await?;
+    let source_region = ec2_client
+        .config()
+        .region()
+        .context("no region configured for the source AMI")?
+        .to_string();
+    let source_tag = format!("{source_region}/{ami_id}");
+
+    let mut copies = Vec::new();
+    for region in regions {
+        ensure!(*region != source_region, "{ami_id} is already in {region}");
+        let conf = ec2_client
+            .config()
+            .to_builder()
+            .region(Region::new(region.clone()))
+            .build();
+        let client = aws_sdk_ec2::Client::from_conf(conf);
+        let image_id = match find_copy(&client, &source_tag).await? {
+            Some(image_id) => {
+                info!("resuming copy {image_id} of {ami_id} in {region}");
+                image_id
+            }
+            None => start_copy(&client, &source, &source_region, &source_tag, options).await?,
+        };
+        copies.push((region.clone(), client, image_id));
+    }
+
+    let mut waits = JoinSet::new();
+    for (index, (region, client, image_id)) in copies.into_iter().enumerate() {
+        let (ami_id, options) = (ami_id.to_string(), options.clone());
+        waits.spawn(async move {
+            wait_for_copy(&client, &image_id, options.timeout)
+                .await
+                .with_context(|| format!("copy {image_id} of {ami_id} in {region} failed"))?;
+            if !options.share_with.is_empty() {
+                share(&client, &image_id, &options.share_with)
+                    .await
+                    .with_context(|| format!("failed to share {image_id} in {region}"))?;
+            }
+            anyhow::Ok((index, image_id))
+        });
+    }
+    let mut image_ids = vec![String::new(); regions.len()];
+    while let Some(result) = waits.join_next().await {
+        let (index, image_id) = result??;
+        image_ids[index] = image_id;
+    }
+    Ok(image_ids)
+}
+
+/// The copy of the source tagged with `source_tag`, unless it failed or was deregistered.
+async fn find_copy(client: &aws_sdk_ec2::Client, source_tag: &str) -> Result<Option<String>> {
+    let output = client
+        .describe_images()
+        .owners("self")
+        .filters(
+            Filter::builder()
+                .name(format!("tag:{SOURCE_TAG}"))
+                .values(source_tag)
+                .build(),
+        )
+        .send()
+        .await
+        .context("failed to look for earlier copies")?;
+    Ok(output
+        .images()
+        .iter()
+        .filter(|image| {
+            matches!(
+                image.state(),
+                Some(ImageState::Available | ImageState::Pending)
+            )
+        })
+        .find_map(|image| image.image_id().map(str::to_string)))
+}
+
+async fn start_copy(
+    client: &aws_sdk_ec2::Client,
+    source: &Image,
+    source_region: &str,
+    source_tag: &str,
+    options: &CopyOptions,
+) -> Result<String> {
+    let ami_id = source.image_id().unwrap_or_default();
+    let name = options.name.as_deref().or(source.name()).unwrap_or(ami_id);
+    let tag = Tag::builder().key(SOURCE_TAG).value(source_tag).build();
+    let image_id = client
+        .copy_image()
+        .source_image_id(ami_id)
+        .source_region(source_region)
+        .name(name)
+        .set_description(source.description().map(str::to_string))
+        .copy_image_tags(options.copy_tags)
+        .set_encrypted(options.kms_key_id.is_some().then_some(true))
+        .set_kms_key_id(options.kms_key_id.clone())
+        .tag_specifications(
+            TagSpecification::builder()
+                .resource_type(ResourceType::Image)
+                .tags(tag)
+                .build(),
+        )
+        .send()
+        .await
+        .with_context(|| format!("failed to copy {ami_id}"))?
+        .image_id
+        .context("CopyImage returned no image ID")?;
+    info!("copying {ami_id} to {image_id}");
+    Ok(image_id)
+}
+
+/// Wait until `image_id` is available, logging the progress of its snapshots.
+async fn wait_for_copy(
+    client: &aws_sdk_ec2::Client,
+    image_id: &str,
+    timeout: Duration,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut last_progress = String::new();
+    loop {
+        let output = client
+            .describe_images()
+            .image_ids(image_id)
+            .send()
+            .await
+            .with_context(|| format!("failed to describe {image_id}"))?;
+        let image = output
+            .images()
+            .first()
+            .with_context(|| format!("{image_id} disappeared"))?;
+        match image.state() {
+            Some(ImageState::Available) => return Ok(()),
+            Some(ImageState::Pending) => {}
+            state => {
+                let reason = image.state_reason().and_then(|r| r.message());
+                bail!(
+                    "{image_id} is {}: {}",
+                    state.map_or("unknown".to_string(), |s| s.to_string()),
+                    reason.unwrap_or("no reason given")
+                );
+            }
+        }
+        let progress = snapshot_progress(client, image).await?;
+        if progress != last_progress {
+            info!("{image_id}: {progress}");
+            last_progress = progress;
+        }
+        if start.elapsed() > timeout {
+            bail!(
+                "{image_id} did not become available within {} minutes",
+                timeout.as_secs() / 60
+            );
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// The progress of the snapshots of a pending copy, e.g. `snap-1: 45%, snap-2: 80%`.
+async fn snapshot_progress(client: &aws_sdk_ec2::Client, image: &Image) -> Result<String> {
+    let snapshot_ids: Vec<String> = image_volumes(image)
+        .into_iter()
+        .map(|v| v.snapshot_id)
+        .collect();
+    if snapshot_ids.is_empty() {
+        return Ok("pending".to_string());
+    }
+    let output = client
+        .describe_snapshots()
+        .set_snapshot_ids(Some(snapshot_ids))
+        .send()
+        .await
+        .context("failed to describe snapshots")?;
+    let progress: Vec<String> = output
+        .snapshots()
+        .iter()
+        .map(|s| {
+            format!(
+                "{}: {}",
+                s.snapshot_id().unwrap_or_default(),
+                s.progress().unwrap_or("0%")
+            )
+        })
+        .collect();
+    Ok(progress.join(", "))
+}
+
+/// Allow `accounts` to launch `image_id` and create volumes from its snapshots.
+pub async fn share(
+    client: &aws_sdk_ec2::Client,
+    image_id: &str,
+    accounts: &[String],
+) -> Result<()> {
+    let image = describe_ami(client, image_id).await?;
+    let permissions = accounts
+        .iter()
+        .fold(LaunchPermissionModifications::builder(), |m, account| {
+            m.add(LaunchPermission::builder().user_id(account).build())
+        })
+        .build();
+    client
+        .modify_image_attribute()
+        .image_id(image_id)
+        .launch_permission(permissions)
+        .send()
+        .await
+        .context("failed to add launch permissions")?;
+    for volume in image_volumes(&image) {
+        client
+            .modify_snapshot_attribute()
+            .snapshot_id(&volume.snapshot_id)
+            .attribute(SnapshotAttributeName::CreateVolumePermission)
+            .operation_type(OperationType::Add)
+            .set_user_ids(Some(accounts.to_vec()))
+            .send()
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to add create volume permissions to {}",
+                    volume.snapshot_id
+                )
+            })?;
+    }
+    info!("shared {image_id} with {}", accounts.join(", "));
+    Ok(())
+}
@@ -0,0 +1,262 @@
+//! Tracking the cloud resources `vmi` creates, so they are removed when a run fails or is
+//! interrupted, and garbage collecting the ones left behind by runs that could not clean up.
+//!
+//! Every volume and snapshot `vmi` creates carries the [`OWNER_TAG`] tag until it is handed
+//! over to the user, which is how `vmi gc` finds them.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use aws_sdk_ec2::client::Waiters;
+use aws_sdk_ec2::types::{Filter, ResourceType, Tag, TagSpecification, VolumeState};
+use aws_smithy_types::DateTime;
+use tracing::{info, warn};
+
+/// Key of the tag marking resources owned by `vmi`. Its value says what they were made from.
+pub const OWNER_TAG: &str = "vmi";
+
+const DETACH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A resource created by `vmi`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    Volume(String),
+    /// A volume attached to an instance.
+    Attachment {
+        volume_id: String,
+        instance_id: String,
+    },
+    Snapshot(String),
+    /// A symlink to an attached volume's device.
+    Symlink(PathBuf),
+}
+
+/// The owner tag with `value`, for resources of `resource_type` created with it.
+pub fn owner_tags(resource_type: ResourceType, value: &str) -> TagSpecification {
+    TagSpecification::builder()
+        .resource_type(resource_type)
+        .tags(Tag::builder().key(OWNER_TAG).value(value).build())
+        .build()
+}
+
+/// Resources created during a run, removed in reverse order unless they are kept.
+///
+/// Clones share the same resources, so one can be handed to an interrupt handler.
+#[derive(Debug, Clone)]
+pub struct CleanupGuard {
+    ec2_client: aws_sdk_ec2::Client,
+    resources: Arc<Mutex<Vec<Resource>>>,
+}
+
+impl CleanupGuard {
+    pub fn new(ec2_client: aws_sdk_ec2::Client) -> Self {
+        CleanupGuard {
+            ec2_client,
+            resources: Arc::default(),
+        }
+    }
+
+    /// A guard using the AWS configuration of the environment.
+    pub async fn from_env() -> Self {
+        Self::new(aws_sdk_ec2::Client::new(&aws_config::load_from_env().await))
+    }
+
+    pub fn track(&self, resource: Resource) {
+        self.resources.lock().unwrap().push(resource);
+    }
+
+    /// Remove the tracked resources when the process receives SIGINT, then exit.
+    pub fn cleanup_on_interrupt(&self) {
+        let guard = self.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                warn!("interrupted, removing created resources");
+                guard.cleanup().await;
+                std::process::exit(130);
+            }
+        });
+    }
+
+    /// Hand the tracked resources over to the user: forget them and drop their owner tags,
+    /// so `vmi gc` leaves them alone.
+    pub async fn keep(&self) -> Result<()> {
+        let resources = std::mem::take(&mut *self.resources.lock().unwrap());
+        let ids: Vec<String> = resources
+            .into_iter()
+            .filter_map(|r| match r {
+                Resource::Volume(id) | Resource::Snapshot(id) => Some(id),
+                _ => None,
+            })
+            .collect();
+        if !ids.is_empty() {
+            self.ec2_client
+                .delete_tags()
+                .set_resources(Some(ids))
+                .tags(Tag::builder().key(OWNER_TAG).build())
+                .send()
+                .await
+                .context("failed to remove the vmi tag")?;
+        }
+        Ok(())
+    }
+
+    /// Remove the tracked resources, newest first. Failures are logged, as cleanup usually
+    /// runs while another error is being reported.
+    pub async fn cleanup(&self) {
+        loop {
+            let Some(resource) = self.resources.lock().unwrap().pop() else {
+                return;
+            };
+            if let Err(e) = self.remove(&resource).await {
+                warn!("failed to remove {resource:?}: {e:#}");
+            }
+        }
+    }
+
+    async fn remove(&self, resource: &Resource) -> Result<()> {
+        match resource {
+            Resource::Volume(volume_id) => delete_volume(&self.ec2_client, volume_id).await,
+            Resource::Attachment {
+                volume_id,
+                instance_id,
+            } => detach_volume(&self.ec2_client, volume_id, Some(instance_id)).await,
+            Resource::Snapshot(snapshot_id) => delete_snapshot(&self.ec2_client, snapshot_id).await,
+            Resource::Symlink(path) => {
+                info!("removing {}", path.display());
+                std::fs::remove_file(path)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn detach_volume(
+    ec2_client: &aws_sdk_ec2::Client,
+    volume_id: &str,
+    instance_id: Option<&str>,
+) -> Result<()> {
+    info!("detaching volume {volume_id}");
+    ec2_client
+        .detach_volume()
+        .volume_id(volume_id)
+        .set_instance_id(instance_id.map(str::to_string))
+        .send()
+        .await?;
+    ec2_client
+        .wait_until_volume_available()
+        .volume_ids(volume_id)
+        .wait(DETACH_TIMEOUT)
+        .await?;
+    Ok(())
+}
+
+async fn delete_volume(ec2_client: &aws_sdk_ec2::Client, volume_id: &str) -> Result<()> {
+    info!("deleting volume {volume_id}");
+    ec2_client
+        .delete_volume()
+        .volume_id(volume_id)
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn delete_snapshot(ec2_client: &aws_sdk_ec2::Client, snapshot_id: &str) -> Result<()> {
+    info!("deleting snapshot {snapshot_id}");
+    ec2_client
+        .delete_snapshot()
+        .snapshot_id(snapshot_id)
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Remove the volumes and snapshots carrying the owner tag that were created more than
+/// `older_than` ago, returning their IDs. Attached volumes are detached first; snapshots
+/// backing an AMI are left alone. With `dry_run`, only list them.
+pub async fn gc(
+    ec2_client: &aws_sdk_ec2::Client,
+    older_than: Duration,
+    dry_run: bool,
+) -> Result<Vec<String>> {
+    let cutoff = DateTime::from(SystemTime::now() - older_than);
+    let is_stale = |created: Option<&DateTime>| created.is_some_and(|t| t.secs() < cutoff.secs());
+    let tagged = Filter::builder().name("tag-key").values(OWNER_TAG).build();
+    let mut removed = Vec::new();
+
+    let volumes = ec2_client
+        .describe_volumes()
+        .filters(tagged.clone())
+        .into_paginator()
+        .items()
+        .send()
+        .try_collect()
+        .await
+        .context("failed to list vmi volumes")?;
+    for volume in volumes {
+        let (Some(volume_id), true) = (volume.volume_id(), is_stale(volume.create_time())) else {
+            continue;
+        };
+        if volume.state() == Some(&VolumeState::Deleting) {
+            continue;
+        }
+        removed.push(volume_id.to_string());
+        if dry_run {
+            continue;
+        }
+        let result = async {
+            if volume.state() == Some(&VolumeState::InUse) {
+                detach_volume(ec2_client, volume_id, None).await?;
+            }
+            delete_volume(ec2_client, volume_id).await
+        };
+        if let Err(e) = result.await {
+            warn!("failed to remove volume {volume_id}: {e:#}");
+            removed.pop();
+        }
+    }
+
+    // Snapshots registered as AMIs are still wanted, whatever their tags.
+    let images = ec2_client
+        .describe_images()
+        .owners("self")
+        .send()
+        .await
+        .context("failed to list AMIs")?;
+    let in_use: HashSet<&str> = images
+        .images()
+        .iter()
+        .flat_map(|image| image.block_device_mappings())
+        .filter_map(|mapping| mapping.ebs()?.snapshot_id())
+        .collect();
+    let snapshots = ec2_client
+        .describe_snapshots()
+        .owner_ids("self")
+        .filters(tagged)
+        .into_paginator()
+        .items()
+        .send()
+        .try_collect()
+        .await
+        .context("failed to list vmi snapshots")?;
+    for snapshot in snapshots {
+        let (Some(snapshot_id), true) = (snapshot.snapshot_id(), is_stale(snapshot.start_time()))
+        else {
+            continue;
+        };
+        if in_use.contains(snapshot_id) {
+            continue;
+        }
+        removed.push(snapshot_id.to_string());
+        if dry_run {
+            continue;
+        }
+        if let Err(e) = delete_snapshot(ec2_client, snapshot_id).await {
+            warn!("failed to remove snapshot {snapshot_id}: {e:#}");
+            removed.pop();
+        }
+    }
+    Ok(removed)
+}
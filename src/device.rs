@@ -0,0 +1,54 @@
+//! Writing guest disk contents to block devices on the host.
+
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use tracing::info;
+
+use crate::disk::{DiskWriter, VirtualDisk};
+use crate::util::{human_size, stream_len};
+
+const COPY_BUFFER_SIZE: usize = 4 << 20;
+
+/// An existing block device, or pre-sized file, as an output.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Device;
+
+impl DiskWriter for Device {
+    fn write_disk(&self, disk: &mut dyn VirtualDisk, path: &Path) -> Result<()> {
+        copy_to_device(disk, path)
+    }
+}
+
+/// Copy the guest disk exposed by `disk` to the existing device at `device_path`.
+pub fn copy_to_device<R: Read + Seek + ?Sized>(disk: &mut R, device_path: &Path) -> Result<()> {
+    let size = stream_len(disk)?;
+    let mut device = OpenOptions::new()
+        .write(true)
+        .open(device_path)
+        .with_context(|| format!("failed to open device {}", device_path.display()))?;
+    let device_size = stream_len(&mut device)?;
+    ensure!(
+        device_size >= size,
+        "device {} ({}) is smaller than the image ({})",
+        device_path.display(),
+        human_size(device_size),
+        human_size(size)
+    );
+
+    info!("writing {} to {}", human_size(size), device_path.display());
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut written = 0u64;
+    while written < size {
+        let n = (size - written).min(buf.len() as u64) as usize;
+        disk.read_exact(&mut buf[..n])
+            .with_context(|| format!("failed to read image at offset {written}"))?;
+        device.write_all(&buf[..n])?;
+        written += n as u64;
+    }
+    device.flush()?;
+    device.sync_all()?;
+    Ok(())
+}
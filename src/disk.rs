@@ -0,0 +1,268 @@
+//! Format-agnostic virtual disks.
+//!
+//! Every image reader implements [`VirtualDisk`] and every output format implements
+//! [`DiskWriter`], so any source can be converted to any sink:
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use vmi::disk::{open_disk, DiskFormat, DiskWriter};
+//! use vmi::vmdk::VmdkOptions;
+//!
+//! let mut disk = open_disk(Path::new("disk.qcow2"), DiskFormat::Qcow2)?;
+//! VmdkOptions::default().write_disk(&mut disk, Path::new("disk.vmdk"))?;
+//! # anyhow::Ok(())
+//! ```
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tracing::info;
+
+use crate::gce::GceTar;
+use crate::ovf::Ova;
+use crate::qcow2::{Qcow2, MAGIC as QCOW2_MAGIC};
+use crate::util::{le_u32, read_exact_at};
+use crate::vdi::{Vdi, SIGNATURE as VDI_SIGNATURE};
+use crate::vhd::{Vhd, FOOTER_COOKIE as VHD_COOKIE};
+use crate::vhdx::{Vhdx, FILE_SIGNATURE as VHDX_SIGNATURE};
+use crate::vmdk::{Vmdk, SPARSE_MAGIC as VMDK_MAGIC};
+
+/// What an [`Extent`] of a disk holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentKind {
+    /// Allocated data, which may still happen to be zero.
+    Data,
+    /// A range that is known to read as zeroes.
+    Zero,
+}
+
+/// A run of a disk with a single allocation state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+    pub kind: ExtentKind,
+}
+
+/// A guest-visible disk that can be read at arbitrary offsets.
+pub trait VirtualDisk: Read + Seek {
+    /// Size of the guest-visible disk in bytes.
+    fn size(&self) -> u64;
+
+    /// Granularity at which the disk is allocated, in bytes.
+    fn block_size(&self) -> u64 {
+        512
+    }
+
+    /// Fill `buf` with the disk contents at `offset`.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        read_exact_at(self, offset, buf)
+    }
+
+    /// The extents of the disk in order, covering all of it.
+    ///
+    /// Formats without an allocation map report the whole disk as data.
+    fn extents(&mut self) -> Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        push_extent(&mut extents, 0, self.size(), ExtentKind::Data);
+        Ok(extents)
+    }
+}
+
+impl<D: VirtualDisk + ?Sized> VirtualDisk for Box<D> {
+    fn size(&self) -> u64 {
+        (**self).size()
+    }
+
+    fn block_size(&self) -> u64 {
+        (**self).block_size()
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn extents(&mut self) -> Result<Vec<Extent>> {
+        (**self).extents()
+    }
+}
+
+/// An output format that virtual disks can be written to.
+pub trait DiskWriter {
+    /// Write the whole of `disk` to a new image, or an existing device, at `path`.
+    fn write_disk(&self, disk: &mut dyn VirtualDisk, path: &Path) -> Result<()>;
+}
+
+/// Append an extent, merging it with the previous one when they are adjacent and alike.
+pub(crate) fn push_extent(extents: &mut Vec<Extent>, offset: u64, len: u64, kind: ExtentKind) {
+    if len == 0 {
+        return;
+    }
+    match extents.last_mut() {
+        Some(last) if last.kind == kind && last.offset + last.len == offset => last.len += len,
+        _ => extents.push(Extent { offset, len, kind }),
+    }
+}
+
+/// A raw image file or block device.
+pub struct RawDisk {
+    file: File,
+    size: u64,
+}
+
+impl RawDisk {
+    pub fn open_path(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        Self::new(file)
+    }
+
+    pub fn new(mut file: File) -> Result<Self> {
+        // Seeking also measures block devices, whose metadata reports no length.
+        let size = file.seek(SeekFrom::End(0))?;
+        file.rewind()?;
+        Ok(RawDisk { file, size })
+    }
+}
+
+impl Read for RawDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for RawDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl VirtualDisk for RawDisk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Holes of sparse files are found with `SEEK_DATA`/`SEEK_HOLE`, without reading them.
+    fn extents(&mut self) -> Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        let mut offset = 0;
+        while offset < self.size {
+            let Some(data) = seek_data(&self.file, libc::SEEK_DATA, offset)? else {
+                break;
+            };
+            // Block devices and filesystems without hole support report everything as data.
+            let hole = seek_data(&self.file, libc::SEEK_HOLE, data)?.unwrap_or(self.size);
+            let hole = hole.min(self.size);
+            push_extent(&mut extents, offset, data - offset, ExtentKind::Zero);
+            push_extent(&mut extents, data, hole - data, ExtentKind::Data);
+            offset = hole;
+        }
+        push_extent(&mut extents, offset, self.size - offset, ExtentKind::Zero);
+        self.file.rewind()?;
+        Ok(extents)
+    }
+}
+
+/// `lseek` with `SEEK_DATA` or `SEEK_HOLE`, returning `None` past the last data.
+fn seek_data(file: &File, whence: libc::c_int, offset: u64) -> Result<Option<u64>> {
+    let Ok(offset) = libc::off_t::try_from(offset) else {
+        return Ok(None);
+    };
+    // SAFETY: lseek only moves the file position of a descriptor we own.
+    let pos = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+    if pos >= 0 {
+        return Ok(Some(pos as u64));
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENXIO) => Ok(None),
+        _ => Err(err).context("failed to seek for data"),
+    }
+}
+
+/// Image formats that can be opened from a local file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+    Vmdk,
+    /// The first disk of an OVA appliance.
+    Ova,
+    Vhd,
+    Vhdx,
+    Vdi,
+    GceTar,
+}
+
+impl DiskFormat {
+    /// Short lowercase name of the format, as used on the command line and in reports.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DiskFormat::Raw => "raw",
+            DiskFormat::Qcow2 => "qcow2",
+            DiskFormat::Vmdk => "vmdk",
+            DiskFormat::Ova => "ova",
+            DiskFormat::Vhd => "vhd",
+            DiskFormat::Vhdx => "vhdx",
+            DiskFormat::Vdi => "vdi",
+            DiskFormat::GceTar => "gce-tar",
+        }
+    }
+}
+
+/// Open the image at `path` as a virtual disk of the given format.
+pub fn open_disk(path: &Path, format: DiskFormat) -> Result<Box<dyn VirtualDisk>> {
+    Ok(match format {
+        DiskFormat::Raw => Box::new(RawDisk::open_path(path)?),
+        DiskFormat::Qcow2 => Box::new(Qcow2::open_path(path)?),
+        DiskFormat::Vmdk => Box::new(Vmdk::open_path(path)?),
+        DiskFormat::Ova => Ova::open_path(path)?.open_disk(0)?,
+        DiskFormat::Vhd => Box::new(Vhd::open_path(path)?),
+        DiskFormat::Vhdx => Box::new(Vhdx::open_path(path)?),
+        DiskFormat::Vdi => Box::new(Vdi::open_path(path)?),
+        DiskFormat::GceTar => Box::new(GceTar::open_path(path)?),
+    })
+}
+
+/// Identify the format of the image at `path` from its magic bytes, falling back to raw.
+pub fn detect_format(path: &Path) -> Result<DiskFormat> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut head = vec![0u8; len.min(1024) as usize];
+    read_exact_at(&mut file, 0, &mut head)?;
+    let mut tail = [0u8; 512];
+    if len >= 512 {
+        read_exact_at(&mut file, len - 512, &mut tail)?;
+    }
+
+    let format = if head.starts_with(QCOW2_MAGIC) {
+        DiskFormat::Qcow2
+    } else if head.starts_with(VHDX_SIGNATURE) {
+        DiskFormat::Vhdx
+    } else if head.starts_with(VMDK_MAGIC) || is_vmdk_descriptor(&head) {
+        DiskFormat::Vmdk
+    } else if head.len() >= 0x44 && le_u32(&head, 0x40) == VDI_SIGNATURE {
+        DiskFormat::Vdi
+    } else if head.starts_with(&[0x1f, 0x8b]) {
+        DiskFormat::GceTar
+    } else if head.get(257..262) == Some(b"ustar") {
+        DiskFormat::Ova
+    } else if tail.starts_with(VHD_COOKIE) || head.starts_with(VHD_COOKIE) {
+        // Fixed VHDs only carry a footer, so check it last: it follows raw disk contents.
+        DiskFormat::Vhd
+    } else {
+        DiskFormat::Raw
+    };
+    info!("detected {} image {}", format.as_str(), path.display());
+    Ok(format)
+}
+
+/// Whether `head` starts a text VMDK descriptor.
+fn is_vmdk_descriptor(head: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(head);
+    text.trim_start().starts_with("# Disk DescriptorFile")
+}
@@ -0,0 +1,623 @@
+//! Amazon EBS direct APIs: reading and writing snapshots block by block over HTTPS, without
+//! attaching a volume to an EC2 instance.
+//!
+//! Requests are signed with SigV4 and sent through the same HTTP connector as the AWS SDK.
+//! The endpoint can be overridden, e.g. for VPC endpoints or a local stand-in server.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, ensure, Context, Result};
+use aws_config::SdkConfig;
+use aws_credential_types::provider::{ProvideCredentials, SharedCredentialsProvider};
+use aws_credential_types::Credentials;
+use aws_sigv4::http_request::{
+    sign, SignableBody, SignableRequest, SigningParams, SigningSettings,
+};
+use aws_sigv4::sign::v4;
+use aws_smithy_http_client::tls::{self, rustls_provider::CryptoMode};
+use aws_smithy_http_client::Connector;
+use aws_smithy_runtime_api::client::http::{HttpConnector, SharedHttpConnector};
+use aws_smithy_runtime_api::client::identity::Identity;
+use aws_smithy_runtime_api::client::orchestrator::HttpRequest;
+use aws_smithy_runtime_api::http::Headers;
+use aws_smithy_types::body::SdkBody;
+use aws_smithy_types::byte_stream::ByteStream;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::ami::VolumeSelector;
+use crate::disk::{push_extent, Extent, ExtentKind, VirtualDisk};
+use crate::util::human_size;
+
+mod writer;
+
+pub use writer::{write_snapshot, SnapshotOptions};
+
+const SERVICE: &str = "ebs";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_ATTEMPTS: u32 = 5;
+// Largest page ListSnapshotBlocks returns.
+const MAX_RESULTS: u32 = 10000;
+// Minutes a started snapshot may go without writes, or without being completed after the
+// last one, before it is cancelled.
+const SNAPSHOT_TIMEOUT_MINUTES: u64 = 60;
+const JSON: (&str, &str) = ("content-type", "application/json");
+/// Default number of blocks transferred in parallel.
+pub const DEFAULT_CONCURRENCY: usize = 16;
+
+/// A client of the EBS direct APIs for one region.
+#[derive(Debug, Clone)]
+pub struct EbsClient {
+    endpoint: String,
+    region: String,
+    credentials: SharedCredentialsProvider,
+    // Credentials are resolved once and reused until they are about to expire.
+    cached: Arc<Mutex<Option<Credentials>>>,
+    connector: SharedHttpConnector,
+}
+
+/// A block of a snapshot that holds data.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Block {
+    pub block_index: u64,
+    pub block_token: String,
+}
+
+/// A snapshot created by `StartSnapshot`, ready for its blocks to be written.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StartedSnapshot {
+    pub snapshot_id: String,
+    pub block_size: u64,
+}
+
+/// The allocated blocks of a snapshot, as listed by `ListSnapshotBlocks`.
+#[derive(Debug, Clone)]
+pub struct SnapshotBlocks {
+    /// Size of the snapshot's volume in bytes.
+    pub volume_size: u64,
+    pub block_size: u64,
+    /// Blocks holding data, by increasing index.
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListSnapshotBlocksPage {
+    #[serde(default)]
+    blocks: Vec<Block>,
+    block_size: u64,
+    // In GiB.
+    volume_size: u64,
+    next_token: Option<String>,
+}
+
+impl EbsClient {
+    /// A client sending requests to `endpoint`, signed for `region` with `credentials`.
+    pub fn new(
+        endpoint: impl Into<String>,
+        region: impl Into<String>,
+        credentials: SharedCredentialsProvider,
+    ) -> Self {
+        let connector = Connector::builder()
+            .tls_provider(tls::Provider::Rustls(CryptoMode::AwsLc))
+            .build();
+        EbsClient {
+            endpoint: endpoint.into().trim_end_matches('/').to_string(),
+            region: region.into(),
+            credentials,
+            cached: Arc::new(Mutex::new(None)),
+            connector: SharedHttpConnector::new(connector),
+        }
+    }
+
+    /// A client using the region and credentials of an AWS SDK configuration, and the
+    /// regional endpoint unless `endpoint` is given.
+    pub fn from_conf(config: &SdkConfig, endpoint: Option<&str>) -> Result<Self> {
+        let region = config
+            .region()
+            .context("no AWS region is configured; set AWS_REGION")?
+            .to_string();
+        let credentials = config
+            .credentials_provider()
+            .context("no AWS credentials are configured")?;
+        let endpoint = endpoint.map_or_else(
+            || format!("https://ebs.{region}.amazonaws.com"),
+            str::to_string,
+        );
+        Ok(Self::new(endpoint, region, credentials))
+    }
+
+    /// List every block of `snapshot_id` that holds data.
+    pub async fn list_snapshot_blocks(&self, snapshot_id: &str) -> Result<SnapshotBlocks> {
+        let path = format!("/snapshots/{}/blocks", uri_encode(snapshot_id));
+        let max_results = MAX_RESULTS.to_string();
+        let mut next_token: Option<String> = None;
+        let mut blocks = Vec::new();
+        loop {
+            let mut query = vec![("maxResults", max_results.as_str())];
+            if let Some(token) = &next_token {
+                query.push(("pageToken", token));
+            }
+            let (_, body) = self.send("GET", &path, &query, &[], &[]).await?;
+            let page: ListSnapshotBlocksPage = serde_json::from_slice(&body)
+                .context("failed to parse ListSnapshotBlocks response")?;
+            blocks.extend(page.blocks);
+            match page.next_token {
+                Some(token) if !token.is_empty() => next_token = Some(token),
+                _ => {
+                    blocks.sort_by_key(|b| b.block_index);
+                    return Ok(SnapshotBlocks {
+                        volume_size: page.volume_size << 30,
+                        block_size: page.block_size,
+                        blocks,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Fetch the data of `block` of `snapshot_id`, verifying its SHA256 checksum.
+    pub async fn get_snapshot_block(&self, snapshot_id: &str, block: &Block) -> Result<Vec<u8>> {
+        let path = format!(
+            "/snapshots/{}/blocks/{}",
+            uri_encode(snapshot_id),
+            block.block_index
+        );
+        let query = [("blockToken", block.block_token.as_str())];
+        let (headers, data) = self.send("GET", &path, &query, &[], &[]).await?;
+        let context = || format!("block {} of {snapshot_id}", block.block_index);
+        if let Some(len) = headers.get("x-amz-Data-Length") {
+            ensure!(
+                len.parse::<usize>().ok() == Some(data.len()),
+                "{} is {} bytes long, not {len}",
+                context(),
+                data.len()
+            );
+        }
+        let algorithm = headers.get("x-amz-Checksum-Algorithm").unwrap_or("SHA256");
+        ensure!(
+            algorithm == "SHA256",
+            "{} has an unsupported checksum algorithm {algorithm}",
+            context()
+        );
+        let expected = headers
+            .get("x-amz-Checksum")
+            .with_context(|| format!("{} has no checksum", context()))?;
+        let actual = aws_smithy_types::base64::encode(Sha256::digest(&data));
+        ensure!(
+            actual == expected,
+            "checksum mismatch for {}: expected {expected}, got {actual}",
+            context()
+        );
+        Ok(data)
+    }
+
+    /// Start a new snapshot of a `volume_gib` GiB volume, to be filled with
+    /// [`put_snapshot_block`](Self::put_snapshot_block).
+    pub async fn start_snapshot(
+        &self,
+        volume_gib: u64,
+        description: Option<&str>,
+        tags: &[(String, String)],
+    ) -> Result<StartedSnapshot> {
+        let mut request = serde_json::json!({
+            "VolumeSize": volume_gib,
+            // Makes retries of the request idempotent.
+            "ClientToken": Uuid::new_v4().to_string(),
+            "Timeout": SNAPSHOT_TIMEOUT_MINUTES,
+        });
+        if let Some(description) = description {
+            request["Description"] = description.into();
+        }
+        if !tags.is_empty() {
+            request["Tags"] = tags
+                .iter()
+                .map(|(key, value)| serde_json::json!({"Key": key, "Value": value}))
+                .collect();
+        }
+        let body = serde_json::to_vec(&request)?;
+        let (_, body) = self.send("POST", "/snapshots", &[], &[JSON], &body).await?;
+        serde_json::from_slice(&body).context("failed to parse StartSnapshot response")
+    }
+
+    /// Write the data of block `index` of a started snapshot, along with the SHA256
+    /// `checksum` of the data.
+    pub async fn put_snapshot_block(
+        &self,
+        snapshot_id: &str,
+        index: u64,
+        data: &[u8],
+        checksum: &[u8],
+    ) -> Result<()> {
+        let path = format!("/snapshots/{}/blocks/{index}", uri_encode(snapshot_id));
+        let len = data.len().to_string();
+        let checksum = aws_smithy_types::base64::encode(checksum);
+        let headers = [
+            ("content-type", "application/octet-stream"),
+            ("x-amz-Data-Length", len.as_str()),
+            ("x-amz-Checksum", checksum.as_str()),
+            ("x-amz-Checksum-Algorithm", "SHA256"),
+        ];
+        self.send("PUT", &path, &[], &headers, data).await?;
+        Ok(())
+    }
+
+    /// Seal a started snapshot after `changed_blocks` blocks were written, given the
+    /// linear checksum of their data: the SHA256 of their SHA256s in block order.
+    pub async fn complete_snapshot(
+        &self,
+        snapshot_id: &str,
+        changed_blocks: usize,
+        checksum: &[u8],
+    ) -> Result<String> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Completion {
+            status: String,
+        }
+
+        let path = format!("/snapshots/completion/{}", uri_encode(snapshot_id));
+        let count = changed_blocks.to_string();
+        let checksum = aws_smithy_types::base64::encode(checksum);
+        let headers = [
+            ("x-amz-ChangedBlocksCount", count.as_str()),
+            ("x-amz-Checksum", checksum.as_str()),
+            ("x-amz-Checksum-Algorithm", "SHA256"),
+            ("x-amz-Checksum-Aggregation-Method", "LINEAR"),
+        ];
+        let (_, body) = self.send("POST", &path, &[], &headers, &[]).await?;
+        let completion: Completion =
+            serde_json::from_slice(&body).context("failed to parse CompleteSnapshot response")?;
+        ensure!(
+            completion.status != "error",
+            "snapshot {snapshot_id} failed to complete"
+        );
+        Ok(completion.status)
+    }
+
+    async fn credentials(&self) -> Result<Credentials> {
+        let mut cached = self.cached.lock().await;
+        let refresh_by = SystemTime::now() + Duration::from_secs(300);
+        if let Some(credentials) = cached.as_ref() {
+            if credentials
+                .expiry()
+                .map_or(true, |expiry| expiry > refresh_by)
+            {
+                return Ok(credentials.clone());
+            }
+        }
+        let credentials = self
+            .credentials
+            .provide_credentials()
+            .await
+            .context("failed to load AWS credentials")?;
+        *cached = Some(credentials.clone());
+        Ok(credentials)
+    }
+
+    /// Send a signed request, retrying throttled and failed ones with backoff, and return
+    /// the response headers and body.
+    async fn send(
+        &self,
+        method: &str,
+        path: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<(Headers, Vec<u8>)> {
+        let mut uri = format!("{}{path}", self.endpoint);
+        for (i, (name, value)) in query.iter().enumerate() {
+            let sep = if i == 0 { '?' } else { '&' };
+            uri.push_str(&format!("{sep}{name}={}", uri_encode(value)));
+        }
+
+        let mut attempt = 1;
+        loop {
+            let request = self.sign(method, &uri, headers, body).await?;
+            let error = match timeout(REQUEST_TIMEOUT, self.connector.call(request)).await {
+                Err(_) => format!("{method} {path} timed out"),
+                Ok(Err(e)) => format!("{method} {path} failed: {e}"),
+                Ok(Ok(response)) => {
+                    let status = response.status().as_u16();
+                    let headers = response.headers().clone();
+                    let data = ByteStream::new(response.into_body())
+                        .collect()
+                        .await
+                        .with_context(|| format!("failed to read {method} {path} response"))?
+                        .to_vec();
+                    if (200..300).contains(&status) {
+                        return Ok((headers, data));
+                    }
+                    let error_type = headers.get("x-amzn-ErrorType").unwrap_or_default();
+                    let message = error_message(&data);
+                    let retryable =
+                        status == 429 || status >= 500 || error_type.contains("Throttl");
+                    if !retryable {
+                        bail!("{method} {path} failed: {status} {error_type} {message}");
+                    }
+                    format!("{method} {path} failed: {status} {error_type} {message}")
+                }
+            };
+            if attempt == MAX_ATTEMPTS {
+                bail!("{error} after {attempt} attempts");
+            }
+            let backoff = Duration::from_millis(200 << attempt);
+            debug!("{error}; retrying in {backoff:?}");
+            sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    async fn sign(
+        &self,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<HttpRequest> {
+        let identity: Identity = self.credentials().await?.into();
+        let params: SigningParams = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name(SERVICE)
+            .time(SystemTime::now())
+            .settings(SigningSettings::default())
+            .build()?
+            .into();
+        let mut builder = http::Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        let mut request = builder.body(body.to_vec())?;
+        let signable = SignableRequest::new(
+            method,
+            uri,
+            headers.iter().copied(),
+            SignableBody::Bytes(body),
+        )?;
+        let (instructions, _) = sign(signable, &params)?.into_parts();
+        instructions.apply_to_request_http1x(&mut request);
+        Ok(HttpRequest::try_from(request.map(SdkBody::from))?)
+    }
+}
+
+/// The message of an AWS JSON error response, or the raw body.
+fn error_message(body: &[u8]) -> String {
+    #[derive(Deserialize)]
+    struct Error {
+        #[serde(alias = "message")]
+        #[serde(rename = "Message")]
+        message: String,
+    }
+    match serde_json::from_slice::<Error>(body) {
+        Ok(e) => e.message,
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters.
+fn uri_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+/// An EBS snapshot exposed as a seekable stream, fetched through the EBS direct APIs.
+///
+/// Blocks are fetched on the tokio runtime the snapshot was opened on, several at a time
+/// ahead of the reader. Reads block on those fetches, so they must happen outside of async
+/// code, e.g. in [`tokio::task::block_in_place`].
+pub struct EbsSnapshot {
+    client: EbsClient,
+    snapshot_id: String,
+    runtime: Handle,
+    size: u64,
+    block_size: u64,
+    blocks: Vec<Block>,
+    concurrency: usize,
+    // Fetches in flight, by position in `blocks`.
+    pending: BTreeMap<usize, JoinHandle<Result<Vec<u8>>>>,
+    current: Option<(usize, Vec<u8>)>,
+    pos: u64,
+}
+
+impl EbsSnapshot {
+    /// List the blocks of `snapshot_id`, ready to read them.
+    pub async fn open(client: EbsClient, snapshot_id: &str) -> Result<Self> {
+        let list = client
+            .list_snapshot_blocks(snapshot_id)
+            .await
+            .with_context(|| format!("failed to list the blocks of {snapshot_id}"))?;
+        ensure!(list.block_size > 0, "{snapshot_id} has a zero block size");
+        info!(
+            "snapshot {snapshot_id} holds {} of data in a {} volume",
+            human_size(list.blocks.len() as u64 * list.block_size),
+            human_size(list.volume_size)
+        );
+        Ok(EbsSnapshot {
+            client,
+            snapshot_id: snapshot_id.to_string(),
+            runtime: Handle::current(),
+            size: list.volume_size,
+            block_size: list.block_size,
+            blocks: list.blocks,
+            concurrency: DEFAULT_CONCURRENCY,
+            pending: BTreeMap::new(),
+            current: None,
+            pos: 0,
+        })
+    }
+
+    /// Fetch up to `concurrency` blocks in parallel.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn snapshot_id(&self) -> &str {
+        &self.snapshot_id
+    }
+
+    /// Bytes of the snapshot held in blocks with data.
+    pub fn stored_size(&self) -> u64 {
+        self.blocks.len() as u64 * self.block_size
+    }
+
+    /// The data of the `i`th listed block, starting fetches of the blocks after it.
+    fn block(&mut self, i: usize) -> io::Result<&[u8]> {
+        if self.current.as_ref().is_some_and(|(cur, _)| *cur == i) {
+            return Ok(&self.current.as_ref().unwrap().1);
+        }
+        // Fetches behind the reader are wasted, as reads are mostly sequential.
+        let stale = self.pending.split_off(&i);
+        for (_, handle) in std::mem::replace(&mut self.pending, stale) {
+            handle.abort();
+        }
+        for j in i..self.blocks.len().min(i + self.concurrency) {
+            if !self.pending.contains_key(&j) {
+                let client = self.client.clone();
+                let snapshot_id = self.snapshot_id.clone();
+                let block = self.blocks[j].clone();
+                let handle = self
+                    .runtime
+                    .spawn(async move { client.get_snapshot_block(&snapshot_id, &block).await });
+                self.pending.insert(j, handle);
+            }
+        }
+        let handle = self.pending.remove(&i).unwrap();
+        let data = self
+            .runtime
+            .block_on(handle)
+            .map_err(io::Error::other)?
+            .map_err(|e| io::Error::other(format!("{e:#}")))?;
+        if (data.len() as u64) != self.block_size {
+            return Err(io::Error::other(format!(
+                "block {} of {} is {} bytes, not {}",
+                self.blocks[i].block_index,
+                self.snapshot_id,
+                data.len(),
+                self.block_size
+            )));
+        }
+        Ok(&self.current.insert((i, data)).1)
+    }
+
+    fn read_mapped_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let index = offset / self.block_size;
+        let within = offset % self.block_size;
+        match self.blocks.binary_search_by_key(&index, |b| b.block_index) {
+            Ok(i) => {
+                let n = buf.len().min((self.block_size - within) as usize);
+                let data = self.block(i)?;
+                buf[..n].copy_from_slice(&data[within as usize..within as usize + n]);
+                Ok(n)
+            }
+            Err(i) => {
+                // A hole, up to the next block with data.
+                let next = self
+                    .blocks
+                    .get(i)
+                    .map_or(u64::MAX, |b| b.block_index * self.block_size);
+                let n = buf.len().min((next - offset) as usize);
+                buf[..n].fill(0);
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl Drop for EbsSnapshot {
+    fn drop(&mut self) {
+        for handle in self.pending.values() {
+            handle.abort();
+        }
+    }
+}
+
+impl VirtualDisk for EbsSnapshot {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn extents(&mut self) -> Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        let mut end = 0;
+        for block in &self.blocks {
+            let offset = (block.block_index * self.block_size).min(self.size);
+            let len = self.block_size.min(self.size - offset);
+            push_extent(&mut extents, end, offset - end, ExtentKind::Zero);
+            push_extent(&mut extents, offset, len, ExtentKind::Data);
+            end = offset + len;
+        }
+        push_extent(&mut extents, end, self.size - end, ExtentKind::Zero);
+        Ok(extents)
+    }
+}
+
+impl Read for EbsSnapshot {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = (buf.len() as u64).min(self.size - self.pos) as usize;
+        let n = self.read_mapped_at(self.pos, &mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for EbsSnapshot {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Open the snapshot of the volume of `ami_id` chosen by `volume`, or its root volume, for
+/// reading through the EBS direct APIs, using the AWS configuration of the environment.
+pub async fn open_ami_snapshot(
+    ami_id: &str,
+    volume: Option<&VolumeSelector>,
+    endpoint: Option<&str>,
+) -> Result<EbsSnapshot> {
+    let config = aws_config::load_from_env().await;
+    let ec2_client = aws_sdk_ec2::Client::new(&config);
+    let snapshot_id = crate::find_ami_snapshot(&ec2_client, ami_id, volume).await?;
+    info!("reading snapshot {snapshot_id} of {ami_id} through the EBS direct APIs");
+    EbsSnapshot::open(EbsClient::from_conf(&config, endpoint)?, &snapshot_id).await
+}
@@ -0,0 +1,127 @@
+//! Writer creating EBS snapshots through the EBS direct APIs.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use super::{EbsClient, DEFAULT_CONCURRENCY};
+use crate::disk::{ExtentKind, VirtualDisk};
+use crate::util::human_size;
+
+const GIB: u64 = 1 << 30;
+
+/// Options of a snapshot written through the EBS direct APIs.
+#[derive(Debug, Clone)]
+pub struct SnapshotOptions {
+    pub description: Option<String>,
+    /// Tags of the snapshot, as key-value pairs.
+    pub tags: Vec<(String, String)>,
+    /// Number of blocks uploaded in parallel.
+    pub concurrency: usize,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        SnapshotOptions {
+            description: None,
+            tags: Vec::new(),
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+}
+
+/// Write the guest disk exposed by `disk` to a new EBS snapshot, returning its ID.
+///
+/// Only blocks holding data are uploaded, each with its SHA256, and the snapshot is completed
+/// with their linear checksum. Uploads run on the current tokio runtime while the disk is
+/// read, so this must be called outside of async code, e.g. in
+/// [`tokio::task::block_in_place`].
+pub fn write_snapshot<D: VirtualDisk + ?Sized>(
+    disk: &mut D,
+    client: &EbsClient,
+    options: &SnapshotOptions,
+) -> Result<String> {
+    let runtime = Handle::try_current().context("writing snapshots requires a tokio runtime")?;
+    let size = disk.size();
+    let volume_gib = size.div_ceil(GIB).max(1);
+    let started = runtime.block_on(client.start_snapshot(
+        volume_gib,
+        options.description.as_deref(),
+        &options.tags,
+    ))?;
+    let snapshot_id = started.snapshot_id;
+    let block_size = started.block_size;
+    info!(
+        "writing {} to snapshot {snapshot_id} of a {volume_gib} GiB volume",
+        human_size(size)
+    );
+
+    let mut checksums = BTreeMap::new();
+    let mut uploads: VecDeque<JoinHandle<Result<()>>> = VecDeque::new();
+    let mut buf = vec![0u8; block_size as usize];
+    let mut next_index = 0;
+    let result = (|| -> Result<()> {
+        for extent in disk.extents()? {
+            if extent.kind == ExtentKind::Zero {
+                continue;
+            }
+            // Data extents that share a block were uploaded with the previous one.
+            let first = (extent.offset / block_size).max(next_index);
+            let end = (extent.offset + extent.len).div_ceil(block_size);
+            for index in first..end {
+                let offset = index * block_size;
+                let n = block_size.min(size - offset) as usize;
+                buf[n..].fill(0);
+                disk.read_at(offset, &mut buf[..n])
+                    .with_context(|| format!("failed to read image at offset {offset}"))?;
+                if buf.iter().all(|&b| b == 0) {
+                    continue;
+                }
+                let checksum: [u8; 32] = Sha256::digest(&buf).into();
+                checksums.insert(index, checksum);
+                if uploads.len() >= options.concurrency.max(1) {
+                    runtime.block_on(uploads.pop_front().unwrap())??;
+                }
+                let client = client.clone();
+                let snapshot_id = snapshot_id.clone();
+                let data = buf.clone();
+                uploads.push_back(runtime.spawn(async move {
+                    client
+                        .put_snapshot_block(&snapshot_id, index, &data, &checksum)
+                        .await
+                        .with_context(|| format!("failed to upload block {index}"))
+                }));
+            }
+            next_index = end;
+        }
+        while let Some(upload) = uploads.pop_front() {
+            runtime.block_on(upload)??;
+        }
+        Ok(())
+    })();
+    if let Err(e) = result {
+        for upload in uploads {
+            upload.abort();
+        }
+        return Err(e.context(format!("failed to write snapshot {snapshot_id}")));
+    }
+
+    let mut linear = Sha256::new();
+    for checksum in checksums.values() {
+        linear.update(checksum);
+    }
+    let status = runtime.block_on(client.complete_snapshot(
+        &snapshot_id,
+        checksums.len(),
+        &linear.finalize(),
+    ))?;
+    info!(
+        "uploaded {} to snapshot {snapshot_id}, now {status}",
+        human_size(checksums.len() as u64 * block_size)
+    );
+    Ok(snapshot_id)
+}
@@ -0,0 +1,229 @@
+//! Exporting AMIs to S3 as disk image files through VM Import/Export.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use aws_config::SdkConfig;
+use aws_sdk_ec2::types::{DiskImageFormat, ExportTaskS3LocationRequest};
+use tracing::info;
+
+use crate::ami::describe_ami;
+use crate::util::human_size;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Disk image format of an exported AMI.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    #[default]
+    Vmdk,
+    Vhd,
+    Raw,
+}
+
+impl ExportFormat {
+    /// Extension of the exported object's key.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Vmdk => "vmdk",
+            ExportFormat::Vhd => "vhd",
+            ExportFormat::Raw => "raw",
+        }
+    }
+}
+
+/// A bucket and key prefix, parsed from an `s3://bucket/prefix` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Location {
+    pub bucket: String,
+    /// Prefix of the exported object's key, ending with `/` unless empty.
+    pub prefix: String,
+}
+
+impl FromStr for S3Location {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        let path = url
+            .strip_prefix("s3://")
+            .with_context(|| format!("{url} is not an s3:// URL"))?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        ensure!(!bucket.is_empty(), "{url} has no bucket");
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => String::new(),
+            prefix => format!("{prefix}/"),
+        };
+        Ok(S3Location {
+            bucket: bucket.to_string(),
+            prefix,
+        })
+    }
+}
+
+/// Options of an AMI export.
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// IAM role VM Import/Export writes to the bucket as.
+    pub role_name: String,
+    /// Local path the exported image is downloaded to.
+    pub download: Option<PathBuf>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            format: ExportFormat::default(),
+            role_name: "vmimport".to_string(),
+            download: None,
+        }
+    }
+}
+
+/// An exported disk image in S3.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedObject {
+    pub bucket: String,
+    pub key: String,
+    pub size: u64,
+}
+
+/// Export `ami_id` to an object under `destination`, waiting for the export task to
+/// complete, and check that the object exists before returning it.
+///
+/// The bucket must be in the same region as the AMI and writable by the role of `options`.
+pub async fn export_ami(
+    config: &SdkConfig,
+    ami_id: &str,
+    destination: &S3Location,
+    options: &ExportOptions,
+) -> Result<ExportedObject> {
+    let ec2_client = aws_sdk_ec2::Client::new(config);
+    describe_ami(&ec2_client, ami_id).await?;
+    let task_id = ec2_client
+        .export_image()
+        .image_id(ami_id)
+        .disk_image_format(match options.format {
+            ExportFormat::Vmdk => DiskImageFormat::Vmdk,
+            ExportFormat::Vhd => DiskImageFormat::Vhd,
+            ExportFormat::Raw => DiskImageFormat::Raw,
+        })
+        .s3_export_location(
+            ExportTaskS3LocationRequest::builder()
+                .s3_bucket(&destination.bucket)
+                .s3_prefix(&destination.prefix)
+                .build(),
+        )
+        .role_name(&options.role_name)
+        .send()
+        .await
+        .with_context(|| format!("failed to export {ami_id}"))?
+        .export_image_task_id
+        .context("ExportImage returned no task ID")?;
+    info!("exporting {ami_id} with task {task_id}");
+    wait_for_export(&ec2_client, &task_id).await?;
+
+    // VM Import/Export names the object after the task.
+    let key = format!(
+        "{}{task_id}.{}",
+        destination.prefix,
+        options.format.extension()
+    );
+    let s3_client = aws_sdk_s3::Client::new(config);
+    let head = s3_client
+        .head_object()
+        .bucket(&destination.bucket)
+        .key(&key)
+        .send()
+        .await
+        .with_context(|| format!("exported s3://{}/{key} not found", destination.bucket))?;
+    let object = ExportedObject {
+        bucket: destination.bucket.clone(),
+        key,
+        size: head
+            .content_length()
+            .and_then(|n| u64::try_from(n).ok())
+            .unwrap_or(0),
+    };
+    info!(
+        "exported {ami_id} to s3://{}/{} ({})",
+        object.bucket,
+        object.key,
+        human_size(object.size)
+    );
+    if let Some(path) = &options.download {
+        download(&s3_client, &object, path).await?;
+    }
+    Ok(object)
+}
+
+/// Poll export task `task_id` until it completes, logging its progress.
+async fn wait_for_export(ec2_client: &aws_sdk_ec2::Client, task_id: &str) -> Result<()> {
+    let mut last_status = String::new();
+    loop {
+        let output = ec2_client
+            .describe_export_image_tasks()
+            .export_image_task_ids(task_id)
+            .send()
+            .await
+            .with_context(|| format!("failed to describe export task {task_id}"))?;
+        let task = output
+            .export_image_tasks()
+            .first()
+            .with_context(|| format!("export task {task_id} not found"))?;
+        let status = task.status().unwrap_or("unknown");
+        let message = task.status_message().unwrap_or_default();
+        match status {
+            "completed" => return Ok(()),
+            "active" => {}
+            _ => bail!("export task {task_id} is {status}: {message}"),
+        }
+        let progress = match task.progress() {
+            Some(progress) => format!("{progress}% {message}"),
+            None => message.to_string(),
+        };
+        if progress != last_status {
+            info!("{task_id}: {}", progress.trim());
+            last_status = progress;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Download `object` to `path`.
+async fn download(
+    s3_client: &aws_sdk_s3::Client,
+    object: &ExportedObject,
+    path: &Path,
+) -> Result<()> {
+    info!(
+        "downloading s3://{}/{} to {}",
+        object.bucket,
+        object.key,
+        path.display()
+    );
+    let output = s3_client
+        .get_object()
+        .bucket(&object.bucket)
+        .key(&object.key)
+        .send()
+        .await
+        .with_context(|| format!("failed to download s3://{}/{}", object.bucket, object.key))?;
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    let written = tokio::io::copy(&mut output.body.into_async_read(), &mut file)
+        .await
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    file.sync_all().await?;
+    ensure!(
+        written == object.size,
+        "downloaded {written} bytes of s3://{}/{}, expected {}",
+        object.bucket,
+        object.key,
+        object.size
+    );
+    Ok(())
+}
@@ -0,0 +1,212 @@
+//! Filesystem detection by superblock signature.
+
+use std::fmt;
+use std::io::{Read, Seek};
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::util::{
+    be_u32, be_u64, format_uuid, le_u16, le_u32, le_u64, read_exact_at, trimmed_string,
+};
+
+/// A filesystem type recognised by [`detect_filesystem`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FilesystemKind {
+    Ext2,
+    Ext3,
+    Ext4,
+    Xfs,
+    Btrfs,
+    Fat12,
+    Fat16,
+    Fat32,
+    Ntfs,
+    Swap,
+}
+
+impl fmt::Display for FilesystemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FilesystemKind::Ext2 => "ext2",
+            FilesystemKind::Ext3 => "ext3",
+            FilesystemKind::Ext4 => "ext4",
+            FilesystemKind::Xfs => "xfs",
+            FilesystemKind::Btrfs => "btrfs",
+            FilesystemKind::Fat12 => "vfat (FAT12)",
+            FilesystemKind::Fat16 => "vfat (FAT16)",
+            FilesystemKind::Fat32 => "vfat (FAT32)",
+            FilesystemKind::Ntfs => "ntfs",
+            FilesystemKind::Swap => "swap",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A filesystem found on a disk or partition.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Filesystem {
+    pub kind: FilesystemKind,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+    /// Size of the filesystem in bytes, as recorded in its superblock.
+    pub size: Option<u64>,
+}
+
+// Enough to cover every superblock we look at (btrfs lives at 64 KiB).
+const PROBE_LEN: usize = 0x10000 + 0x1000;
+
+/// Detect the filesystem stored at `offset` in `r` by inspecting well-known superblocks.
+///
+/// `len` bounds the probe so that a small partition never reads into its neighbour.
+pub fn detect_filesystem<R: Read + Seek + ?Sized>(
+    r: &mut R,
+    offset: u64,
+    len: u64,
+) -> Result<Option<Filesystem>> {
+    let probe_len = (PROBE_LEN as u64).min(len) as usize;
+    if probe_len < 512 {
+        return Ok(None);
+    }
+    let mut buf = vec![0u8; probe_len];
+    read_exact_at(r, offset, &mut buf)?;
+
+    Ok(probe_swap(&buf)
+        .or_else(|| probe_xfs(&buf))
+        .or_else(|| probe_ext(&buf))
+        .or_else(|| probe_btrfs(&buf))
+        .or_else(|| probe_ntfs(&buf))
+        .or_else(|| probe_fat(&buf)))
+}
+
+fn probe_ext(buf: &[u8]) -> Option<Filesystem> {
+    const SB: usize = 1024;
+    if buf.len() < SB + 1024 || le_u16(buf, SB + 56) != 0xEF53 {
+        return None;
+    }
+    let sb = &buf[SB..SB + 1024];
+    let compat = le_u32(sb, 92);
+    let incompat = le_u32(sb, 96);
+    let ro_compat = le_u32(sb, 100);
+
+    const COMPAT_HAS_JOURNAL: u32 = 0x4;
+    // extents | 64bit | flex_bg
+    const INCOMPAT_EXT4: u32 = 0x40 | 0x80 | 0x200;
+    // huge_file | dir_nlink | extra_isize | metadata_csum
+    const RO_COMPAT_EXT4: u32 = 0x8 | 0x20 | 0x40 | 0x400;
+    let kind = if incompat & INCOMPAT_EXT4 != 0 || ro_compat & RO_COMPAT_EXT4 != 0 {
+        FilesystemKind::Ext4
+    } else if compat & COMPAT_HAS_JOURNAL != 0 {
+        FilesystemKind::Ext3
+    } else {
+        FilesystemKind::Ext2
+    };
+
+    let block_size = 1024u64 << le_u32(sb, 24).min(16);
+    let mut blocks = u64::from(le_u32(sb, 4));
+    if incompat & 0x80 != 0 {
+        blocks |= u64::from(le_u32(sb, 0x150)) << 32;
+    }
+    Some(Filesystem {
+        kind,
+        label: trimmed_string(&sb[120..136]),
+        uuid: Some(format_uuid(sb[104..120].try_into().unwrap())),
+        size: Some(blocks * block_size),
+    })
+}
+
+fn probe_xfs(buf: &[u8]) -> Option<Filesystem> {
+    if &buf[0..4] != b"XFSB" {
+        return None;
+    }
+    let block_size = u64::from(be_u32(buf, 4));
+    let blocks = be_u64(buf, 8);
+    Some(Filesystem {
+        kind: FilesystemKind::Xfs,
+        label: trimmed_string(&buf[108..120]),
+        uuid: Some(format_uuid(buf[32..48].try_into().unwrap())),
+        size: Some(blocks * block_size),
+    })
+}
+
+fn probe_btrfs(buf: &[u8]) -> Option<Filesystem> {
+    const SB: usize = 0x10000;
+    if buf.len() < SB + 0x1000 || &buf[SB + 0x40..SB + 0x48] != b"_BHRfS_M" {
+        return None;
+    }
+    let sb = &buf[SB..SB + 0x1000];
+    Some(Filesystem {
+        kind: FilesystemKind::Btrfs,
+        label: trimmed_string(&sb[0x12b..0x12b + 256]),
+        uuid: Some(format_uuid(sb[0x20..0x30].try_into().unwrap())),
+        size: Some(le_u64(sb, 0x70)),
+    })
+}
+
+fn probe_ntfs(buf: &[u8]) -> Option<Filesystem> {
+    if &buf[3..11] != b"NTFS    " {
+        return None;
+    }
+    let bytes_per_sector = u64::from(le_u16(buf, 11));
+    let sectors = le_u64(buf, 0x28);
+    Some(Filesystem {
+        kind: FilesystemKind::Ntfs,
+        // The volume label lives in the $Volume MFT record, not in the boot sector.
+        label: None,
+        uuid: Some(format!("{:016X}", le_u64(buf, 0x48))),
+        size: Some(sectors * bytes_per_sector),
+    })
+}
+
+fn probe_fat(buf: &[u8]) -> Option<Filesystem> {
+    if le_u16(buf, 510) != 0xAA55 {
+        return None;
+    }
+    let bytes_per_sector = u64::from(le_u16(buf, 11));
+    if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096) || buf[13] == 0 {
+        return None;
+    }
+    let (kind, ebpb) = if &buf[82..90] == b"FAT32   " {
+        (FilesystemKind::Fat32, 64)
+    } else if &buf[54..62] == b"FAT16   " {
+        (FilesystemKind::Fat16, 36)
+    } else if &buf[54..62] == b"FAT12   " {
+        (FilesystemKind::Fat12, 36)
+    } else {
+        return None;
+    };
+    let sectors = match le_u16(buf, 19) {
+        0 => u64::from(le_u32(buf, 32)),
+        n => u64::from(n),
+    };
+    let serial = le_u32(buf, ebpb + 3);
+    let label = trimmed_string(&buf[ebpb + 7..ebpb + 18]).filter(|l| l != "NO NAME");
+    Some(Filesystem {
+        kind,
+        label,
+        uuid: Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF)),
+        size: Some(sectors * bytes_per_sector),
+    })
+}
+
+fn probe_swap(buf: &[u8]) -> Option<Filesystem> {
+    for page_size in [4096usize, 8192, 16384, 65536] {
+        if buf.len() < page_size {
+            break;
+        }
+        let magic = &buf[page_size - 10..page_size];
+        if magic != b"SWAPSPACE2" && magic != b"SWAP-SPACE" {
+            continue;
+        }
+        let last_page = u64::from(le_u32(buf, 1028));
+        return Some(Filesystem {
+            kind: FilesystemKind::Swap,
+            label: trimmed_string(&buf[1052..1068]),
+            uuid: Some(format_uuid(buf[1036..1052].try_into().unwrap())),
+            size: Some((last_page + 1) * page_size as u64),
+        });
+    }
+    None
+}
@@ -0,0 +1,238 @@
+//! Google Compute Engine image tarballs: a gzip-compressed GNU tar holding a `disk.raw`.
+//!
+//! The disk is usually stored as an old-GNU sparse member, as produced by
+//! `tar --format=oldgnu -Sczf`. Reads decompress the archive as a stream; seeking backwards
+//! restarts decompression from the beginning of the file.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use flate2::read::MultiGzDecoder;
+use tar::{Archive, EntryType, GnuExtSparseHeader, Header};
+
+use crate::disk::{push_extent, Extent, ExtentKind, VirtualDisk};
+
+mod writer;
+
+pub use writer::{write_gce_tar, GceTarball};
+
+/// Name of the disk inside the tarball, as required by GCE.
+pub const DISK_NAME: &str = "disk.raw";
+const BLOCK: u64 = 512;
+
+/// A run of stored data of the disk; everything between runs is a hole.
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    offset: u64,
+    len: u64,
+    // Offset of the data from the start of the member's data in the archive.
+    stored: u64,
+}
+
+/// The `disk.raw` of a GCE image tarball exposed as a seekable stream.
+pub struct GceTar {
+    path: PathBuf,
+    decoder: MultiGzDecoder<BufReader<File>>,
+    // Position in the decompressed tar stream.
+    stream_pos: u64,
+    data_start: u64,
+    chunks: Vec<Chunk>,
+    size: u64,
+    pos: u64,
+}
+
+impl GceTar {
+    pub fn open_path(path: &Path) -> Result<Self> {
+        let header_pos = {
+            let mut archive = Archive::new(open_decoder(path)?);
+            let mut found = None;
+            for entry in archive
+                .entries()
+                .context("not a gzip-compressed tar archive")?
+            {
+                let entry = entry.context("failed to read tar entry")?;
+                let name = entry.path()?.to_string_lossy().into_owned();
+                if name.trim_start_matches("./") == DISK_NAME {
+                    found = Some(entry.raw_header_position());
+                    break;
+                }
+            }
+            found.with_context(|| format!("{} contains no {DISK_NAME}", path.display()))?
+        };
+
+        let mut image = GceTar {
+            path: path.to_path_buf(),
+            decoder: open_decoder(path)?,
+            stream_pos: 0,
+            data_start: 0,
+            chunks: Vec::new(),
+            size: 0,
+            pos: 0,
+        };
+        image.skip_to(header_pos)?;
+        let mut header = Header::new_old();
+        image.read_stream(header.as_mut_bytes())?;
+        let size = header.entry_size()?;
+        match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                image.size = size;
+                image.chunks.push(Chunk {
+                    offset: 0,
+                    len: size,
+                    stored: 0,
+                });
+            }
+            EntryType::GNUSparse => {
+                let gnu = header
+                    .as_gnu()
+                    .context("sparse tar member without a GNU header")?;
+                let mut blocks = Vec::new();
+                for block in gnu.sparse.iter().filter(|b| !b.is_empty()) {
+                    blocks.push((block.offset()?, block.length()?));
+                }
+                let mut extended = gnu.is_extended();
+                while extended {
+                    let mut ext = GnuExtSparseHeader::new();
+                    image.read_stream(ext.as_mut_bytes())?;
+                    for block in ext.sparse().iter().filter(|b| !b.is_empty()) {
+                        blocks.push((block.offset()?, block.length()?));
+                    }
+                    extended = ext.is_extended();
+                }
+                let mut stored = 0;
+                let mut end = 0;
+                for (offset, len) in blocks {
+                    ensure!(offset >= end, "out of order sparse tar blocks");
+                    end = offset + len;
+                    if len > 0 {
+                        image.chunks.push(Chunk {
+                            offset,
+                            len,
+                            stored,
+                        });
+                        stored += len;
+                    }
+                }
+                ensure!(
+                    stored == size,
+                    "sparse tar map does not match the member size"
+                );
+                image.size = gnu.real_size()?;
+                ensure!(end <= image.size, "sparse tar map exceeds the file size");
+            }
+            other => bail!("{DISK_NAME} is a {other:?} tar member, not a file"),
+        }
+        image.data_start = image.stream_pos;
+        Ok(image)
+    }
+
+    /// Size of the guest-visible disk in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.size
+    }
+
+    /// Bytes of disk data stored in the archive, excluding holes.
+    pub fn stored_size(&self) -> u64 {
+        self.chunks.iter().map(|c| c.len).sum()
+    }
+
+    fn read_stream(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.decoder.read_exact(buf)?;
+        self.stream_pos += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Move the decompressed stream to `target`, restarting it if `target` lies behind.
+    fn skip_to(&mut self, target: u64) -> io::Result<()> {
+        if target < self.stream_pos {
+            self.decoder = open_decoder(&self.path).map_err(io::Error::other)?;
+            self.stream_pos = 0;
+        }
+        let skipped = io::copy(
+            &mut (&mut self.decoder).take(target - self.stream_pos),
+            &mut io::sink(),
+        )?;
+        self.stream_pos += skipped;
+        if self.stream_pos != target {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "tar archive is truncated",
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_mapped_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let index = self.chunks.partition_point(|c| c.offset + c.len <= offset);
+        let Some(chunk) = self.chunks.get(index).copied() else {
+            buf.fill(0);
+            return Ok(buf.len());
+        };
+        if offset < chunk.offset {
+            let n = buf.len().min((chunk.offset - offset) as usize);
+            buf[..n].fill(0);
+            return Ok(n);
+        }
+        let n = buf.len().min((chunk.offset + chunk.len - offset) as usize);
+        self.skip_to(self.data_start + chunk.stored + (offset - chunk.offset))?;
+        self.read_stream(&mut buf[..n])?;
+        Ok(n)
+    }
+}
+
+fn open_decoder(path: &Path) -> Result<MultiGzDecoder<BufReader<File>>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    Ok(MultiGzDecoder::new(BufReader::with_capacity(1 << 20, file)))
+}
+
+impl VirtualDisk for GceTar {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn extents(&mut self) -> Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        let mut end = 0;
+        for chunk in &self.chunks {
+            push_extent(&mut extents, end, chunk.offset - end, ExtentKind::Zero);
+            push_extent(&mut extents, chunk.offset, chunk.len, ExtentKind::Data);
+            end = chunk.offset + chunk.len;
+        }
+        push_extent(&mut extents, end, self.size - end, ExtentKind::Zero);
+        Ok(extents)
+    }
+}
+
+impl Read for GceTar {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = (buf.len() as u64).min(self.size - self.pos) as usize;
+        let n = self.read_mapped_at(self.pos, &mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for GceTar {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
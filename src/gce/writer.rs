@@ -0,0 +1,138 @@
+//! Writer producing GCE image tarballs.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tar::{EntryType, GnuExtSparseHeader, Header};
+use tracing::info;
+
+use super::{BLOCK, DISK_NAME};
+use crate::disk::{DiskWriter, VirtualDisk};
+use crate::util::{human_size, stream_len};
+
+// GCE only imports disks whose size is a whole number of GiB.
+const ALIGNMENT: u64 = 1 << 30;
+// Granularity at which zero runs become holes.
+const SCAN_CHUNK: u64 = 64 << 10;
+// Sparse map entries in each extension header.
+const EXT_SPARSE_ENTRIES: usize = 21;
+
+/// The GCE image tarball output format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GceTarball;
+
+impl DiskWriter for GceTarball {
+    fn write_disk(&self, disk: &mut dyn VirtualDisk, path: &Path) -> Result<()> {
+        write_gce_tar(disk, path)
+    }
+}
+
+/// Write the guest disk exposed by `disk` to a new GCE image tarball at `path`.
+///
+/// The disk is stored as a sparse `disk.raw` in an old-GNU tar, rounded up to a whole number
+/// of GiB, ready for `gcloud compute images create --source-uri`. The disk is read twice: once
+/// to find its holes and once to copy its data.
+pub fn write_gce_tar<R: Read + Seek + ?Sized>(disk: &mut R, path: &Path) -> Result<()> {
+    let size = stream_len(disk)?;
+    let raw_size = size.next_multiple_of(ALIGNMENT).max(ALIGNMENT);
+    info!(
+        "writing {} gce image tarball to {}",
+        human_size(raw_size),
+        path.display()
+    );
+
+    let map = data_map(disk, size)?;
+    let stored: u64 = map.iter().map(|(_, len)| len).sum();
+    info!("{} of the disk holds data", human_size(stored));
+
+    // The map ends with an empty block at the end of the file, so readers know its size
+    // even when it ends in a hole.
+    let mut blocks = map.clone();
+    blocks.push((raw_size, 0));
+    let mut header = Header::new_gnu();
+    header.set_path(DISK_NAME)?;
+    header.set_mode(0o644);
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    );
+    header.set_entry_type(EntryType::GNUSparse);
+    header.set_size(stored);
+    let gnu = header.as_gnu_mut().unwrap();
+    gnu.set_real_size(raw_size);
+    let (first, rest) = blocks.split_at(blocks.len().min(gnu.sparse.len()));
+    for (slot, (offset, len)) in gnu.sparse.iter_mut().zip(first) {
+        slot.set_offset(*offset);
+        slot.set_length(*len);
+    }
+    gnu.set_is_extended(!rest.is_empty());
+    header.set_cksum();
+
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut out = GzEncoder::new(
+        BufWriter::with_capacity(4 << 20, file),
+        Compression::default(),
+    );
+    out.write_all(header.as_bytes())?;
+    let groups: Vec<_> = rest.chunks(EXT_SPARSE_ENTRIES).collect();
+    for (i, group) in groups.iter().enumerate() {
+        let mut ext = GnuExtSparseHeader::new();
+        for (slot, (offset, len)) in ext.sparse_mut().iter_mut().zip(group.iter()) {
+            slot.set_offset(*offset);
+            slot.set_length(*len);
+        }
+        ext.set_is_extended(i + 1 < groups.len());
+        out.write_all(ext.as_bytes())?;
+    }
+
+    let mut buf = vec![0u8; SCAN_CHUNK as usize];
+    for (offset, len) in map {
+        disk.seek(SeekFrom::Start(offset))?;
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(SCAN_CHUNK) as usize;
+            let available = size.saturating_sub(offset + done).min(n as u64) as usize;
+            buf[available..n].fill(0);
+            disk.read_exact(&mut buf[..available])
+                .with_context(|| format!("failed to read image at offset {}", offset + done))?;
+            out.write_all(&buf[..n])?;
+            done += n as u64;
+        }
+    }
+    let padding = stored.next_multiple_of(BLOCK) - stored;
+    // Pad the member, then end the archive with two empty blocks.
+    out.write_all(&vec![0; (padding + 2 * BLOCK) as usize])?;
+    let file = out.finish()?.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Offsets and lengths of the runs of `disk` that are not entirely zero.
+fn data_map<R: Read + Seek + ?Sized>(disk: &mut R, size: u64) -> Result<Vec<(u64, u64)>> {
+    let mut map: Vec<(u64, u64)> = Vec::new();
+    let mut buf = vec![0u8; SCAN_CHUNK as usize];
+    disk.seek(SeekFrom::Start(0))?;
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(SCAN_CHUNK) as usize;
+        disk.read_exact(&mut buf[..len])
+            .with_context(|| format!("failed to read image at offset {offset}"))?;
+        if buf[..len].iter().any(|&b| b != 0) {
+            // Runs stay a multiple of the tar block size, padding the disk's tail with zeroes.
+            let run = (len as u64).next_multiple_of(BLOCK);
+            match map.last_mut() {
+                Some((start, run_len)) if *start + *run_len == offset => *run_len += run,
+                _ => map.push((offset, run)),
+            }
+        }
+        offset += len as u64;
+    }
+    Ok(map)
+}
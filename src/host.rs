@@ -0,0 +1,103 @@
+//! Detecting whether `vmi` runs on an EC2 instance, which attaching volumes requires.
+//!
+//! The firmware's DMI data names Amazon on Nitro instances and carries an `ec2` UUID on Xen
+//! ones. Lookups take the filesystem root so they can run against a fake `/sys`.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tracing::debug;
+
+use crate::imds::ImdsClient;
+
+const IMDS_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What the DMI data under a filesystem root says about the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmiEvidence {
+    /// The host is an EC2 instance, as shown by the named file.
+    Ec2(&'static str),
+    NotEc2,
+    /// No DMI data is readable, as in some containers.
+    Unknown,
+}
+
+/// Inspect the DMI data and Xen hypervisor UUID under the filesystem `root`.
+pub fn dmi_evidence(root: &Path) -> DmiEvidence {
+    let read = |path: &str| {
+        let value = fs::read_to_string(root.join(path)).ok()?;
+        Some(value.trim().to_ascii_lowercase())
+    };
+    let mut readable = false;
+    for path in [
+        "sys/class/dmi/id/board_vendor",
+        "sys/class/dmi/id/sys_vendor",
+    ] {
+        if let Some(vendor) = read(path) {
+            readable = true;
+            if vendor == "amazon ec2" {
+                return DmiEvidence::Ec2(path);
+            }
+        }
+    }
+    // Xen instances have UUIDs starting with `ec2`, which DMI may give in little-endian.
+    for path in ["sys/hypervisor/uuid", "sys/class/dmi/id/product_uuid"] {
+        if let Some(uuid) = read(path) {
+            readable = true;
+            let swapped: String = uuid
+                .get(..8)
+                .unwrap_or_default()
+                .as_bytes()
+                .chunks(2)
+                .rev()
+                .map(|byte| String::from_utf8_lossy(byte).into_owned())
+                .collect();
+            if uuid.starts_with("ec2") || swapped.starts_with("ec2") {
+                return DmiEvidence::Ec2(path);
+            }
+        }
+    }
+    if readable {
+        DmiEvidence::NotEc2
+    } else {
+        DmiEvidence::Unknown
+    }
+}
+
+/// Check that this host, with its filesystem at `root`, is an EC2 instance whose metadata
+/// service answers through `imds`, so volumes can be attached to it.
+pub async fn ensure_ec2_host(root: &Path, imds: &ImdsClient) -> Result<()> {
+    let evidence = dmi_evidence(root);
+    debug!("DMI evidence of EC2: {evidence:?}");
+    if evidence == DmiEvidence::NotEc2 {
+        bail!(
+            "this host is not an EC2 instance, so AMI volumes cannot be attached to it; \
+             convert the AMI to an image file with `--ami-access direct` instead"
+        );
+    }
+    let probe = ImdsClient::new(imds.endpoint())
+        .with_timeout(IMDS_PROBE_TIMEOUT)
+        .with_attempts(1);
+    let result = probe.instance_id().await;
+    match (evidence, result) {
+        (_, Ok(instance_id)) => {
+            debug!("running on {instance_id}");
+            Ok(())
+        }
+        (DmiEvidence::Ec2(_), Err(e)) => Err(e).with_context(|| {
+            format!(
+                "this host is an EC2 instance but its metadata service at {} does not \
+                 answer; check the endpoint and the hop limit of the instance's metadata \
+                 options",
+                imds.endpoint()
+            )
+        }),
+        (_, Err(e)) => Err(e).context(
+            "this host does not look like an EC2 instance, so AMI volumes cannot be \
+             attached to it; convert the AMI to an image file with `--ami-access direct` \
+             instead",
+        ),
+    }
+}
@@ -0,0 +1,232 @@
+//! Client of the EC2 instance metadata service (IMDS).
+//!
+//! Requests use IMDSv2 session tokens, which are cached until shortly before they expire.
+//! When no token can be had, as with IMDSv1-only endpoints or a hop limit too low for
+//! containers, the client falls back to unauthenticated IMDSv1 requests.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use hyper::{client::HttpConnector, Body, Client, Request, StatusCode};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+/// The IPv4 endpoint of the instance metadata service.
+pub const DEFAULT_ENDPOINT: &str = "http://169.254.169.254";
+/// The IPv6 endpoint, available on Nitro instances.
+pub const IPV6_ENDPOINT: &str = "http://[fd00:ec2::254]";
+/// Environment variable overriding the endpoint, as used by the AWS SDKs.
+pub const ENDPOINT_ENV: &str = "AWS_EC2_METADATA_SERVICE_ENDPOINT";
+/// Environment variable choosing the `IPv4` or `IPv6` endpoint, as used by the AWS SDKs.
+pub const ENDPOINT_MODE_ENV: &str = "AWS_EC2_METADATA_SERVICE_ENDPOINT_MODE";
+
+const TOKEN_TTL: Duration = Duration::from_secs(21600);
+// Tokens are refreshed this long before they expire, so they stay valid in flight.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// The instance identity document, with the fields `vmi` uses.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityDocument {
+    pub account_id: String,
+    pub region: String,
+    pub availability_zone: String,
+    pub instance_id: String,
+    pub instance_type: String,
+    pub image_id: String,
+    pub architecture: String,
+}
+
+#[derive(Debug)]
+enum Session {
+    Token {
+        token: String,
+        expires: Instant,
+    },
+    /// The endpoint gave no token, so requests go without one.
+    V1,
+}
+
+/// Client of the instance metadata service.
+#[derive(Debug)]
+pub struct ImdsClient {
+    endpoint: String,
+    client: Client<HttpConnector>,
+    session: Mutex<Option<Session>>,
+    timeout: Duration,
+    attempts: u32,
+}
+
+impl ImdsClient {
+    /// A client of the service at `endpoint`, a URL or bare host such as `fd00:ec2::254`.
+    pub fn new(endpoint: &str) -> Self {
+        ImdsClient {
+            endpoint: normalize_endpoint(endpoint),
+            client: Client::new(),
+            session: Mutex::new(None),
+            timeout: Duration::from_secs(1),
+            attempts: 3,
+        }
+    }
+
+    /// A client of the endpoint chosen by [`ENDPOINT_ENV`] or [`ENDPOINT_MODE_ENV`], or the
+    /// default IPv4 one.
+    pub fn from_env() -> Self {
+        if let Ok(endpoint) = std::env::var(ENDPOINT_ENV) {
+            return Self::new(&endpoint);
+        }
+        match std::env::var(ENDPOINT_MODE_ENV) {
+            Ok(mode) if mode.eq_ignore_ascii_case("ipv6") => Self::new(IPV6_ENDPOINT),
+            _ => Self::new(DEFAULT_ENDPOINT),
+        }
+    }
+
+    /// Timeout of each request.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Number of times each request is tried before giving up.
+    pub fn with_attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Read the value at `path` (e.g. `/latest/meta-data/instance-id`).
+    pub async fn get(&self, path: &str) -> Result<String> {
+        let token = self.token().await?;
+        let (status, body) = self.send("GET", path, token.as_deref()).await?;
+        let (status, body) = if status == StatusCode::UNAUTHORIZED && token.is_some() {
+            // The token was revoked or expired early; get a fresh one.
+            *self.session.lock().await = None;
+            let token = self.token().await?;
+            self.send("GET", path, token.as_deref()).await?
+        } else {
+            (status, body)
+        };
+        if !status.is_success() {
+            bail!("failed to read {path} from the instance metadata service: {status}");
+        }
+        Ok(body)
+    }
+
+    pub async fn instance_id(&self) -> Result<String> {
+        self.get("/latest/meta-data/instance-id").await
+    }
+
+    pub async fn availability_zone(&self) -> Result<String> {
+        self.get("/latest/meta-data/placement/availability-zone")
+            .await
+    }
+
+    pub async fn instance_type(&self) -> Result<String> {
+        self.get("/latest/meta-data/instance-type").await
+    }
+
+    pub async fn identity_document(&self) -> Result<IdentityDocument> {
+        let document = self
+            .get("/latest/dynamic/instance-identity/document")
+            .await?;
+        serde_json::from_str(&document).context("failed to parse the instance identity document")
+    }
+
+    pub async fn region(&self) -> Result<String> {
+        Ok(self.identity_document().await?.region)
+    }
+
+    /// The session token to send, or `None` when falling back to IMDSv1.
+    async fn token(&self) -> Result<Option<String>> {
+        let mut session = self.session.lock().await;
+        if let Some(Session::Token { token, expires }) = &*session {
+            if Instant::now() + TOKEN_REFRESH_MARGIN < *expires {
+                return Ok(Some(token.clone()));
+            }
+        }
+        if let Some(Session::V1) = &*session {
+            return Ok(None);
+        }
+        let requested = Instant::now();
+        let (token, new_session) = match self.send("PUT", "/latest/api/token", None).await {
+            Ok((status, token)) if status.is_success() => {
+                let expires = requested + TOKEN_TTL;
+                (Some(token.clone()), Session::Token { token, expires })
+            }
+            // IMDSv2 is available but tokens are refused, so IMDSv1 would be too.
+            Ok((StatusCode::FORBIDDEN, _)) => {
+                bail!("the instance metadata service refused to issue a token")
+            }
+            Ok((status, _)) => {
+                debug!("no IMDSv2 token ({status}), falling back to IMDSv1");
+                (None, Session::V1)
+            }
+            Err(e) => {
+                warn!("no IMDSv2 token ({e:#}), falling back to IMDSv1");
+                (None, Session::V1)
+            }
+        };
+        *session = Some(new_session);
+        Ok(token)
+    }
+
+    /// Send a request, retrying failed connections and server errors with backoff.
+    async fn send(
+        &self,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+    ) -> Result<(StatusCode, String)> {
+        let uri = format!("{}{path}", self.endpoint);
+        let mut attempt = 0;
+        loop {
+            let mut req = Request::builder().method(method).uri(&uri);
+            req = match token {
+                Some(token) => req.header("X-aws-ec2-metadata-token", token),
+                None if method == "PUT" => req.header(
+                    "X-aws-ec2-metadata-token-ttl-seconds",
+                    TOKEN_TTL.as_secs().to_string(),
+                ),
+                None => req,
+            };
+            let result = async {
+                let resp = timeout(self.timeout, self.client.request(req.body(Body::empty())?))
+                    .await
+                    .context("timed out")??;
+                let status = resp.status();
+                let body = hyper::body::to_bytes(resp.into_body()).await?;
+                anyhow::Ok((status, String::from_utf8(body.to_vec())?))
+            }
+            .await;
+            attempt += 1;
+            let retry = match &result {
+                Ok((status, _)) => {
+                    status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(_) => true,
+            };
+            if !retry || attempt >= self.attempts {
+                return result.with_context(|| format!("failed to request {uri}"));
+            }
+            debug!("retrying {method} {uri} after attempt {attempt}");
+            tokio::time::sleep(Duration::from_millis(100 << attempt)).await;
+        }
+    }
+}
+
+/// `endpoint` as a URL without a trailing slash, bracketing bare IPv6 addresses.
+fn normalize_endpoint(endpoint: &str) -> String {
+    let endpoint = endpoint.trim_end_matches('/');
+    if endpoint.contains("://") {
+        endpoint.to_string()
+    } else if endpoint.matches(':').count() > 1 && !endpoint.starts_with('[') {
+        format!("http://[{endpoint}]")
+    } else {
+        format!("http://{endpoint}")
+    }
+}
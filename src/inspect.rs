@@ -0,0 +1,544 @@
+//! Inspection of the guest-visible contents of a disk image.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use aws_sdk_ec2::types::Image;
+use schemars::{JsonSchema, Schema};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::ami::describe_ami;
+use crate::filesystem::{detect_filesystem, Filesystem};
+use crate::gce::GceTar;
+use crate::ovf::{Appliance, Ova};
+use crate::partition::{read_partition_table, Partition, PartitionTable};
+use crate::qcow2::Qcow2;
+use crate::util::{allocated_size, human_size, stream_len};
+use crate::vdi::Vdi;
+use crate::vhd::Vhd;
+use crate::vhdx::Vhdx;
+use crate::vmdk::Vmdk;
+
+/// Version of the machine-readable [`DiskReport`] schema.
+///
+/// Bump this whenever a field is removed, renamed or changes meaning. Adding optional fields
+/// does not require a new version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Everything `vmi inspect` reports about a disk image.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct DiskReport {
+    /// Version of the report schema; changes only on breaking changes.
+    pub schema_version: u32,
+    /// Image format, e.g. `raw`.
+    pub format: String,
+    /// Size of the disk as seen by a guest, in bytes.
+    pub virtual_size: u64,
+    /// Bytes actually used by the image on its storage, when known.
+    pub allocated_size: Option<u64>,
+    /// Image that unallocated regions are read from, for overlay formats.
+    pub backing_file: Option<String>,
+    pub partition_table: Option<PartitionTable>,
+    /// Filesystem written directly to the unpartitioned disk.
+    pub filesystem: Option<Filesystem>,
+    /// Metadata of the cloud provider object the image was read from.
+    pub cloud: Option<CloudMetadata>,
+    /// Virtual hardware of the appliance the disk belongs to, for OVA images.
+    pub appliance: Option<Appliance>,
+}
+
+/// Provider-side metadata of a cloud machine image.
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct CloudMetadata {
+    /// Cloud provider, e.g. `aws`.
+    pub provider: String,
+    /// Provider image identifier, e.g. `ami-0123456789abcdef0`.
+    pub image_id: String,
+    pub name: Option<String>,
+    pub region: Option<String>,
+    pub tags: BTreeMap<String, String>,
+    pub description: Option<String>,
+    /// Account owning the image.
+    pub owner_id: Option<String>,
+    /// Alias of the owner, e.g. `amazon`.
+    pub owner_alias: Option<String>,
+    /// Creation time, in ISO 8601.
+    pub creation_date: Option<String>,
+    /// Provider-side state, e.g. `available`.
+    pub state: Option<String>,
+    pub public: Option<bool>,
+    /// CPU architecture, e.g. `x86_64`.
+    pub architecture: Option<String>,
+    /// Virtualization type, e.g. `hvm`.
+    pub virtualization_type: Option<String>,
+    /// Firmware the image boots with, e.g. `uefi`.
+    pub boot_mode: Option<String>,
+    /// Whether Elastic Network Adapter (ENA) networking is enabled.
+    pub ena_support: Option<bool>,
+    /// TPM version exposed to instances, e.g. `v2.0`.
+    pub tpm_support: Option<String>,
+    pub root_device_name: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub block_devices: Vec<CloudBlockDevice>,
+}
+
+/// A block device mapping of a cloud machine image.
+#[derive(Debug, Clone, Default, Serialize, JsonSchema)]
+pub struct CloudBlockDevice {
+    /// Device name, e.g. `/dev/xvda`.
+    pub device_name: String,
+    /// Instance store volume, e.g. `ephemeral0`, for mappings without a snapshot.
+    pub virtual_name: Option<String>,
+    /// Whether the mapping suppresses a device of the image.
+    pub no_device: bool,
+    pub snapshot_id: Option<String>,
+    /// Size of the volume, in bytes.
+    pub volume_size: Option<u64>,
+    /// Bytes of data stored in the snapshot, when known.
+    pub snapshot_size: Option<u64>,
+    /// Volume type, e.g. `gp3`.
+    pub volume_type: Option<String>,
+    pub iops: Option<u32>,
+    /// Throughput, in MiB/s.
+    pub throughput: Option<u32>,
+    pub encrypted: Option<bool>,
+    pub kms_key_id: Option<String>,
+    pub delete_on_termination: Option<bool>,
+}
+
+/// JSON schema describing the serialized form of [`DiskReport`].
+pub fn report_schema() -> Schema {
+    schemars::schema_for!(DiskReport)
+}
+
+/// Inspect a local Raw format image or block device.
+pub fn inspect_raw(path: &Path) -> Result<DiskReport> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut report = inspect_disk(&mut file, "raw")?;
+    report.allocated_size = allocated_size(&file)?;
+    Ok(report)
+}
+
+/// Inspect a local QCOW2 image.
+pub fn inspect_qcow2(path: &Path) -> Result<DiskReport> {
+    let mut image = Qcow2::open_path(path)?;
+    let mut report = inspect_disk(&mut image, "qcow2")?;
+    report.allocated_size = Some(image.allocated_size()?);
+    report.backing_file = image.header().backing_file.clone();
+    Ok(report)
+}
+
+/// Inspect a local VMDK disk, given its descriptor or monolithic sparse file.
+pub fn inspect_vmdk(path: &Path) -> Result<DiskReport> {
+    let mut image = Vmdk::open_path(path)?;
+    let mut report = inspect_disk(&mut image, "vmdk")?;
+    report.allocated_size = Some(image.allocated_size());
+    Ok(report)
+}
+
+/// Inspect a local VDI; for a differencing image the parent becomes the backing file.
+pub fn inspect_vdi(path: &Path) -> Result<DiskReport> {
+    let mut image = Vdi::open_path(path)?;
+    let mut report = inspect_disk(&mut image, "vdi")?;
+    let file = File::open(path)?;
+    report.allocated_size = allocated_size(&file)?;
+    report.backing_file = image.parent_path().map(|p| p.display().to_string());
+    Ok(report)
+}
+
+/// Inspect a local VHD; for a differencing disk the parent becomes the backing file.
+pub fn inspect_vhd(path: &Path) -> Result<DiskReport> {
+    let mut image = Vhd::open_path(path)?;
+    let mut report = inspect_disk(&mut image, "vhd")?;
+    let file = File::open(path)?;
+    report.allocated_size = allocated_size(&file)?;
+    report.backing_file = image.parent_path().map(|p| p.display().to_string());
+    Ok(report)
+}
+
+/// Inspect a local VHDX, replaying its log in memory if it was not closed cleanly.
+pub fn inspect_vhdx(path: &Path) -> Result<DiskReport> {
+    let mut image = Vhdx::open_path(path)?;
+    let mut report = inspect_disk(&mut image, "vhdx")?;
+    let file = File::open(path)?;
+    report.allocated_size = allocated_size(&file)?;
+    report.backing_file = image.parent_path().map(|p| p.display().to_string());
+    Ok(report)
+}
+
+/// Inspect the `disk.raw` of a GCE image tarball; the allocated size is the data it stores.
+pub fn inspect_gce_tar(path: &Path) -> Result<DiskReport> {
+    let mut image = GceTar::open_path(path)?;
+    let mut report = inspect_disk(&mut image, "gce-tar")?;
+    report.allocated_size = Some(image.stored_size());
+    Ok(report)
+}
+
+/// Inspect an OVA appliance: its virtual hardware, manifest and first disk.
+pub fn inspect_ova(path: &Path) -> Result<DiskReport> {
+    let ova = Ova::open_path(path)?;
+    let appliance = ova.appliance()?;
+    ensure!(!appliance.disks.is_empty(), "OVA has no disks");
+    let mut report = inspect_disk(&mut ova.open_disk(0)?, "ova")?;
+    let file = File::open(path)?;
+    report.allocated_size = allocated_size(&file)?;
+    report.appliance = Some(appliance);
+    Ok(report)
+}
+
+/// Inspect an AMI through the EC2 API: its metadata, block device mappings and the
+/// snapshots behind them. The disk contents are not read.
+pub async fn inspect_ami(ec2_client: &aws_sdk_ec2::Client, ami_id: &str) -> Result<DiskReport> {
+    let image = describe_ami(ec2_client, ami_id).await?;
+    let region = ec2_client.config().region().map(|r| r.to_string());
+    let mut cloud = ami_metadata(&image, region);
+    for device in &mut cloud.block_devices {
+        let Some(snapshot_id) = &device.snapshot_id else {
+            continue;
+        };
+        // Snapshots of AMIs shared with this account are often not visible to it.
+        let snapshot = match ec2_client
+            .describe_snapshots()
+            .snapshot_ids(snapshot_id)
+            .send()
+            .await
+        {
+            Ok(output) => output.snapshots.unwrap_or_default().into_iter().next(),
+            Err(e) => {
+                debug!("failed to describe {snapshot_id}: {e}");
+                None
+            }
+        };
+        if let Some(snapshot) = snapshot {
+            device.snapshot_size = snapshot
+                .full_snapshot_size_in_bytes()
+                .and_then(|size| u64::try_from(size).ok());
+            device.encrypted = device.encrypted.or(snapshot.encrypted());
+            if device.kms_key_id.is_none() {
+                device.kms_key_id = snapshot.kms_key_id().map(str::to_string);
+            }
+        }
+    }
+
+    let root = cloud
+        .block_devices
+        .iter()
+        .find(|d| Some(&d.device_name) == cloud.root_device_name.as_ref());
+    let ebs: Vec<_> = cloud
+        .block_devices
+        .iter()
+        .filter(|d| d.snapshot_id.is_some())
+        .collect();
+    Ok(DiskReport {
+        schema_version: SCHEMA_VERSION,
+        format: "ami".to_string(),
+        virtual_size: root.and_then(|d| d.volume_size).unwrap_or(0),
+        allocated_size: ebs.iter().map(|d| d.snapshot_size).sum(),
+        backing_file: None,
+        partition_table: None,
+        filesystem: None,
+        cloud: Some(cloud),
+        appliance: None,
+    })
+}
+
+/// The metadata of an AMI described in `region`.
+pub fn ami_metadata(image: &Image, region: Option<String>) -> CloudMetadata {
+    let block_devices = image
+        .block_device_mappings()
+        .iter()
+        .map(|mapping| {
+            let ebs = mapping.ebs();
+            CloudBlockDevice {
+                device_name: mapping.device_name().unwrap_or_default().to_string(),
+                virtual_name: mapping.virtual_name().map(str::to_string),
+                no_device: mapping.no_device().is_some(),
+                snapshot_id: ebs.and_then(|e| e.snapshot_id()).map(str::to_string),
+                volume_size: ebs
+                    .and_then(|e| e.volume_size())
+                    .and_then(|gib| u64::try_from(gib).ok())
+                    .map(|gib| gib << 30),
+                snapshot_size: None,
+                volume_type: ebs.and_then(|e| e.volume_type()).map(|t| t.to_string()),
+                iops: ebs
+                    .and_then(|e| e.iops())
+                    .and_then(|n| u32::try_from(n).ok()),
+                throughput: ebs
+                    .and_then(|e| e.throughput())
+                    .and_then(|n| u32::try_from(n).ok()),
+                encrypted: ebs.and_then(|e| e.encrypted()),
+                kms_key_id: ebs.and_then(|e| e.kms_key_id()).map(str::to_string),
+                delete_on_termination: ebs.and_then(|e| e.delete_on_termination()),
+            }
+        })
+        .collect();
+    CloudMetadata {
+        provider: "aws".to_string(),
+        image_id: image.image_id().unwrap_or_default().to_string(),
+        name: image.name().map(str::to_string),
+        region,
+        tags: image
+            .tags()
+            .iter()
+            .filter_map(|t| Some((t.key()?.to_string(), t.value()?.to_string())))
+            .collect(),
+        description: image.description().map(str::to_string),
+        owner_id: image.owner_id().map(str::to_string),
+        owner_alias: image.image_owner_alias().map(str::to_string),
+        creation_date: image.creation_date().map(str::to_string),
+        state: image.state().map(|s| s.to_string()),
+        public: image.public(),
+        architecture: image.architecture().map(|a| a.to_string()),
+        virtualization_type: image.virtualization_type().map(|v| v.to_string()),
+        boot_mode: image.boot_mode().map(|b| b.to_string()),
+        ena_support: image.ena_support(),
+        tpm_support: image.tpm_support().map(|t| t.to_string()),
+        root_device_name: image.root_device_name().map(str::to_string),
+        block_devices,
+    }
+}
+
+/// Inspect the guest-visible disk exposed by `disk`.
+pub fn inspect_disk<R: Read + Seek + ?Sized>(disk: &mut R, format: &str) -> Result<DiskReport> {
+    let virtual_size = stream_len(disk)?;
+    let mut partition_table = read_partition_table(disk, virtual_size)?;
+
+    let mut filesystem = None;
+    match &mut partition_table {
+        Some(table) => {
+            let sector_size = table.sector_size;
+            for p in &mut table.partitions {
+                p.filesystem = probe_partition(disk, sector_size, p, virtual_size)?;
+            }
+        }
+        None => filesystem = detect_filesystem(disk, 0, virtual_size)?,
+    }
+
+    Ok(DiskReport {
+        schema_version: SCHEMA_VERSION,
+        format: format.to_string(),
+        virtual_size,
+        allocated_size: None,
+        backing_file: None,
+        partition_table,
+        filesystem,
+        cloud: None,
+        appliance: None,
+    })
+}
+
+fn probe_partition<R: Read + Seek + ?Sized>(
+    disk: &mut R,
+    sector_size: u64,
+    p: &Partition,
+    disk_size: u64,
+) -> Result<Option<Filesystem>> {
+    let start = p.start_lba * sector_size;
+    let len = p.sectors() * sector_size;
+    if p.flags.contains(&"protective") || p.type_name == Some("Extended") || start >= disk_size {
+        return Ok(None);
+    }
+    detect_filesystem(disk, start, len.min(disk_size - start))
+}
+
+impl fmt::Display for DiskReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Format:         {}", self.format)?;
+        writeln!(
+            f,
+            "Virtual size:   {} ({} bytes)",
+            human_size(self.virtual_size),
+            self.virtual_size
+        )?;
+        if let Some(allocated) = self.allocated_size {
+            writeln!(
+                f,
+                "Allocated size: {} ({} bytes)",
+                human_size(allocated),
+                allocated
+            )?;
+        }
+        if let Some(backing) = &self.backing_file {
+            writeln!(f, "Backing file:   {backing}")?;
+        }
+        if let Some(appliance) = &self.appliance {
+            write_appliance(f, appliance)?;
+        }
+        if let Some(cloud) = &self.cloud {
+            write_cloud(f, cloud)?;
+            if self.partition_table.is_none() && self.filesystem.is_none() {
+                // The contents of the image were not read.
+                return Ok(());
+            }
+        }
+
+        let Some(table) = &self.partition_table else {
+            writeln!(f, "Partition table: none")?;
+            return match &self.filesystem {
+                Some(fs) => writeln!(f, "Filesystem:     {}", describe_filesystem(fs)),
+                None => writeln!(f, "Filesystem:     unknown"),
+            };
+        };
+
+        writeln!(
+            f,
+            "Partition table: {} (disk id {}, {}-byte sectors)",
+            table.kind, table.disk_id, table.sector_size
+        )?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:>3}  {:>12}  {:>12}  {:>10}  {:<24}  {:<16}  Filesystem",
+            "#", "Start", "End", "Size", "Type", "Name"
+        )?;
+        for p in &table.partitions {
+            let type_desc = p.type_name.unwrap_or(&p.type_id);
+            writeln!(
+                f,
+                "{:>3}  {:>12}  {:>12}  {:>10}  {:<24}  {:<16}  {}",
+                p.number,
+                p.start_lba,
+                p.end_lba,
+                human_size(p.sectors() * table.sector_size),
+                type_desc,
+                p.name.as_deref().unwrap_or("-"),
+                p.filesystem
+                    .as_ref()
+                    .map_or("-".to_string(), describe_filesystem),
+            )?;
+            if let Some(guid) = &p.guid {
+                writeln!(f, "     type {}  guid {}", p.type_id, guid)?;
+            }
+            if !p.flags.is_empty() {
+                writeln!(f, "     flags: {}", p.flags.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_appliance(f: &mut fmt::Formatter<'_>, appliance: &Appliance) -> fmt::Result {
+    writeln!(
+        f,
+        "Appliance:      {}",
+        appliance.name.as_deref().unwrap_or("-")
+    )?;
+    if let Some(os) = &appliance.os {
+        writeln!(f, "  OS:           {os}")?;
+    }
+    if let Some(cpus) = appliance.cpus {
+        writeln!(f, "  CPUs:         {cpus}")?;
+    }
+    if let Some(memory) = appliance.memory {
+        writeln!(f, "  Memory:       {}", human_size(memory))?;
+    }
+    for nic in &appliance.nics {
+        writeln!(
+            f,
+            "  NIC:          {} {} on {}",
+            nic.name.as_deref().unwrap_or("-"),
+            nic.adapter.as_deref().unwrap_or("unknown"),
+            nic.network.as_deref().unwrap_or("no network")
+        )?;
+    }
+    for disk in &appliance.disks {
+        writeln!(
+            f,
+            "  Disk:         {} {} {} on {}",
+            disk.id,
+            disk.file.as_deref().unwrap_or("(blank)"),
+            human_size(disk.capacity),
+            disk.controller.as_deref().unwrap_or("unknown controller")
+        )?;
+    }
+    for check in &appliance.manifest {
+        let status = if check.valid { "ok" } else { "MISMATCH" };
+        writeln!(
+            f,
+            "  Manifest:     {} {} {status}",
+            check.file, check.algorithm
+        )?;
+    }
+    writeln!(f)
+}
+
+fn write_cloud(f: &mut fmt::Formatter<'_>, cloud: &CloudMetadata) -> fmt::Result {
+    let name = cloud.name.as_deref().unwrap_or("-");
+    writeln!(f, "Image:          {} ({name})", cloud.image_id)?;
+    let field = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+    let flag = |value: Option<bool>| match value {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "-",
+    };
+    if let Some(description) = &cloud.description {
+        writeln!(f, "  Description:  {description}")?;
+    }
+    writeln!(
+        f,
+        "  Provider:     {} {}",
+        cloud.provider,
+        field(&cloud.region)
+    )?;
+    match &cloud.owner_alias {
+        Some(alias) => writeln!(f, "  Owner:        {} ({alias})", field(&cloud.owner_id))?,
+        None => writeln!(f, "  Owner:        {}", field(&cloud.owner_id))?,
+    }
+    writeln!(f, "  Created:      {}", field(&cloud.creation_date))?;
+    writeln!(f, "  State:        {}", field(&cloud.state))?;
+    writeln!(f, "  Public:       {}", flag(cloud.public))?;
+    writeln!(f, "  Architecture: {}", field(&cloud.architecture))?;
+    writeln!(f, "  Virt type:    {}", field(&cloud.virtualization_type))?;
+    writeln!(f, "  Boot mode:    {}", field(&cloud.boot_mode))?;
+    writeln!(f, "  ENA support:  {}", flag(cloud.ena_support))?;
+    writeln!(f, "  TPM support:  {}", field(&cloud.tpm_support))?;
+    writeln!(f, "  Root device:  {}", field(&cloud.root_device_name))?;
+    for device in &cloud.block_devices {
+        let detail = match (&device.snapshot_id, &device.virtual_name) {
+            _ if device.no_device => "suppressed".to_string(),
+            (Some(snapshot_id), _) => {
+                let mut detail = snapshot_id.clone();
+                if let Some(size) = device.volume_size {
+                    detail.push_str(&format!(" {}", human_size(size)));
+                }
+                if let Some(size) = device.snapshot_size {
+                    detail.push_str(&format!(" ({} stored)", human_size(size)));
+                }
+                if let Some(volume_type) = &device.volume_type {
+                    detail.push_str(&format!(" {volume_type}"));
+                }
+                if device.encrypted == Some(true) {
+                    detail.push_str(" encrypted");
+                    if let Some(key) = &device.kms_key_id {
+                        detail.push_str(&format!(" with {key}"));
+                    }
+                }
+                detail
+            }
+            (None, Some(virtual_name)) => format!("instance store {virtual_name}"),
+            (None, None) => "empty EBS volume".to_string(),
+        };
+        writeln!(f, "  Device:       {} {detail}", device.device_name)?;
+    }
+    for (key, value) in &cloud.tags {
+        writeln!(f, "  Tag:          {key}={value}")?;
+    }
+    writeln!(f)
+}
+
+fn describe_filesystem(fs: &Filesystem) -> String {
+    let mut s = fs.kind.to_string();
+    if let Some(label) = &fs.label {
+        s.push_str(&format!(" label={label:?}"));
+    }
+    if let Some(uuid) = &fs.uuid {
+        s.push_str(&format!(" uuid={uuid}"));
+    }
+    s
+}
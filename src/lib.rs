@@ -1,85 +1,96 @@
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use anyhow::{ensure, Context, Result};
 use aws_sdk_ec2::client::Waiters;
-use hyper::{client::HttpConnector, Body, Client, Request};
-use tokio::time::timeout;
-
-use tracing::{debug, info};
-
-// Acquire a token from the AWS API.
-async fn get_ec2_token(client: &Client<HttpConnector>) -> Result<String> {
-    const AWS_TOKEN_API_URL: &str = "http://169.254.169.254/latest/api/token";
-    let req = Request::builder()
-        .method("PUT")
-        .uri(AWS_TOKEN_API_URL)
-        .header("X-aws-ec2-metadata-token-ttl-seconds", "21600")
-        .body(Body::empty())?;
-    send(client, req).await.context("failed to get ec2 token")
+use aws_sdk_ec2::types::ResourceType;
+
+use tracing::{debug, info, warn};
+
+use crate::ami::{describe_ami_volumes, select_volume, VolumeSelector};
+use crate::cleanup::{owner_tags, CleanupGuard, Resource};
+use crate::host::ensure_ec2_host;
+use crate::imds::ImdsClient;
+use crate::nvme::{ebs_device_name, find_attached_device};
+
+pub mod ami;
+pub mod cleanup;
+pub mod device;
+pub mod disk;
+pub mod ebs;
+pub mod export;
+pub mod filesystem;
+pub mod gce;
+pub mod host;
+pub mod imds;
+pub mod inspect;
+pub mod nvme;
+pub mod ovf;
+pub mod partition;
+pub mod qcow2;
+pub mod raw;
+mod util;
+pub mod vdi;
+pub mod vhd;
+pub mod vhdx;
+pub mod vmdk;
+
+pub use util::ReadSeek;
+
+/// Find the snapshot backing the EBS volume of `ami_id` chosen by `volume`, or its root
+/// volume.
+pub async fn find_ami_snapshot(
+    ec2_client: &aws_sdk_ec2::Client,
+    ami_id: &str,
+    volume: Option<&VolumeSelector>,
+) -> Result<String> {
+    let volumes = describe_ami_volumes(ec2_client, ami_id).await?;
+    let volume = select_volume(&volumes, volume)
+        .with_context(|| format!("failed to find the snapshot of {ami_id}"))?;
+    Ok(volume.snapshot_id.clone())
 }
 
-// Read a metadata value from the AWS API.
-async fn get_ec2_value(client: &Client<HttpConnector>, url: &str, token: &str) -> Result<String> {
-    let req = Request::builder()
-        .method("GET")
-        .uri(url)
-        .header("X-aws-ec2-metadata-token", token)
-        .body(Body::empty())?;
-    send(client, req).await.context("failed to get ec2 value")
-}
-
-// Send an HTTP request, returning the body as a string.
-async fn send(client: &Client<HttpConnector>, req: Request<Body>) -> Result<String> {
-    let resp = timeout(Duration::from_secs(3), client.request(req)).await??;
-    let status = resp.status();
-    ensure!(status.is_success(), "failed metadata request: {status}");
-    let body_bytes = hyper::body::to_bytes(resp.into_body()).await?.to_vec();
-    Ok(std::str::from_utf8(&body_bytes)?.into())
-}
-
-async fn get_ec2_instance_id_and_zone() -> Result<(String, String)> {
-    const AWS_INSTANCE_ID_URL: &str = "http://169.254.169.254/latest/meta-data/instance-id";
-    const AWS_INSTANCE_AVAILABILITY_ZONE: &str =
-        "http://169.254.169.254/latest/meta-data/placement/availability-zone";
-    let client = Client::new();
-    let token = get_ec2_token(&client).await?;
-    let id = get_ec2_value(&client, AWS_INSTANCE_ID_URL, &token).await?;
-    let zone = get_ec2_value(&client, AWS_INSTANCE_AVAILABILITY_ZONE, &token).await?;
-    Ok((id, zone))
+/// Load an EBS volume of an Amazon Machine Image (AMI), the root volume unless `volume`
+/// chooses another, to a device on the current EC2 host.
+///
+/// Returns the block device the volume appeared as. When that is not `device_path`, as on
+/// Nitro instances, `device_path` is made a symlink to it if possible.
+///
+/// The host's instance and zone are read through `imds`. The volume, its attachment and the symlink are tracked by `cleanup`, and removed again
+/// if loading fails.
+pub async fn load_ami_to_device(
+    ami_id: String,
+    volume: Option<&VolumeSelector>,
+    device_path: String,
+    imds: &ImdsClient,
+    cleanup: &CleanupGuard,
+) -> Result<PathBuf> {
+    let result = attach_ami_volume(ami_id, volume, device_path, imds, cleanup).await;
+    if result.is_err() {
+        cleanup.cleanup().await;
+    }
+    result
 }
 
-/// Load an Amazon Machine Image (AMI) to a device on the current EC2 host.
-pub async fn load_ami_to_device(ami_id: String, device_path: String) -> Result<()> {
-    // TODO: check that host is EC2 instance.
-    // TODO: check that ami_id is valid.
+async fn attach_ami_volume(
+    ami_id: String,
+    volume: Option<&VolumeSelector>,
+    device_path: String,
+    imds: &ImdsClient,
+    cleanup: &CleanupGuard,
+) -> Result<PathBuf> {
+    ensure_ec2_host(Path::new("/"), imds).await?;
     ensure!(
-        !std::path::Path::new(&device_path).exists(),
+        !Path::new(&device_path).exists(),
         "device path {} already exists",
         device_path
     );
 
-    // Find the snapshot id
     let ec2_client = aws_sdk_ec2::Client::new(&aws_config::load_from_env().await);
-    let describe_images_output = ec2_client
-        .describe_images()
-        .image_ids(ami_id.clone())
-        .send()
-        .await?;
+    let snapshot_id = find_ami_snapshot(&ec2_client, &ami_id, volume).await?;
 
-    let snapshot_id = describe_images_output
-        .images
-        .unwrap_or_default()
-        .get(0)
-        .and_then(|image| {
-            image.block_device_mappings.as_ref().and_then(|mappings| {
-                mappings.get(0).and_then(|mapping| {
-                    mapping.ebs.as_ref().and_then(|ebs| ebs.snapshot_id.clone())
-                })
-            })
-        })
-        .expect("Failed to find snapshot ID for the given AMI ID");
-
-    let (ec2_host_instance_id, zone) = get_ec2_instance_id_and_zone().await?;
+    let ec2_host_instance_id = imds.instance_id().await?;
+    let zone = imds.availability_zone().await?;
 
     info!("ec2 host instance id: {}", ec2_host_instance_id);
     info!("snapshot id: {}", snapshot_id);
@@ -89,11 +100,13 @@ pub async fn load_ami_to_device(ami_id: String, device_path: String) -> Result<(
         .create_volume()
         .availability_zone(zone)
         .snapshot_id(snapshot_id)
+        .tag_specifications(owner_tags(ResourceType::Volume, &ami_id))
         .send()
         .await?;
     let volume_id = create_volume_output
         .volume_id
-        .expect("Failed to create volume");
+        .context("CreateVolume returned no volume ID")?;
+    cleanup.track(Resource::Volume(volume_id.clone()));
 
     let max_wait = Duration::from_secs(60);
     info!(
@@ -111,24 +124,56 @@ pub async fn load_ami_to_device(ami_id: String, device_path: String) -> Result<(
         .attach_volume()
         .device(device_path.clone())
         .volume_id(volume_id.clone())
-        .instance_id(ec2_host_instance_id)
+        .instance_id(ec2_host_instance_id.clone())
         .send()
         .await?;
-
-    // TODO(Jonathon): this doesn't actually work because EC2 uses dynamic device naming
-    // and puts the device at /dev/nvme2n1 or something.
-    //
-    // If the volume attach actually worked then you'll see a new /dev/nvme* in blockdev --report.
-    //
-    // $ sudo file -s /dev/nvme2n1
-    // /dev/nvme2n1: SGI XFS filesystem data (blksz 4096, inosz 512, v2 dirs)
-    //
-    // Above you can see the attach worked and gave me an XFS filesystem to mount.
-    //
-    while !std::path::Path::new(&device_path).exists() {
+    cleanup.track(Resource::Attachment {
+        volume_id: volume_id.clone(),
+        instance_id: ec2_host_instance_id,
+    });
+
+    // Nitro instances expose the volume as an NVMe namespace such as /dev/nvme1n1 rather
+    // than at the requested device name, so look it up by its volume ID.
+    let max_wait = Duration::from_secs(120);
+    let start = Instant::now();
+    let device = loop {
+        if let Some(device) =
+            find_attached_device(Path::new("/"), &volume_id, Path::new(&device_path))?
+        {
+            break device;
+        }
+        ensure!(
+            start.elapsed() < max_wait,
+            "volume {volume_id} did not appear at {device_path} within {} seconds",
+            max_wait.as_secs()
+        );
         tokio::time::sleep(Duration::from_millis(500)).await;
         debug!("still waiting for device to be attached at {}", device_path);
+    };
+
+    if device != Path::new(&device_path) {
+        if device.starts_with("/dev/nvme") {
+            match ebs_device_name(&device) {
+                Ok(Some(name)) if !device_path.ends_with(&name) => warn!(
+                    "{} reports it was attached as {name}, not {device_path}",
+                    device.display()
+                ),
+                Err(e) => debug!("{e:#}"),
+                _ => {}
+            }
+        }
+        match std::os::unix::fs::symlink(&device, &device_path) {
+            Ok(()) => {
+                info!("linked {} to {}", device_path, device.display());
+                cleanup.track(Resource::Symlink(PathBuf::from(&device_path)));
+            }
+            Err(e) => warn!(
+                "failed to link {} to {}: {e}",
+                device_path,
+                device.display()
+            ),
+        }
     }
-
-    Ok(())
+    info!("volume {} is attached at {}", volume_id, device.display());
+    Ok(device)
 }
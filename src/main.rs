@@ -1,11 +1,33 @@
-use anyhow::{bail, Result};
-use aws_config::load_from_env;
-use aws_sdk_s3::Client;
-use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Result};
+use clap::{ArgAction, Args, Parser, Subcommand};
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber;
+use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
+use vmi::ami::{
+    copy_ami, describe_ami_volumes, validate_ami_id, AmiOptions, Architecture, BootMode,
+    CopyOptions, VolumeSelector,
+};
+use vmi::cleanup::{gc, CleanupGuard};
+use vmi::device::Device;
+use vmi::disk::{detect_format, open_disk, DiskFormat, DiskWriter};
+use vmi::ebs::{open_ami_snapshot, DEFAULT_CONCURRENCY};
+use vmi::export::{export_ami, ExportFormat, ExportOptions, S3Location};
+use vmi::gce::GceTarball;
+use vmi::imds::ImdsClient;
+use vmi::inspect::{
+    inspect_ami, inspect_gce_tar, inspect_ova, inspect_qcow2, inspect_raw, inspect_vdi,
+    inspect_vhd, inspect_vhdx, inspect_vmdk, report_schema,
+};
 use vmi::load_ami_to_device;
+use vmi::ovf::OvaOptions;
+use vmi::qcow2::{flatten_qcow2, rebase_qcow2, Compression, Qcow2Options};
+use vmi::raw::SparseFile;
+use vmi::vdi::{VdiOptions, VdiVariant};
+use vmi::vhd::FixedVhd;
+use vmi::vmdk::{AdapterType, VmdkOptions};
 
 const NAME: &str = "vmi";
 
@@ -25,6 +47,20 @@ struct GlobalOpts {
     /// Verbosity level (can be specified multiple times)
     #[clap(long, short, global = true, default_value_t = 1)]
     verbose: usize,
+
+    /// Output format for command results
+    #[clap(long, short, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+#[derive(Debug, clap::ValueEnum, Clone, Copy)]
+enum OutputFormat {
+    /// Human readable text
+    Text,
+    /// JSON matching the schema printed by `vmi schema`
+    Json,
+    /// YAML matching the schema printed by `vmi schema`
+    Yaml,
 }
 
 #[derive(Debug, Subcommand)]
@@ -39,6 +75,9 @@ enum Command {
         sink: Sink,
         /// Sink ID (e.g. /dev/xvdg for a device).
         sink_id: String,
+
+        #[clap(flatten)]
+        args: Box<ConvertArgs>,
     },
     /// Return information on virtual machine images
     Inspect {
@@ -47,14 +86,121 @@ enum Command {
         /// Source ID (e.g. /path/to/raw.img for a local Raw format image).
         source_id: String,
     },
+    /// Merge a QCOW2 overlay and its backing chain into a standalone image
+    Flatten {
+        /// Path of the top-most QCOW2 overlay.
+        overlay: String,
+        /// Path of the standalone QCOW2 image to create.
+        destination: String,
+
+        #[clap(flatten)]
+        qcow2: Qcow2Args,
+    },
+    /// Rewrite a QCOW2 overlay on top of a different backing image
+    Rebase {
+        /// Path of the QCOW2 overlay to rewrite in place.
+        overlay: String,
+        /// New backing image, relative to the overlay's directory unless absolute. Without
+        /// it the overlay becomes a standalone image.
+        #[clap(long, short)]
+        base: Option<String>,
+        /// Only change the backing file reference, without copying clusters that differ
+        /// between the old and new base.
+        #[clap(long = "unsafe")]
+        skip_compare: bool,
+    },
+    /// Print the JSON schema of machine-readable `inspect` output
+    Schema,
+    /// Copy a cloud machine image to other regions and share it with other accounts
+    Copy {
+        /// Type of the image to copy.
+        source: CopySource,
+        /// Source ID (e.g. ami-1234), in the configured region.
+        source_id: String,
+
+        #[clap(flatten)]
+        args: CopyArgs,
+    },
+    /// Delete volumes and snapshots left behind by interrupted runs
+    Gc {
+        /// Only delete resources created at least this many hours ago, so running
+        /// conversions are left alone.
+        #[clap(long, value_name = "HOURS", default_value_t = 24)]
+        older_than: u64,
+        /// List the resources that would be deleted without deleting them.
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, clap::ValueEnum, Clone)]
+enum CopySource {
+    /// Amazon Machine Image (AMI)
+    Ami,
+}
+
+#[derive(Debug, Args)]
+struct CopyArgs {
+    /// Regions to copy the image to (repeatable or comma-separated)
+    #[clap(long, value_name = "REGION", value_delimiter = ',', required = true)]
+    to_region: Vec<String>,
+
+    /// Name of the copies, instead of the source's
+    #[clap(long)]
+    name: Option<String>,
+
+    /// KMS key to encrypt the copied snapshots with, e.g. an alias existing in every region
+    #[clap(long)]
+    kms_key: Option<String>,
+
+    /// Accounts allowed to launch the copies and create volumes from their snapshots
+    /// (repeatable or comma-separated)
+    #[clap(long, value_name = "ACCOUNT", value_delimiter = ',')]
+    share_with: Vec<String>,
+
+    /// Do not copy the tags of the source image
+    #[clap(long)]
+    no_copy_tags: bool,
+
+    /// Minutes to wait for each copy to become available
+    #[clap(long, value_name = "MINUTES", default_value_t = 360)]
+    timeout: u64,
+}
+
+impl CopyArgs {
+    fn options(&self) -> CopyOptions {
+        CopyOptions {
+            name: self.name.clone(),
+            kms_key_id: self.kms_key.clone(),
+            copy_tags: !self.no_copy_tags,
+            share_with: self.share_with.clone(),
+            timeout: Duration::from_secs(self.timeout * 60),
+        }
+    }
 }
 
 #[derive(Debug, clap::ValueEnum, Clone)]
 enum Source {
+    /// Detect the format of a local image from its contents, falling back to raw
+    Auto,
     /// Amazon Machine Image (AMI)
     Ami,
     /// Raw format image
     Raw,
+    /// QEMU copy-on-write (QCOW2) image
+    Qcow2,
+    /// VMware virtual disk (VMDK), given its descriptor or monolithic file
+    Vmdk,
+    /// Open Virtual Appliance (OVA); its first disk is converted
+    Ova,
+    /// Microsoft Virtual Hard Disk (VHD), fixed, dynamic or differencing
+    Vhd,
+    /// Hyper-V Virtual Hard Disk v2 (VHDX), dynamic, fixed or differencing
+    Vhdx,
+    /// VirtualBox Disk Image (VDI), dynamic, fixed or differencing
+    Vdi,
+    /// Google Compute Engine image tarball (disk.raw in a .tar.gz)
+    GceTar,
     // Add other variants as needed
 }
 
@@ -62,27 +208,539 @@ enum Source {
 enum Sink {
     /// Device path on the host machine. e.g /dev/xvdg.
     Device,
+    /// New sparse raw image file, storing only the blocks that hold data
+    #[value(alias = "raw")]
+    File,
+    /// QEMU copy-on-write (QCOW2) image file
+    Qcow2,
+    /// VMware streamOptimized virtual disk (VMDK) file
+    Vmdk,
+    /// Open Virtual Appliance (OVA) around a streamOptimized VMDK
+    Ova,
+    /// Fixed Microsoft Virtual Hard Disk (VHD), sized for Azure
+    Vhd,
+    /// VirtualBox Disk Image (VDI) file
+    Vdi,
+    /// Google Compute Engine image tarball, ready for `gcloud compute images create`
+    GceTar,
+    /// Amazon Machine Image (AMI) registered from a new EBS snapshot; the sink ID is its name
+    Ami,
+    /// S3 object exported from an AMI by VM Import/Export; the sink ID is s3://bucket/prefix
+    S3,
     // Add other variants as needed
 }
 
-#[allow(unused)]
+/// Format-specific options of `convert`.
+#[derive(Debug, Args)]
+struct ConvertArgs {
+    #[clap(flatten)]
+    ami: AmiArgs,
+
+    #[clap(flatten)]
+    ebs: EbsArgs,
+
+    #[clap(flatten)]
+    export: ExportArgs,
+
+    #[clap(flatten)]
+    ami_output: AmiOutputArgs,
+
+    #[clap(flatten)]
+    qcow2: Qcow2Args,
+
+    #[clap(flatten)]
+    vmdk: VmdkArgs,
+
+    #[clap(flatten)]
+    ova: OvaArgs,
+
+    #[clap(flatten)]
+    vdi: VdiArgs,
+}
+
+#[derive(Debug, Args)]
+#[clap(next_help_heading = "AMI source")]
+struct AmiArgs {
+    /// How an AMI source is read when converting it to an image file. The device sink
+    /// always attaches a volume.
+    #[clap(long, value_enum, default_value_t = AmiAccessArg::Direct)]
+    ami_access: AmiAccessArg,
+
+    /// Device name the AMI's volume is attached at when read with `--ami-access attach`
+    #[clap(long, default_value = "/dev/sdf")]
+    attach_device: String,
+
+    /// Endpoint of the instance metadata service, e.g. http://[fd00:ec2::254] in IPv6-only
+    /// subnets. Defaults to AWS_EC2_METADATA_SERVICE_ENDPOINT or the IPv4 endpoint.
+    #[clap(long)]
+    imds_endpoint: Option<String>,
+
+    /// EBS volume of the AMI to convert, by device name (e.g. /dev/sdb) or index among its
+    /// EBS volumes. Defaults to the root volume.
+    #[clap(long, value_name = "DEVICE|INDEX", conflicts_with = "all_volumes")]
+    volume: Option<VolumeSelector>,
+
+    /// Convert every EBS volume of the AMI. The sink ID must contain `{index}` or
+    /// `{device}` (e.g. `disk-{device}.qcow2`), replaced for each volume.
+    #[clap(long)]
+    all_volumes: bool,
+}
+
+impl AmiArgs {
+    fn imds_client(&self) -> ImdsClient {
+        match &self.imds_endpoint {
+            Some(endpoint) => ImdsClient::new(endpoint),
+            None => ImdsClient::from_env(),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+#[clap(next_help_heading = "S3 export")]
+struct ExportArgs {
+    /// Disk image format of AMIs exported to S3
+    #[clap(long, value_enum, default_value_t = ExportFormatArg::Vmdk)]
+    export_format: ExportFormatArg,
+
+    /// IAM role VM Import/Export writes the exported image to the bucket as
+    #[clap(long, default_value = "vmimport")]
+    export_role: String,
+
+    /// Also download the exported image to this path
+    #[clap(long, value_name = "PATH")]
+    download: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::ValueEnum, Clone, Copy)]
+enum ExportFormatArg {
+    Vmdk,
+    Vhd,
+    Raw,
+}
+
+impl ExportArgs {
+    fn options(&self) -> ExportOptions {
+        ExportOptions {
+            format: match self.export_format {
+                ExportFormatArg::Vmdk => ExportFormat::Vmdk,
+                ExportFormatArg::Vhd => ExportFormat::Vhd,
+                ExportFormatArg::Raw => ExportFormat::Raw,
+            },
+            role_name: self.export_role.clone(),
+            download: self.download.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+#[clap(next_help_heading = "EBS direct APIs")]
+struct EbsArgs {
+    /// Endpoint of the EBS direct APIs, instead of the regional one
+    #[clap(long)]
+    ebs_endpoint: Option<String>,
+
+    /// Number of snapshot blocks transferred in parallel through the EBS direct APIs
+    #[clap(long, default_value_t = DEFAULT_CONCURRENCY)]
+    ebs_concurrency: usize,
+}
+
+#[derive(Debug, Args)]
+#[clap(next_help_heading = "AMI output")]
+struct AmiOutputArgs {
+    /// CPU architecture of an AMI sink
+    #[clap(long, value_enum, default_value_t = ArchitectureArg::X86_64)]
+    architecture: ArchitectureArg,
+
+    /// Boot mode of an AMI sink, instead of the instance type's default
+    #[clap(long, value_enum)]
+    boot_mode: Option<BootModeArg>,
+
+    /// Enable ENA networking on an AMI sink
+    #[clap(long, default_value_t = true, action = ArgAction::Set)]
+    ena_support: bool,
+
+    /// Enable SR-IOV enhanced networking on an AMI sink
+    #[clap(long)]
+    sriov_net_support: bool,
+
+    /// Root device name of an AMI sink
+    #[clap(long, default_value = "/dev/xvda")]
+    root_device_name: String,
+
+    /// Description of an AMI sink and its snapshot
+    #[clap(long)]
+    ami_description: Option<String>,
+}
+
+#[derive(Debug, clap::ValueEnum, Clone, Copy)]
+enum ArchitectureArg {
+    #[clap(name = "x86_64")]
+    X86_64,
+    Arm64,
+}
+
+#[derive(Debug, clap::ValueEnum, Clone, Copy)]
+enum BootModeArg {
+    LegacyBios,
+    Uefi,
+    UefiPreferred,
+}
+
+impl AmiOutputArgs {
+    fn options(&self, ebs: &EbsArgs) -> AmiOptions {
+        AmiOptions {
+            architecture: match self.architecture {
+                ArchitectureArg::X86_64 => Architecture::X86_64,
+                ArchitectureArg::Arm64 => Architecture::Arm64,
+            },
+            boot_mode: self.boot_mode.map(|b| match b {
+                BootModeArg::LegacyBios => BootMode::LegacyBios,
+                BootModeArg::Uefi => BootMode::Uefi,
+                BootModeArg::UefiPreferred => BootMode::UefiPreferred,
+            }),
+            ena_support: self.ena_support,
+            sriov_net_support: self.sriov_net_support,
+            root_device_name: self.root_device_name.clone(),
+            description: self.ami_description.clone(),
+            ebs_endpoint: ebs.ebs_endpoint.clone(),
+            concurrency: ebs.ebs_concurrency,
+        }
+    }
+}
+
+#[derive(Debug, clap::ValueEnum, Clone, Copy)]
+enum AmiAccessArg {
+    /// Stream the AMI's snapshot through the EBS direct APIs, from anywhere
+    Direct,
+    /// Attach a volume of the AMI to this EC2 instance and read the device
+    Attach,
+}
+
+#[derive(Debug, Args)]
+#[clap(next_help_heading = "QCOW2 output")]
+struct Qcow2Args {
+    /// Cluster size of a QCOW2 sink, in bytes
+    #[clap(long, default_value_t = 65536)]
+    cluster_size: u64,
+
+    /// Compress the data clusters of a QCOW2 sink
+    #[clap(long, value_enum)]
+    compression: Option<CompressionArg>,
+
+    /// Enable lazy refcounts on a QCOW2 sink
+    #[clap(long)]
+    lazy_refcounts: bool,
+}
+
+#[derive(Debug, clap::ValueEnum, Clone, Copy)]
+enum CompressionArg {
+    Zlib,
+    Zstd,
+}
+
+impl Qcow2Args {
+    fn options(&self) -> Qcow2Options {
+        Qcow2Options {
+            cluster_size: self.cluster_size,
+            compression: self.compression.map(|c| match c {
+                CompressionArg::Zlib => Compression::Zlib,
+                CompressionArg::Zstd => Compression::Zstd,
+            }),
+            lazy_refcounts: self.lazy_refcounts,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+#[clap(next_help_heading = "VMDK output")]
+struct VmdkArgs {
+    /// Disk controller recorded in the descriptor of a VMDK sink
+    #[clap(long, value_enum, default_value_t = AdapterTypeArg::Lsilogic)]
+    adapter_type: AdapterTypeArg,
+}
+
+#[derive(Debug, clap::ValueEnum, Clone, Copy)]
+enum AdapterTypeArg {
+    Ide,
+    Lsilogic,
+    Buslogic,
+    #[clap(name = "legacyESX")]
+    LegacyEsx,
+}
+
+impl VmdkArgs {
+    fn options(&self) -> VmdkOptions {
+        VmdkOptions {
+            adapter_type: match self.adapter_type {
+                AdapterTypeArg::Ide => AdapterType::Ide,
+                AdapterTypeArg::Lsilogic => AdapterType::LsiLogic,
+                AdapterTypeArg::Buslogic => AdapterType::BusLogic,
+                AdapterTypeArg::LegacyEsx => AdapterType::LegacyEsx,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+#[clap(next_help_heading = "OVA output")]
+struct OvaArgs {
+    /// Number of virtual CPUs of an OVA sink's virtual machine
+    #[clap(long, default_value_t = 1)]
+    cpus: u64,
+
+    /// Memory of an OVA sink's virtual machine, in MiB
+    #[clap(long, default_value_t = 1024)]
+    memory: u64,
+
+    /// Network the OVA sink's network adapter is connected to
+    #[clap(long, default_value = "VM Network")]
+    network: String,
+}
+
+impl OvaArgs {
+    fn options(&self, vmdk: &VmdkArgs) -> OvaOptions {
+        OvaOptions {
+            cpus: self.cpus,
+            memory_mib: self.memory,
+            network: self.network.clone(),
+            vmdk: vmdk.options(),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+#[clap(next_help_heading = "VDI output")]
+struct VdiArgs {
+    /// Block allocation of a VDI sink
+    #[clap(long, value_enum, default_value_t = VdiVariantArg::Dynamic)]
+    vdi_variant: VdiVariantArg,
+}
+
+#[derive(Debug, clap::ValueEnum, Clone, Copy)]
+enum VdiVariantArg {
+    /// Only blocks holding data are stored
+    Dynamic,
+    /// Every block is preallocated
+    Static,
+}
+
+impl VdiArgs {
+    fn options(&self) -> VdiOptions {
+        VdiOptions {
+            variant: match self.vdi_variant {
+                VdiVariantArg::Dynamic => VdiVariant::Dynamic,
+                VdiVariantArg::Static => VdiVariant::Static,
+            },
+        }
+    }
+}
+
+impl Source {
+    /// Format of a source that is a local image file.
+    fn disk_format(&self) -> Option<DiskFormat> {
+        Some(match self {
+            Source::Ami | Source::Auto => return None,
+            Source::Raw => DiskFormat::Raw,
+            Source::Qcow2 => DiskFormat::Qcow2,
+            Source::Vmdk => DiskFormat::Vmdk,
+            Source::Ova => DiskFormat::Ova,
+            Source::Vhd => DiskFormat::Vhd,
+            Source::Vhdx => DiskFormat::Vhdx,
+            Source::Vdi => DiskFormat::Vdi,
+            Source::GceTar => DiskFormat::GceTar,
+        })
+    }
+
+    /// Resolve `auto` to the detected format of the local image `source_id`.
+    fn resolve(self, source_id: &str) -> Result<Source> {
+        let path = Path::new(source_id);
+        let detected = match self {
+            Source::Ami => return Ok(self),
+            Source::Auto if source_id.starts_with("ami-") && !path.exists() => {
+                return Ok(Source::Ami)
+            }
+            Source::Auto => return Ok(detect_format(path)?.into()),
+            Source::Raw => detect_format(path)?,
+            _ => return Ok(self),
+        };
+        // A raw source is the silent fallback, so double check it.
+        if detected != DiskFormat::Raw {
+            warn!(
+                "{source_id} looks like a {} image, not raw; pass `{}` or `auto` as the source",
+                detected.as_str(),
+                detected.as_str()
+            );
+        }
+        Ok(self)
+    }
+}
+
+impl From<DiskFormat> for Source {
+    fn from(format: DiskFormat) -> Self {
+        match format {
+            DiskFormat::Raw => Source::Raw,
+            DiskFormat::Qcow2 => Source::Qcow2,
+            DiskFormat::Vmdk => Source::Vmdk,
+            DiskFormat::Ova => Source::Ova,
+            DiskFormat::Vhd => Source::Vhd,
+            DiskFormat::Vhdx => Source::Vhdx,
+            DiskFormat::Vdi => Source::Vdi,
+            DiskFormat::GceTar => Source::GceTar,
+        }
+    }
+}
+
+/// The writer producing a sink's output format.
+fn sink_writer(sink: &Sink, args: &ConvertArgs) -> Box<dyn DiskWriter> {
+    match sink {
+        Sink::Device => Box::new(Device),
+        Sink::File => Box::new(SparseFile),
+        Sink::Qcow2 => Box::new(args.qcow2.options()),
+        Sink::Vmdk => Box::new(args.vmdk.options()),
+        Sink::Ova => Box::new(args.ova.options(&args.vmdk)),
+        Sink::Vhd => Box::new(FixedVhd),
+        Sink::Vdi => Box::new(args.vdi.options()),
+        Sink::GceTar => Box::new(GceTarball),
+        Sink::Ami => Box::new(args.ami_output.options(&args.ebs)),
+        Sink::S3 => unreachable!("only AMIs are exported to S3, without a disk writer"),
+    }
+}
+
 async fn handle_convert(
     source: Source,
     source_id: String,
     sink: Sink,
     sink_id: String,
+    args: ConvertArgs,
 ) -> Result<()> {
-    match (source, sink) {
-        (Source::Ami, Sink::Device) => {
-            load_ami_to_device(source_id, sink_id).await?;
-        }
-        _ => bail!("Unsupported conversion"),
+    let source = source.resolve(&source_id)?;
+    let is_ami = matches!(source, Source::Ami);
+    ensure!(
+        is_ami || (args.ami.volume.is_none() && !args.ami.all_volumes),
+        "--volume and --all-volumes only apply to AMI sources"
+    );
+    if let Sink::S3 = sink {
+        ensure!(is_ami, "only AMIs can be exported to S3");
+        ensure!(
+            args.ami.volume.is_none() && !args.ami.all_volumes,
+            "AMIs are exported to S3 whole, without --volume or --all-volumes"
+        );
+        let destination: S3Location = sink_id.parse()?;
+        let config = aws_config::load_from_env().await;
+        let object = export_ami(&config, &source_id, &destination, &args.export.options()).await?;
+        println!("s3://{}/{}", object.bucket, object.key);
+        return Ok(());
+    }
+    if !args.ami.all_volumes {
+        let volume = args.ami.volume.as_ref();
+        return convert_volume(&source, &source_id, volume, &sink, sink_id, &args).await;
+    }
+    ensure!(
+        sink_id.contains("{index}") || sink_id.contains("{device}"),
+        "with --all-volumes the sink ID must contain {{index}} or {{device}}"
+    );
+    let ec2_client = aws_sdk_ec2::Client::new(&aws_config::load_from_env().await);
+    let volumes = describe_ami_volumes(&ec2_client, &source_id).await?;
+    ensure!(!volumes.is_empty(), "{source_id} has no EBS volumes");
+    for (index, volume) in volumes.iter().enumerate() {
+        let volume_sink_id = sink_id
+            .replace("{index}", &index.to_string())
+            .replace("{device}", volume.short_name());
+        info!("converting {} to {volume_sink_id}", volume.device_name);
+        let selector = VolumeSelector::Device(volume.device_name.clone());
+        convert_volume(
+            &source,
+            &source_id,
+            Some(&selector),
+            &sink,
+            volume_sink_id,
+            &args,
+        )
+        .await?;
     }
     Ok(())
 }
 
-#[allow(unused)]
-async fn handle_inspect(source: Source, source_id: String) -> Result<()> {
+/// Convert one disk of the source, the EBS volume chosen by `volume` for AMIs.
+async fn convert_volume(
+    source: &Source,
+    source_id: &str,
+    volume: Option<&VolumeSelector>,
+    sink: &Sink,
+    sink_id: String,
+    args: &ConvertArgs,
+) -> Result<()> {
+    if let (Source::Ami, Sink::Device) = (source, sink) {
+        let cleanup = CleanupGuard::from_env().await;
+        cleanup.cleanup_on_interrupt();
+        let imds = args.ami.imds_client();
+        load_ami_to_device(source_id.to_string(), volume, sink_id, &imds, &cleanup).await?;
+        // The volume now belongs to the user.
+        cleanup.keep().await?;
+        return Ok(());
+    }
+    let mut attached = None;
+    let mut image = match (source.disk_format(), args.ami.ami_access) {
+        (Some(format), _) => open_disk(Path::new(source_id), format)?,
+        (None, AmiAccessArg::Direct) => {
+            let endpoint = args.ebs.ebs_endpoint.as_deref();
+            let snapshot = open_ami_snapshot(source_id, volume, endpoint).await?;
+            Box::new(snapshot.with_concurrency(args.ebs.ebs_concurrency))
+        }
+        (None, AmiAccessArg::Attach) => {
+            // Attach a temporary volume of the AMI to this host and read it like a raw image.
+            let cleanup = CleanupGuard::from_env().await;
+            cleanup.cleanup_on_interrupt();
+            let device_path = args.ami.attach_device.clone();
+            let imds = args.ami.imds_client();
+            let device =
+                load_ami_to_device(source_id.to_string(), volume, device_path, &imds, &cleanup)
+                    .await?;
+            let disk = open_disk(&device, DiskFormat::Raw);
+            attached = Some(cleanup);
+            disk?
+        }
+    };
+    // Snapshots read or written through the EBS direct APIs block on transfers running on
+    // this runtime.
+    let result = tokio::task::block_in_place(|| {
+        sink_writer(sink, args).write_disk(&mut image, Path::new(&sink_id))
+    });
+    drop(image);
+    if let Some(cleanup) = attached {
+        cleanup.cleanup().await;
+    }
+    result
+}
+
+async fn handle_inspect(source: Source, source_id: String, output: OutputFormat) -> Result<()> {
+    let source = source.resolve(&source_id)?;
+    let report = match source {
+        Source::Raw => inspect_raw(Path::new(&source_id))?,
+        Source::Qcow2 => inspect_qcow2(Path::new(&source_id))?,
+        Source::Vmdk => inspect_vmdk(Path::new(&source_id))?,
+        Source::Ova => inspect_ova(Path::new(&source_id))?,
+        Source::Vhd => inspect_vhd(Path::new(&source_id))?,
+        Source::Vhdx => inspect_vhdx(Path::new(&source_id))?,
+        Source::Vdi => inspect_vdi(Path::new(&source_id))?,
+        Source::GceTar => inspect_gce_tar(Path::new(&source_id))?,
+        Source::Ami => {
+            validate_ami_id(&source_id)?;
+            let ec2_client = aws_sdk_ec2::Client::new(&aws_config::load_from_env().await);
+            inspect_ami(&ec2_client, &source_id).await?
+        }
+        _ => bail!("Unsupported inspection"),
+    };
+    match output {
+        OutputFormat::Text => print!("{report}"),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(&report)?),
+    }
+    if let Some(appliance) = &report.appliance {
+        if let Some(check) = appliance.manifest.iter().find(|c| !c.valid) {
+            bail!("{} does not match the OVA manifest", check.file);
+        }
+    }
     Ok(())
 }
 
@@ -105,35 +763,62 @@ async fn main() -> Result<()> {
         )
         .init();
 
-    let shared_config = load_from_env().await;
-    let s3_client = Client::new(&shared_config);
-
-    let response = s3_client.list_buckets().send().await?;
-
-    println!("Buckets:");
-    if let Some(buckets) = response.buckets {
-        for bucket in buckets {
-            let name = bucket.name().unwrap_or("Unnamed");
-            let creation_date = bucket
-                .creation_date()
-                .map_or("Unknown".to_string(), |cd| cd.to_string());
-            println!("  - {} (created: {})", name, creation_date);
-        }
-    } else {
-        println!("No buckets found.");
-    }
-
     match cli.command {
         Command::Convert {
             source,
             source_id,
             sink,
             sink_id,
+            args,
         } => {
-            handle_convert(source, source_id, sink, sink_id).await?;
+            handle_convert(source, source_id, sink, sink_id, *args).await?;
         }
         Command::Inspect { source, source_id } => {
-            handle_inspect(source, source_id).await?;
+            handle_inspect(source, source_id, cli.global_opts.output).await?;
+        }
+        Command::Flatten {
+            overlay,
+            destination,
+            qcow2,
+        } => {
+            flatten_qcow2(
+                Path::new(&overlay),
+                Path::new(&destination),
+                &qcow2.options(),
+            )?;
+        }
+        Command::Rebase {
+            overlay,
+            base,
+            skip_compare,
+        } => {
+            rebase_qcow2(Path::new(&overlay), base.as_deref(), skip_compare)?;
+        }
+        Command::Schema => {
+            println!("{}", serde_json::to_string_pretty(&report_schema())?);
+        }
+        Command::Copy {
+            source: CopySource::Ami,
+            source_id,
+            args,
+        } => {
+            validate_ami_id(&source_id)?;
+            let ec2_client = aws_sdk_ec2::Client::new(&aws_config::load_from_env().await);
+            let copies =
+                copy_ami(&ec2_client, &source_id, &args.to_region, &args.options()).await?;
+            for (region, image_id) in args.to_region.iter().zip(copies) {
+                println!("{region} {image_id}");
+            }
+        }
+        Command::Gc {
+            older_than,
+            dry_run,
+        } => {
+            let ec2_client = aws_sdk_ec2::Client::new(&aws_config::load_from_env().await);
+            let older_than = Duration::from_secs(older_than * 60 * 60);
+            for id in gc(&ec2_client, older_than, dry_run).await? {
+                println!("{id}");
+            }
         }
     }
 
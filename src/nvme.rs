@@ -0,0 +1,153 @@
+//! Finding the block devices of attached EBS volumes.
+//!
+//! On Nitro instances EBS volumes are NVMe namespaces named by attach order, such as
+//! `/dev/nvme1n1`, rather than the device name passed to `AttachVolume`. The controller's
+//! serial number is the volume ID without its dash, which identifies the device. Lookups
+//! take the filesystem root so they can run against a fake `/sys` and `/dev`.
+
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tracing::debug;
+
+/// Model number of the NVMe controllers of EBS volumes.
+pub const EBS_MODEL: &str = "Amazon Elastic Block Store";
+
+/// Serial number of the NVMe controller of an EBS volume, e.g. `vol0123456789abcdef0`.
+pub fn ebs_serial(volume_id: &str) -> String {
+    volume_id.replacen("vol-", "vol", 1)
+}
+
+/// Find the block device of `volume_id` attached at `requested` (e.g. `/dev/sdf`), under the
+/// filesystem `root`.
+///
+/// The requested path wins when it exists. Otherwise the NVMe namespace whose controller
+/// serial is the volume ID is looked up in sysfs, then in `/dev/disk/by-id`, and finally the
+/// `/dev/xvd*` name Xen instances give to `/dev/sd*` devices is tried.
+pub fn find_attached_device(
+    root: &Path,
+    volume_id: &str,
+    requested: &Path,
+) -> Result<Option<PathBuf>> {
+    let requested = rooted(root, requested);
+    if requested.exists() {
+        return Ok(Some(requested));
+    }
+    if let Some(device) = find_nvme_device(root, volume_id)? {
+        return Ok(Some(device));
+    }
+    let serial = ebs_serial(volume_id);
+    let by_id = root
+        .join("dev/disk/by-id")
+        .join(format!("nvme-{}_{serial}", EBS_MODEL.replace(' ', "_")));
+    if by_id.exists() {
+        let device = fs::canonicalize(&by_id)
+            .with_context(|| format!("failed to resolve {}", by_id.display()))?;
+        return Ok(Some(device));
+    }
+    let name = requested.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    if let Some(letter) = name.strip_prefix("sd") {
+        let xen = requested.with_file_name(format!("xvd{letter}"));
+        if xen.exists() {
+            return Ok(Some(xen));
+        }
+    }
+    Ok(None)
+}
+
+/// Find the NVMe namespace of `volume_id` by the serial numbers of the controllers in sysfs.
+pub fn find_nvme_device(root: &Path, volume_id: &str) -> Result<Option<PathBuf>> {
+    let serial = ebs_serial(volume_id);
+    let sys_block = root.join("sys/block");
+    let entries = match fs::read_dir(&sys_block) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(format!("failed to list {}", sys_block.display())),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok()?.file_name().into_string().ok())
+        .filter(|name| name.starts_with("nvme"))
+        .collect();
+    names.sort();
+    for name in names {
+        // The namespace's `device` links to its controller.
+        let controller = sys_block.join(&name).join("device");
+        let Ok(found) = fs::read_to_string(controller.join("serial")) else {
+            continue;
+        };
+        let model = fs::read_to_string(controller.join("model")).unwrap_or_default();
+        debug!("{name} has serial {} ({})", found.trim(), model.trim());
+        if found.trim() == serial && model.trim() == EBS_MODEL {
+            return Ok(Some(root.join("dev").join(name)));
+        }
+    }
+    Ok(None)
+}
+
+/// `path`, an absolute path on the host, under the filesystem `root`.
+fn rooted(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+// Linux's `struct nvme_admin_cmd`.
+#[repr(C)]
+#[derive(Default)]
+struct NvmeAdminCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+// _IOWR('N', 0x41, struct nvme_admin_cmd)
+const NVME_IOCTL_ADMIN_CMD: u64 = 0xC048_4E41;
+const NVME_ADMIN_IDENTIFY: u8 = 0x06;
+const IDENTIFY_CONTROLLER: u32 = 1;
+// EBS keeps the device name given to AttachVolume in the vendor specific area of the
+// identify controller data.
+const EBS_DEVICE_NAME: std::ops::Range<usize> = 3072..3104;
+
+/// The device name an EBS volume was attached with (e.g. `sdf`), read from the identify
+/// controller data of its NVMe `device`. Needs read access to the device.
+pub fn ebs_device_name(device: &Path) -> Result<Option<String>> {
+    let file =
+        File::open(device).with_context(|| format!("failed to open {}", device.display()))?;
+    let mut data = vec![0u8; 4096];
+    let mut cmd = NvmeAdminCmd {
+        opcode: NVME_ADMIN_IDENTIFY,
+        addr: data.as_mut_ptr() as u64,
+        data_len: data.len() as u32,
+        cdw10: IDENTIFY_CONTROLLER,
+        ..Default::default()
+    };
+    // SAFETY: the command describes `data`, which outlives the call, as its only buffer.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), NVME_IOCTL_ADMIN_CMD as _, &mut cmd) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("failed to identify NVMe controller {}", device.display()));
+    }
+    let model = String::from_utf8_lossy(&data[24..64]);
+    if model.trim() != EBS_MODEL {
+        return Ok(None);
+    }
+    let name = String::from_utf8_lossy(&data[EBS_DEVICE_NAME]);
+    let name = name.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    Ok((!name.is_empty()).then(|| name.trim_start_matches("/dev/").to_string()))
+}
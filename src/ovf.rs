@@ -0,0 +1,541 @@
+//! Open Virtualization Format (OVF) descriptors and OVA appliances.
+//!
+//! See DMTF DSP0243. Only the parts needed to describe a virtual machine's hardware and disks
+//! are modelled; unknown sections are ignored when parsing.
+
+use std::fmt::{self, Write as _};
+
+use anyhow::{ensure, Context, Result};
+use roxmltree::Node;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+mod ova;
+
+pub use ova::{write_ova, Ova, OvaOptions};
+
+/// `rasd:ResourceType` values of the hardware items vmi cares about.
+pub const RESOURCE_CPU: u32 = 3;
+pub const RESOURCE_MEMORY: u32 = 4;
+pub const RESOURCE_IDE_CONTROLLER: u32 = 5;
+pub const RESOURCE_SCSI_CONTROLLER: u32 = 6;
+pub const RESOURCE_ETHERNET: u32 = 10;
+pub const RESOURCE_DISK: u32 = 17;
+pub const RESOURCE_SATA_CONTROLLER: u32 = 20;
+
+/// Disk format URI of a streamOptimized VMDK.
+pub const FORMAT_STREAM_OPTIMIZED: &str =
+    "http://www.vmware.com/interfaces/specifications/vmdk.html#streamOptimized";
+
+/// A file listed in the descriptor's `References` section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileReference {
+    pub id: String,
+    pub href: String,
+    pub size: Option<u64>,
+    /// Compression of the stored file, e.g. `gzip`.
+    pub compression: Option<String>,
+}
+
+/// A virtual disk from the `DiskSection`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disk {
+    pub id: String,
+    /// Id of the [`FileReference`] holding the disk contents; `None` for a blank disk.
+    pub file_ref: Option<String>,
+    /// Capacity in bytes.
+    pub capacity: u64,
+    pub format: Option<String>,
+    /// Bytes actually used by the guest, when known.
+    pub populated_size: Option<u64>,
+}
+
+/// One `Item` of a `VirtualHardwareSection`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HardwareItem {
+    pub instance_id: String,
+    pub resource_type: u32,
+    pub resource_sub_type: Option<String>,
+    pub element_name: Option<String>,
+    pub virtual_quantity: Option<u64>,
+    pub allocation_units: Option<String>,
+    pub address: Option<String>,
+    pub address_on_parent: Option<String>,
+    pub parent: Option<String>,
+    /// E.g. `ovf:/disk/vmdisk1`.
+    pub host_resource: Option<String>,
+    /// Network a network adapter is connected to.
+    pub connection: Option<String>,
+    pub automatic_allocation: Option<bool>,
+}
+
+/// A `VirtualSystem` and its virtual hardware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualSystem {
+    pub id: String,
+    pub name: Option<String>,
+    /// CIM operating system id from the `OperatingSystemSection`.
+    pub os_id: Option<u32>,
+    pub os_description: Option<String>,
+    /// Virtual hardware family, e.g. `vmx-10`.
+    pub system_type: Option<String>,
+    pub items: Vec<HardwareItem>,
+}
+
+/// A parsed OVF descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub files: Vec<FileReference>,
+    pub disks: Vec<Disk>,
+    pub networks: Vec<String>,
+    /// The first virtual system of the appliance.
+    pub system: VirtualSystem,
+}
+
+impl Envelope {
+    /// Parse the XML text of an OVF descriptor.
+    pub fn parse(text: &str) -> Result<Self> {
+        let doc = roxmltree::Document::parse(text).context("OVF descriptor is not valid XML")?;
+        let root = doc.root_element();
+        ensure!(
+            root.tag_name().name() == "Envelope",
+            "OVF descriptor has no Envelope element"
+        );
+
+        let mut files = Vec::new();
+        if let Some(references) = child(root, "References") {
+            for file in children(references, "File") {
+                files.push(FileReference {
+                    id: attr(file, "id").context("OVF File has no id")?.to_string(),
+                    href: attr(file, "href")
+                        .context("OVF File has no href")?
+                        .to_string(),
+                    size: attr(file, "size").map(str::parse).transpose()?,
+                    compression: attr(file, "compression").map(str::to_string),
+                });
+            }
+        }
+
+        let mut disks = Vec::new();
+        if let Some(section) = child(root, "DiskSection") {
+            for disk in children(section, "Disk") {
+                let id = attr(disk, "diskId").context("OVF Disk has no diskId")?;
+                let capacity: u64 = attr(disk, "capacity")
+                    .context("OVF Disk has no capacity")?
+                    .parse()
+                    .with_context(|| format!("invalid capacity of OVF disk {id}"))?;
+                let units = attr(disk, "capacityAllocationUnits").unwrap_or("byte");
+                let multiplier = parse_units(units)
+                    .with_context(|| format!("unsupported allocation units {units:?}"))?;
+                disks.push(Disk {
+                    id: id.to_string(),
+                    file_ref: attr(disk, "fileRef").map(str::to_string),
+                    capacity: capacity * multiplier,
+                    format: attr(disk, "format").map(str::to_string),
+                    populated_size: attr(disk, "populatedSize").and_then(|s| s.parse().ok()),
+                });
+            }
+        }
+
+        let networks = child(root, "NetworkSection")
+            .map(|section| {
+                children(section, "Network")
+                    .filter_map(|n| attr(n, "name").map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let system = root
+            .descendants()
+            .find(|n| n.tag_name().name() == "VirtualSystem")
+            .context("OVF descriptor has no VirtualSystem")?;
+        Ok(Envelope {
+            files,
+            disks,
+            networks,
+            system: parse_system(system)?,
+        })
+    }
+
+    pub fn file(&self, id: &str) -> Option<&FileReference> {
+        self.files.iter().find(|f| f.id == id)
+    }
+
+    /// Summarize the virtual hardware of the appliance.
+    pub fn appliance(&self) -> Appliance {
+        let items = &self.system.items;
+        let cpus = items
+            .iter()
+            .find(|i| i.resource_type == RESOURCE_CPU)
+            .and_then(|i| i.virtual_quantity);
+        let memory = items
+            .iter()
+            .find(|i| i.resource_type == RESOURCE_MEMORY)
+            .and_then(|i| {
+                let units = i.allocation_units.as_deref().unwrap_or("byte * 2^20");
+                Some(i.virtual_quantity? * parse_units(units)?)
+            });
+        let nics = items
+            .iter()
+            .filter(|i| i.resource_type == RESOURCE_ETHERNET)
+            .map(|i| NetworkAdapter {
+                name: i.element_name.clone(),
+                adapter: i.resource_sub_type.clone(),
+                network: i.connection.clone(),
+            })
+            .collect();
+
+        let disks = self
+            .disks
+            .iter()
+            .map(|disk| {
+                // VirtualBox omits the `ovf:` scheme of the host resource.
+                let resource = format!("/disk/{}", disk.id);
+                let controller = items
+                    .iter()
+                    .find(|i| {
+                        i.resource_type == RESOURCE_DISK
+                            && i.host_resource
+                                .as_deref()
+                                .map(|r| r.trim_start_matches("ovf:"))
+                                == Some(resource.as_str())
+                    })
+                    .and_then(|i| {
+                        items
+                            .iter()
+                            .find(|c| Some(&c.instance_id) == i.parent.as_ref())
+                    })
+                    .map(describe_controller);
+                ApplianceDisk {
+                    id: disk.id.clone(),
+                    file: disk
+                        .file_ref
+                        .as_deref()
+                        .and_then(|r| self.file(r))
+                        .map(|f| f.href.clone()),
+                    capacity: disk.capacity,
+                    format: disk.format.clone(),
+                    controller,
+                }
+            })
+            .collect();
+
+        Appliance {
+            name: self
+                .system
+                .name
+                .clone()
+                .or_else(|| Some(self.system.id.clone())),
+            os: self.system.os_description.clone(),
+            cpus,
+            memory,
+            nics,
+            disks,
+            manifest: Vec::new(),
+        }
+    }
+}
+
+fn describe_controller(item: &HardwareItem) -> String {
+    let bus = match item.resource_type {
+        RESOURCE_IDE_CONTROLLER => "ide",
+        RESOURCE_SCSI_CONTROLLER => "scsi",
+        RESOURCE_SATA_CONTROLLER => "sata",
+        _ => "other",
+    };
+    match &item.resource_sub_type {
+        Some(sub_type) => format!("{bus} ({sub_type})"),
+        None => bus.to_string(),
+    }
+}
+
+fn parse_system(system: Node) -> Result<VirtualSystem> {
+    let os = child(system, "OperatingSystemSection");
+    let hardware = child(system, "VirtualHardwareSection");
+    let mut items = Vec::new();
+    for item in hardware.iter().flat_map(|h| h.children()).filter(|n| {
+        matches!(
+            n.tag_name().name(),
+            "Item" | "StorageItem" | "EthernetPortItem"
+        )
+    }) {
+        let field = |name: &str| child(item, name).and_then(|n| n.text()).map(|t| t.trim());
+        let resource_type = field("ResourceType").context("OVF Item has no ResourceType")?;
+        items.push(HardwareItem {
+            instance_id: field("InstanceID").unwrap_or_default().to_string(),
+            resource_type: resource_type
+                .parse()
+                .with_context(|| format!("invalid ResourceType {resource_type}"))?,
+            resource_sub_type: field("ResourceSubType").map(str::to_string),
+            element_name: field("ElementName").map(str::to_string),
+            virtual_quantity: field("VirtualQuantity").and_then(|q| q.parse().ok()),
+            allocation_units: field("AllocationUnits").map(str::to_string),
+            address: field("Address").map(str::to_string),
+            address_on_parent: field("AddressOnParent").map(str::to_string),
+            parent: field("Parent").map(str::to_string),
+            host_resource: field("HostResource").map(str::to_string),
+            connection: field("Connection").map(str::to_string),
+            automatic_allocation: field("AutomaticAllocation").map(|a| a == "true"),
+        });
+    }
+    Ok(VirtualSystem {
+        id: attr(system, "id").unwrap_or_default().to_string(),
+        name: child(system, "Name")
+            .and_then(|n| n.text())
+            .map(|t| t.trim().to_string()),
+        os_id: os
+            .and_then(|os| attr(os, "id"))
+            .and_then(|id| id.parse().ok()),
+        os_description: os
+            .and_then(|os| child(os, "Description"))
+            .and_then(|n| n.text())
+            .map(|t| t.trim().to_string()),
+        system_type: hardware
+            .and_then(|h| child(h, "System"))
+            .and_then(|s| child(s, "VirtualSystemType"))
+            .and_then(|n| n.text())
+            .map(|t| t.trim().to_string()),
+        items,
+    })
+}
+
+/// Multiplier in bytes of programmatic units such as `byte * 2^20`.
+fn parse_units(units: &str) -> Option<u64> {
+    match units.trim() {
+        "KiloBytes" => return Some(1 << 10),
+        "MegaBytes" => return Some(1 << 20),
+        "GigaBytes" => return Some(1 << 30),
+        _ => {}
+    }
+    let mut multiplier = 1u64;
+    for factor in units.split('*').map(str::trim) {
+        multiplier *= match factor.split_once('^') {
+            _ if factor == "byte" => 1,
+            Some((base, exp)) => base
+                .trim()
+                .parse::<u64>()
+                .ok()?
+                .pow(exp.trim().parse().ok()?),
+            None => factor.parse().ok()?,
+        };
+    }
+    Some(multiplier)
+}
+
+fn child<'a, 'i>(node: Node<'a, 'i>, name: &str) -> Option<Node<'a, 'i>> {
+    node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn children<'a, 'i: 'a>(node: Node<'a, 'i>, name: &'a str) -> impl Iterator<Item = Node<'a, 'i>> {
+    node.children()
+        .filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+/// Look up an attribute by local name, whatever its namespace prefix.
+fn attr<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.attributes()
+        .find(|a| a.name() == name)
+        .map(|a| a.value())
+}
+
+impl fmt::Display for Envelope {
+    /// Write the descriptor as OVF 1.0 XML.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            f,
+            r#"<Envelope xmlns="http://schemas.dmtf.org/ovf/envelope/1" xmlns:ovf="http://schemas.dmtf.org/ovf/envelope/1" xmlns:rasd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_ResourceAllocationSettingData" xmlns:vssd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_VirtualSystemSettingData" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">"#
+        )?;
+
+        writeln!(f, "  <References>")?;
+        for file in &self.files {
+            write!(
+                f,
+                r#"    <File ovf:href="{}" ovf:id="{}""#,
+                escape(&file.href),
+                escape(&file.id)
+            )?;
+            if let Some(size) = file.size {
+                write!(f, r#" ovf:size="{size}""#)?;
+            }
+            if let Some(compression) = &file.compression {
+                write!(f, r#" ovf:compression="{}""#, escape(compression))?;
+            }
+            writeln!(f, "/>")?;
+        }
+        writeln!(f, "  </References>")?;
+
+        writeln!(f, "  <DiskSection>")?;
+        writeln!(f, "    <Info>Virtual disk information</Info>")?;
+        for disk in &self.disks {
+            write!(
+                f,
+                r#"    <Disk ovf:capacity="{}" ovf:capacityAllocationUnits="byte" ovf:diskId="{}""#,
+                disk.capacity,
+                escape(&disk.id)
+            )?;
+            if let Some(file_ref) = &disk.file_ref {
+                write!(f, r#" ovf:fileRef="{}""#, escape(file_ref))?;
+            }
+            if let Some(format) = &disk.format {
+                write!(f, r#" ovf:format="{}""#, escape(format))?;
+            }
+            if let Some(populated) = disk.populated_size {
+                write!(f, r#" ovf:populatedSize="{populated}""#)?;
+            }
+            writeln!(f, "/>")?;
+        }
+        writeln!(f, "  </DiskSection>")?;
+
+        if !self.networks.is_empty() {
+            writeln!(f, "  <NetworkSection>")?;
+            writeln!(f, "    <Info>The list of logical networks</Info>")?;
+            for network in &self.networks {
+                writeln!(f, r#"    <Network ovf:name="{}">"#, escape(network))?;
+                writeln!(
+                    f,
+                    "      <Description>The {} network</Description>",
+                    escape(network)
+                )?;
+                writeln!(f, "    </Network>")?;
+            }
+            writeln!(f, "  </NetworkSection>")?;
+        }
+
+        let system = &self.system;
+        writeln!(f, r#"  <VirtualSystem ovf:id="{}">"#, escape(&system.id))?;
+        writeln!(f, "    <Info>A virtual machine</Info>")?;
+        if let Some(name) = &system.name {
+            writeln!(f, "    <Name>{}</Name>", escape(name))?;
+        }
+        if let Some(os_id) = system.os_id {
+            writeln!(f, r#"    <OperatingSystemSection ovf:id="{os_id}">"#)?;
+            writeln!(
+                f,
+                "      <Info>The kind of installed guest operating system</Info>"
+            )?;
+            if let Some(description) = &system.os_description {
+                writeln!(
+                    f,
+                    "      <Description>{}</Description>",
+                    escape(description)
+                )?;
+            }
+            writeln!(f, "    </OperatingSystemSection>")?;
+        }
+        writeln!(f, "    <VirtualHardwareSection>")?;
+        writeln!(f, "      <Info>Virtual hardware requirements</Info>")?;
+        writeln!(f, "      <System>")?;
+        writeln!(
+            f,
+            "        <vssd:ElementName>Virtual Hardware Family</vssd:ElementName>"
+        )?;
+        writeln!(f, "        <vssd:InstanceID>0</vssd:InstanceID>")?;
+        writeln!(
+            f,
+            "        <vssd:VirtualSystemIdentifier>{}</vssd:VirtualSystemIdentifier>",
+            escape(system.name.as_deref().unwrap_or(&system.id))
+        )?;
+        if let Some(system_type) = &system.system_type {
+            writeln!(
+                f,
+                "        <vssd:VirtualSystemType>{}</vssd:VirtualSystemType>",
+                escape(system_type)
+            )?;
+        }
+        writeln!(f, "      </System>")?;
+        for item in &system.items {
+            f.write_str(&format_item(item))?;
+        }
+        writeln!(f, "    </VirtualHardwareSection>")?;
+        writeln!(f, "  </VirtualSystem>")?;
+        writeln!(f, "</Envelope>")
+    }
+}
+
+fn format_item(item: &HardwareItem) -> String {
+    // The CIM schema requires the rasd elements in alphabetical order.
+    let fields = [
+        ("Address", item.address.clone()),
+        ("AddressOnParent", item.address_on_parent.clone()),
+        ("AllocationUnits", item.allocation_units.clone()),
+        (
+            "AutomaticAllocation",
+            item.automatic_allocation.map(|a| a.to_string()),
+        ),
+        ("Connection", item.connection.clone()),
+        ("ElementName", item.element_name.clone()),
+        ("HostResource", item.host_resource.clone()),
+        ("InstanceID", Some(item.instance_id.clone())),
+        ("Parent", item.parent.clone()),
+        ("ResourceSubType", item.resource_sub_type.clone()),
+        ("ResourceType", Some(item.resource_type.to_string())),
+        (
+            "VirtualQuantity",
+            item.virtual_quantity.map(|q| q.to_string()),
+        ),
+    ];
+    let mut s = String::from("      <Item>\n");
+    for (name, value) in fields {
+        if let Some(value) = value {
+            let _ = writeln!(s, "        <rasd:{name}>{}</rasd:{name}>", escape(&value));
+        }
+    }
+    s.push_str("      </Item>\n");
+    s
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Virtual hardware of an OVF appliance, as reported by `vmi inspect ova`.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Appliance {
+    pub name: Option<String>,
+    /// Guest operating system description.
+    pub os: Option<String>,
+    pub cpus: Option<u64>,
+    /// Memory size in bytes.
+    pub memory: Option<u64>,
+    pub nics: Vec<NetworkAdapter>,
+    pub disks: Vec<ApplianceDisk>,
+    /// Result of checking each manifest entry; empty when the appliance has no manifest.
+    pub manifest: Vec<ManifestCheck>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct NetworkAdapter {
+    pub name: Option<String>,
+    /// Adapter model, e.g. `E1000` or `VmxNet3`.
+    pub adapter: Option<String>,
+    /// Logical network the adapter is connected to.
+    pub network: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ApplianceDisk {
+    pub id: String,
+    /// File holding the disk inside the appliance.
+    pub file: Option<String>,
+    /// Capacity in bytes.
+    pub capacity: u64,
+    /// Disk format URI.
+    pub format: Option<String>,
+    /// Controller the disk is attached to, e.g. `scsi (lsilogic)`.
+    pub controller: Option<String>,
+}
+
+/// Outcome of verifying one file listed in an OVA manifest.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ManifestCheck {
+    pub file: String,
+    /// Digest algorithm, e.g. `SHA256`.
+    pub algorithm: String,
+    /// Whether the file exists and its digest matches the manifest.
+    pub valid: bool,
+}
@@ -0,0 +1,414 @@
+//! OVA appliances: an OVF descriptor, its manifest and disks in a single tar archive.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Context, Result};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use tar::{Archive, Builder, EntryType, Header};
+use tracing::{info, warn};
+
+use super::{
+    Appliance, Disk, Envelope, FileReference, HardwareItem, ManifestCheck, VirtualSystem,
+    FORMAT_STREAM_OPTIMIZED, RESOURCE_CPU, RESOURCE_DISK, RESOURCE_ETHERNET,
+    RESOURCE_IDE_CONTROLLER, RESOURCE_MEMORY, RESOURCE_SCSI_CONTROLLER,
+};
+use crate::disk::{DiskWriter, VirtualDisk};
+use crate::qcow2::{Qcow2, MAGIC as QCOW2_MAGIC};
+use crate::util::{human_size, read_exact_at, stream_len, Slice};
+use crate::vmdk::{write_stream, AdapterType, Vmdk, VmdkOptions, SPARSE_MAGIC};
+
+/// A file stored in the OVA's tar archive.
+struct Entry {
+    name: String,
+    offset: u64,
+    size: u64,
+}
+
+/// An OVA appliance opened for reading.
+pub struct Ova {
+    file: File,
+    entries: Vec<Entry>,
+    envelope: Envelope,
+}
+
+impl Ova {
+    pub fn open_path(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mut entries = Vec::new();
+        let mut archive = Archive::new(&file);
+        for entry in archive
+            .entries_with_seek()
+            .context("OVA is not a tar archive")?
+        {
+            let entry = entry.context("failed to read OVA tar entry")?;
+            if !matches!(
+                entry.header().entry_type(),
+                EntryType::Regular | EntryType::Continuous
+            ) {
+                continue;
+            }
+            let name = entry.path()?.to_string_lossy().into_owned();
+            entries.push(Entry {
+                name: name.trim_start_matches("./").to_string(),
+                offset: entry.raw_file_position(),
+                size: entry.size(),
+            });
+        }
+
+        // The descriptor is required to be the first file, but be lenient about it.
+        let ovf = entries
+            .iter()
+            .find(|e| e.name.ends_with(".ovf"))
+            .context("OVA contains no .ovf descriptor")?;
+        let mut text = String::new();
+        Slice::new(&file, ovf.offset, ovf.size)
+            .read_to_string(&mut text)
+            .context("OVF descriptor is not UTF-8")?;
+        let envelope = Envelope::parse(&text)?;
+        Ok(Ova {
+            file,
+            entries,
+            envelope,
+        })
+    }
+
+    pub fn envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+
+    fn entry(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|e| e.name == name)
+    }
+
+    fn open_entry(&self, index: usize) -> Result<Slice<File>> {
+        let entry = &self.entries[index];
+        Ok(Slice::new(self.file.try_clone()?, entry.offset, entry.size))
+    }
+
+    fn read_entry(&self, index: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open_entry(index)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Check every file listed in the manifest against its digest.
+    ///
+    /// Returns an empty list when the appliance has no manifest.
+    pub fn verify_manifest(&self) -> Result<Vec<ManifestCheck>> {
+        let Some(mf) = self.entries.iter().position(|e| e.name.ends_with(".mf")) else {
+            return Ok(Vec::new());
+        };
+        let text = String::from_utf8(self.read_entry(mf)?).context("OVA manifest is not UTF-8")?;
+        let mut checks = Vec::new();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            // Lines look like `SHA256(disk1.vmdk)= 0123...`.
+            let parsed = line.split_once('(').and_then(|(algorithm, rest)| {
+                let (file, digest) = rest.rsplit_once(")=")?;
+                Some((algorithm.trim(), file, digest.trim()))
+            });
+            let Some((algorithm, file, expected)) = parsed else {
+                bail!("invalid OVA manifest line {line:?}");
+            };
+            let valid = match self.entry(file) {
+                Some(index) => {
+                    let actual = digest(algorithm, &mut self.open_entry(index)?)?;
+                    actual.eq_ignore_ascii_case(expected)
+                }
+                None => false,
+            };
+            if !valid {
+                warn!("{file} does not match its {algorithm} digest in the OVA manifest");
+            }
+            checks.push(ManifestCheck {
+                file: file.to_string(),
+                algorithm: algorithm.to_string(),
+                valid,
+            });
+        }
+        Ok(checks)
+    }
+
+    /// Describe the appliance's virtual hardware and verify its manifest.
+    pub fn appliance(&self) -> Result<Appliance> {
+        let mut appliance = self.envelope.appliance();
+        appliance.manifest = self.verify_manifest()?;
+        Ok(appliance)
+    }
+
+    /// Open the guest-visible contents of the appliance's disk at `index`.
+    pub fn open_disk(&self, index: usize) -> Result<Box<dyn VirtualDisk>> {
+        let disk = self
+            .envelope
+            .disks
+            .get(index)
+            .with_context(|| format!("OVA has no disk {index}"))?;
+        let file = disk
+            .file_ref
+            .as_deref()
+            .and_then(|r| self.envelope.file(r))
+            .with_context(|| format!("OVF disk {} has no file", disk.id))?;
+        ensure!(
+            file.compression.is_none(),
+            "compressed OVF disk files are not supported"
+        );
+        let entry = self
+            .entry(&file.href)
+            .with_context(|| format!("{} is missing from the OVA", file.href))?;
+
+        let mut slice = self.open_entry(entry)?;
+        let mut magic = [0u8; 4];
+        read_exact_at(&mut slice, 0, &mut magic)?;
+        slice.rewind()?;
+        if &magic == SPARSE_MAGIC {
+            Ok(Box::new(Vmdk::open(Box::new(slice))?))
+        } else if &magic == QCOW2_MAGIC {
+            Ok(Box::new(Qcow2::open(slice)?))
+        } else {
+            bail!(
+                "unsupported format {} of OVF disk {}",
+                disk.format.as_deref().unwrap_or("unknown"),
+                disk.id
+            )
+        }
+    }
+}
+
+/// Hex digest of everything read from `r`.
+fn digest<R: Read>(algorithm: &str, r: &mut R) -> Result<String> {
+    fn hash<D: Digest, R: Read>(r: &mut R) -> Result<String> {
+        let mut hasher = D::new();
+        let mut buf = vec![0u8; 1 << 20];
+        loop {
+            let n = r.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+    match algorithm {
+        "SHA1" => hash::<Sha1, _>(r),
+        "SHA256" => hash::<Sha256, _>(r),
+        "SHA512" => hash::<Sha512, _>(r),
+        other => bail!("unsupported OVA manifest digest {other}"),
+    }
+}
+
+/// Options for the virtual machine described by a generated OVA.
+#[derive(Debug, Clone)]
+pub struct OvaOptions {
+    pub cpus: u64,
+    /// Memory size in MiB.
+    pub memory_mib: u64,
+    /// Logical network the single network adapter is connected to.
+    pub network: String,
+    pub vmdk: VmdkOptions,
+}
+
+impl Default for OvaOptions {
+    fn default() -> Self {
+        OvaOptions {
+            cpus: 1,
+            memory_mib: 1024,
+            network: "VM Network".to_string(),
+            vmdk: VmdkOptions::default(),
+        }
+    }
+}
+
+impl DiskWriter for OvaOptions {
+    fn write_disk(&self, disk: &mut dyn VirtualDisk, path: &Path) -> Result<()> {
+        write_ova(disk, path, self)
+    }
+}
+
+/// Write the guest disk exposed by `disk` to a new OVA appliance at `path`.
+///
+/// The appliance holds an OVF 1.0 descriptor, a SHA256 manifest and the disk as a
+/// streamOptimized VMDK, in that order.
+pub fn write_ova<R: Read + Seek + ?Sized>(
+    disk: &mut R,
+    path: &Path,
+    options: &OvaOptions,
+) -> Result<()> {
+    let name = path
+        .file_stem()
+        .context("ova path has no file name")?
+        .to_string_lossy()
+        .into_owned();
+    let size = stream_len(disk)?;
+    info!(
+        "writing {} ova appliance to {}",
+        human_size(size),
+        path.display()
+    );
+
+    // The manifest needs the digest of the disk before the archive is written.
+    let tmp_path = path.with_extension("vmdk.tmp");
+    let mut vmdk = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)
+        .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+    let result = write_appliance(disk, size, &mut vmdk, path, &name, options);
+    fs::remove_file(&tmp_path)
+        .with_context(|| format!("failed to remove {}", tmp_path.display()))?;
+    result
+}
+
+fn write_appliance<R: Read + ?Sized>(
+    disk: &mut R,
+    size: u64,
+    vmdk: &mut File,
+    path: &Path,
+    name: &str,
+    options: &OvaOptions,
+) -> Result<()> {
+    let disk_name = format!("{name}-disk1.vmdk");
+    let mut out = BufWriter::with_capacity(4 << 20, &mut *vmdk);
+    write_stream(disk, &mut out, size, &disk_name, &options.vmdk)?;
+    out.flush()?;
+    drop(out);
+    let vmdk_size = vmdk.seek(SeekFrom::End(0))?;
+    vmdk.rewind()?;
+    let vmdk_digest = digest("SHA256", vmdk)?;
+    vmdk.rewind()?;
+
+    let ovf_name = format!("{name}.ovf");
+    let ovf = appliance_envelope(name, &disk_name, vmdk_size, size, options).to_string();
+    let manifest = format!(
+        "SHA256({ovf_name})= {}\nSHA256({disk_name})= {vmdk_digest}\n",
+        hex::encode(Sha256::digest(ovf.as_bytes()))
+    );
+
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut builder = Builder::new(BufWriter::with_capacity(4 << 20, file));
+    append_file(&mut builder, &ovf_name, ovf.len() as u64, ovf.as_bytes())?;
+    append_file(
+        &mut builder,
+        &format!("{name}.mf"),
+        manifest.len() as u64,
+        manifest.as_bytes(),
+    )?;
+    append_file(&mut builder, &disk_name, vmdk_size, vmdk)?;
+    let file = builder
+        .into_inner()?
+        .into_inner()
+        .map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn append_file<W: Write, R: Read>(
+    builder: &mut Builder<W>,
+    name: &str,
+    size: u64,
+    data: R,
+) -> Result<()> {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut header = Header::new_ustar();
+    header.set_path(name)?;
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_entry_type(EntryType::Regular);
+    header.set_cksum();
+    builder
+        .append(&header, data)
+        .with_context(|| format!("failed to add {name} to the ova"))
+}
+
+/// Describe a virtual machine with one disk and one network adapter.
+fn appliance_envelope(
+    name: &str,
+    disk_name: &str,
+    file_size: u64,
+    capacity: u64,
+    options: &OvaOptions,
+) -> Envelope {
+    let adapter = options.vmdk.adapter_type;
+    let (controller_type, controller_sub_type) = match adapter {
+        AdapterType::Ide => (RESOURCE_IDE_CONTROLLER, None),
+        _ => (RESOURCE_SCSI_CONTROLLER, Some(adapter.as_str().to_string())),
+    };
+    let items = vec![
+        HardwareItem {
+            instance_id: "1".to_string(),
+            resource_type: RESOURCE_CPU,
+            element_name: Some(format!("{} virtual CPU(s)", options.cpus)),
+            virtual_quantity: Some(options.cpus),
+            allocation_units: Some("hertz * 10^6".to_string()),
+            ..Default::default()
+        },
+        HardwareItem {
+            instance_id: "2".to_string(),
+            resource_type: RESOURCE_MEMORY,
+            element_name: Some(format!("{}MB of memory", options.memory_mib)),
+            virtual_quantity: Some(options.memory_mib),
+            allocation_units: Some("byte * 2^20".to_string()),
+            ..Default::default()
+        },
+        HardwareItem {
+            instance_id: "3".to_string(),
+            resource_type: controller_type,
+            resource_sub_type: controller_sub_type,
+            element_name: Some("Disk controller 0".to_string()),
+            address: Some("0".to_string()),
+            ..Default::default()
+        },
+        HardwareItem {
+            instance_id: "4".to_string(),
+            resource_type: RESOURCE_DISK,
+            element_name: Some("Hard disk 1".to_string()),
+            address_on_parent: Some("0".to_string()),
+            parent: Some("3".to_string()),
+            host_resource: Some("ovf:/disk/vmdisk1".to_string()),
+            ..Default::default()
+        },
+        HardwareItem {
+            instance_id: "5".to_string(),
+            resource_type: RESOURCE_ETHERNET,
+            resource_sub_type: Some("E1000".to_string()),
+            element_name: Some("Network adapter 1".to_string()),
+            connection: Some(options.network.clone()),
+            automatic_allocation: Some(true),
+            ..Default::default()
+        },
+    ];
+    Envelope {
+        files: vec![FileReference {
+            id: "file1".to_string(),
+            href: disk_name.to_string(),
+            size: Some(file_size),
+            compression: None,
+        }],
+        disks: vec![Disk {
+            id: "vmdisk1".to_string(),
+            file_ref: Some("file1".to_string()),
+            capacity,
+            format: Some(FORMAT_STREAM_OPTIMIZED.to_string()),
+            populated_size: None,
+        }],
+        networks: vec![options.network.clone()],
+        system: VirtualSystem {
+            id: name.to_string(),
+            name: Some(name.to_string()),
+            // CIM operating system "Other".
+            os_id: Some(1),
+            os_description: Some("Other".to_string()),
+            system_type: Some("vmx-10".to_string()),
+            items,
+        },
+    }
+}
@@ -0,0 +1,324 @@
+//! MBR and GPT partition table parsing.
+
+use std::fmt;
+use std::io::{Read, Seek};
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::filesystem::Filesystem;
+use crate::util::{format_guid, le_u16, le_u32, le_u64, read_exact_at};
+
+/// The partitioning scheme of a disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionTableKind {
+    Mbr,
+    Gpt,
+}
+
+impl fmt::Display for PartitionTableKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionTableKind::Mbr => f.write_str("mbr"),
+            PartitionTableKind::Gpt => f.write_str("gpt"),
+        }
+    }
+}
+
+/// A parsed partition table.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct PartitionTable {
+    pub kind: PartitionTableKind,
+    /// Logical sector size the table was found with.
+    pub sector_size: u64,
+    /// MBR disk signature or GPT disk GUID.
+    pub disk_id: String,
+    pub partitions: Vec<Partition>,
+}
+
+/// A single partition table entry.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Partition {
+    /// 1-based partition number, as the Linux kernel would name it.
+    pub number: u32,
+    pub start_lba: u64,
+    /// Last sector of the partition (inclusive).
+    pub end_lba: u64,
+    /// MBR type byte (e.g. `0x83`) or GPT type GUID.
+    pub type_id: String,
+    pub type_name: Option<&'static str>,
+    /// GPT partition name.
+    pub name: Option<String>,
+    /// GPT unique partition GUID.
+    pub guid: Option<String>,
+    pub flags: Vec<&'static str>,
+    /// Filesystem found inside the partition, if any.
+    pub filesystem: Option<Filesystem>,
+}
+
+impl Partition {
+    pub fn sectors(&self) -> u64 {
+        self.end_lba + 1 - self.start_lba
+    }
+}
+
+/// Read the partition table from the start of a disk, if it has one.
+pub fn read_partition_table<R: Read + Seek + ?Sized>(
+    r: &mut R,
+    disk_size: u64,
+) -> Result<Option<PartitionTable>> {
+    if disk_size < 1024 {
+        return Ok(None);
+    }
+    let mut mbr = [0u8; 512];
+    read_exact_at(r, 0, &mut mbr)?;
+
+    for sector_size in [512u64, 4096] {
+        if let Some(table) = read_gpt(r, sector_size, disk_size)? {
+            return Ok(Some(table));
+        }
+    }
+    read_mbr(r, &mbr, disk_size)
+}
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+fn read_gpt<R: Read + Seek + ?Sized>(
+    r: &mut R,
+    sector_size: u64,
+    disk_size: u64,
+) -> Result<Option<PartitionTable>> {
+    if disk_size < sector_size * 3 {
+        return Ok(None);
+    }
+    let last_lba = disk_size / sector_size - 1;
+    let mut header = vec![0u8; sector_size as usize];
+    read_exact_at(r, sector_size, &mut header)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    if !gpt_header_valid(&header) {
+        warn!("primary GPT header is corrupt, trying the backup header");
+        read_exact_at(r, last_lba * sector_size, &mut header)?;
+        if &header[0..8] != GPT_SIGNATURE || !gpt_header_valid(&header) {
+            warn!("backup GPT header is also corrupt");
+            return Ok(None);
+        }
+    }
+
+    let disk_guid = format_guid(header[56..72].try_into().unwrap());
+    let entries_lba = le_u64(&header, 72);
+    let num_entries = le_u32(&header, 80) as usize;
+    let entry_size = le_u32(&header, 84) as usize;
+    if entry_size < 128 || num_entries > 4096 {
+        warn!("unsupported GPT entry layout ({num_entries} x {entry_size} bytes)");
+        return Ok(None);
+    }
+    let mut entries = vec![0u8; num_entries * entry_size];
+    read_exact_at(r, entries_lba * sector_size, &mut entries)?;
+    if crc32fast::hash(&entries) != le_u32(&header, 88) {
+        warn!("GPT partition entry array checksum mismatch");
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid: [u8; 16] = entry[0..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let type_id = format_guid(&type_guid);
+        let attributes = le_u64(entry, 48);
+        let name_units: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+        let name = String::from_utf16_lossy(&name_units);
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            start_lba: le_u64(entry, 32),
+            end_lba: le_u64(entry, 40),
+            type_name: gpt_type_name(&type_id),
+            type_id,
+            name: (!name.is_empty()).then_some(name),
+            guid: Some(format_guid(entry[16..32].try_into().unwrap())),
+            flags: gpt_flags(attributes),
+            filesystem: None,
+        });
+    }
+
+    Ok(Some(PartitionTable {
+        kind: PartitionTableKind::Gpt,
+        sector_size,
+        disk_id: disk_guid,
+        partitions,
+    }))
+}
+
+fn gpt_header_valid(header: &[u8]) -> bool {
+    let size = le_u32(header, 12) as usize;
+    if !(92..=header.len()).contains(&size) {
+        return false;
+    }
+    let mut copy = header[..size].to_vec();
+    copy[16..20].fill(0);
+    crc32fast::hash(&copy) == le_u32(header, 16)
+}
+
+fn gpt_flags(attributes: u64) -> Vec<&'static str> {
+    const FLAGS: [(u32, &str); 6] = [
+        (0, "required"),
+        (1, "no-block-io"),
+        (2, "legacy-bios-bootable"),
+        (60, "read-only"),
+        (62, "hidden"),
+        (63, "no-automount"),
+    ];
+    FLAGS
+        .iter()
+        .filter(|(bit, _)| attributes & (1 << bit) != 0)
+        .map(|&(_, name)| name)
+        .collect()
+}
+
+fn gpt_type_name(guid: &str) -> Option<&'static str> {
+    Some(match guid {
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B" => "EFI System",
+        "21686148-6449-6E6F-744E-656564454649" => "BIOS boot",
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => "Linux filesystem",
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => "Linux swap",
+        "E6D6D379-F507-44C2-A23C-238F2A3DF928" => "Linux LVM",
+        "A19D880F-05FC-4D3B-A006-743F0F84911E" => "Linux RAID",
+        "933AC7E1-2EB4-4F13-B844-0E14E2AEF915" => "Linux home",
+        "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709" => "Linux root (x86-64)",
+        "B921B045-1DF0-41C3-AF44-4C6F280D3FAE" => "Linux root (ARM-64)",
+        "BC13C2FF-59E6-4262-A352-B275FD6F7172" => "Linux extended boot",
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => "Microsoft basic data",
+        "E3C9E316-0B5C-4DB8-817D-F92DF00215AE" => "Microsoft reserved",
+        "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC" => "Windows recovery",
+        "6A898CC3-1DD2-11B2-99A6-080020736631" => "ZFS",
+        "48465300-0000-11AA-AA11-00306543ECAC" => "Apple HFS+",
+        _ => return None,
+    })
+}
+
+fn read_mbr<R: Read + Seek + ?Sized>(
+    r: &mut R,
+    mbr: &[u8; 512],
+    disk_size: u64,
+) -> Result<Option<PartitionTable>> {
+    if le_u16(mbr, 510) != 0xAA55 || !mbr_entries_valid(mbr) {
+        return Ok(None);
+    }
+    // A FAT or NTFS volume boot record carries the same signature but is not a partition table.
+    if &mbr[3..11] == b"NTFS    "
+        || &mbr[54..59] == b"FAT12"
+        || &mbr[54..59] == b"FAT16"
+        || &mbr[82..87] == b"FAT32"
+    {
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for i in 0..4 {
+        let e = &mbr[446 + i * 16..446 + (i + 1) * 16];
+        let Some(p) = mbr_partition(e, i as u32 + 1, 0) else {
+            continue;
+        };
+        if is_extended(e[4]) {
+            extended = Some(p.start_lba);
+        }
+        partitions.push(p);
+    }
+
+    // Walk the chain of extended boot records to find logical partitions.
+    if let Some(ext_start) = extended {
+        let mut ebr_lba = ext_start;
+        let mut number = 5;
+        let mut ebr = [0u8; 512];
+        while number < 5 + 128 && (ebr_lba + 1) * 512 <= disk_size {
+            read_exact_at(r, ebr_lba * 512, &mut ebr)?;
+            if le_u16(&ebr, 510) != 0xAA55 {
+                warn!("invalid extended boot record at LBA {ebr_lba}");
+                break;
+            }
+            if let Some(p) = mbr_partition(&ebr[446..462], number, ebr_lba) {
+                partitions.push(p);
+                number += 1;
+            }
+            let next = &ebr[462..478];
+            if next[4] == 0 || le_u32(next, 12) == 0 {
+                break;
+            }
+            ebr_lba = ext_start + u64::from(le_u32(next, 8));
+        }
+    }
+
+    if partitions.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(PartitionTable {
+        kind: PartitionTableKind::Mbr,
+        sector_size: 512,
+        disk_id: format!("0x{:08x}", le_u32(mbr, 440)),
+        partitions,
+    }))
+}
+
+fn mbr_entries_valid(mbr: &[u8; 512]) -> bool {
+    (0..4).all(|i| matches!(mbr[446 + i * 16], 0x00 | 0x80))
+}
+
+fn is_extended(type_byte: u8) -> bool {
+    matches!(type_byte, 0x05 | 0x0F | 0x85)
+}
+
+fn mbr_partition(e: &[u8], number: u32, base_lba: u64) -> Option<Partition> {
+    let type_byte = e[4];
+    let start = u64::from(le_u32(e, 8));
+    let sectors = u64::from(le_u32(e, 12));
+    if type_byte == 0 || sectors == 0 {
+        return None;
+    }
+    let mut flags = Vec::new();
+    if e[0] == 0x80 {
+        flags.push("boot");
+    }
+    if type_byte == 0xEE {
+        flags.push("protective");
+    }
+    Some(Partition {
+        number,
+        start_lba: base_lba + start,
+        end_lba: base_lba + start + sectors - 1,
+        type_id: format!("0x{type_byte:02x}"),
+        type_name: mbr_type_name(type_byte),
+        name: None,
+        guid: None,
+        flags,
+        filesystem: None,
+    })
+}
+
+fn mbr_type_name(type_byte: u8) -> Option<&'static str> {
+    Some(match type_byte {
+        0x01 => "FAT12",
+        0x04 | 0x06 | 0x0E => "FAT16",
+        0x05 | 0x0F | 0x85 => "Extended",
+        0x07 => "HPFS/NTFS/exFAT",
+        0x0B | 0x0C => "W95 FAT32",
+        0x27 => "Hidden NTFS WinRE",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x8E => "Linux LVM",
+        0xA5 => "FreeBSD",
+        0xEE => "GPT protective",
+        0xEF => "EFI System",
+        0xFD => "Linux raid autodetect",
+        _ => return None,
+    })
+}
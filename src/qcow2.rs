@@ -0,0 +1,602 @@
+//! Reader and writer for QEMU copy-on-write (QCOW2) images, versions 2 and 3.
+//!
+//! See <https://www.qemu.org/docs/master/interop/qcow2.html> for the on-disk format.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use tracing::warn;
+
+use crate::disk::{push_extent, Extent, ExtentKind, VirtualDisk};
+use crate::util::{be_u32, be_u64, read_exact_at, stream_len, ReadSeek};
+
+mod chain;
+mod writer;
+
+pub use chain::{flatten_qcow2, rebase_qcow2};
+pub use writer::{write_qcow2, Qcow2Options};
+
+pub(crate) const MAGIC: &[u8; 4] = b"QFI\xfb";
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_EXTERNAL_DATA: u64 = 1 << 2;
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+const INCOMPAT_EXTENDED_L2: u64 = 1 << 4;
+const INCOMPAT_KNOWN: u64 = INCOMPAT_DIRTY
+    | INCOMPAT_CORRUPT
+    | INCOMPAT_EXTERNAL_DATA
+    | INCOMPAT_COMPRESSION_TYPE
+    | INCOMPAT_EXTENDED_L2;
+
+const EXT_END: u32 = 0;
+const EXT_BACKING_FORMAT: u32 = 0xE279_2ACA;
+
+// Bits 9-55 of L1 and standard L2 entries hold a host offset.
+const OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+const L2_COMPRESSED: u64 = 1 << 62;
+const L2_ZERO: u64 = 1 << 0;
+
+// Number of L2 tables kept in memory while reading.
+const L2_CACHE_TABLES: usize = 64;
+
+// Longest backing chain we follow before assuming a loop.
+const MAX_CHAIN_DEPTH: usize = 64;
+
+/// Algorithm used for compressed clusters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Raw deflate, the default.
+    Zlib,
+    Zstd,
+}
+
+/// The fixed QCOW2 header plus the header extensions `vmi` understands.
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub version: u32,
+    pub backing_file: Option<String>,
+    pub backing_format: Option<String>,
+    pub cluster_bits: u32,
+    /// Virtual disk size in bytes.
+    pub size: u64,
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    pub nb_snapshots: u32,
+    pub incompatible_features: u64,
+    pub compatible_features: u64,
+    pub refcount_order: u32,
+    pub header_length: u32,
+    pub compression: Compression,
+}
+
+impl Header {
+    /// Parse the header found at the start of `r`.
+    pub fn read<R: Read + Seek + ?Sized>(r: &mut R) -> Result<Self> {
+        let mut buf = [0u8; 112];
+        read_exact_at(r, 0, &mut buf[..72]).context("failed to read qcow2 header")?;
+        ensure!(&buf[0..4] == MAGIC, "not a qcow2 image (bad magic)");
+        let version = be_u32(&buf, 4);
+        ensure!(
+            version == 2 || version == 3,
+            "unsupported qcow2 version {version}"
+        );
+
+        let cluster_bits = be_u32(&buf, 20);
+        ensure!(
+            (9..=21).contains(&cluster_bits),
+            "invalid qcow2 cluster_bits {cluster_bits}"
+        );
+        let crypt_method = be_u32(&buf, 32);
+        ensure!(
+            crypt_method == 0,
+            "encrypted qcow2 images are not supported"
+        );
+
+        let mut header = Header {
+            version,
+            backing_file: None,
+            backing_format: None,
+            cluster_bits,
+            size: be_u64(&buf, 24),
+            l1_size: be_u32(&buf, 36),
+            l1_table_offset: be_u64(&buf, 40),
+            refcount_table_offset: be_u64(&buf, 48),
+            refcount_table_clusters: be_u32(&buf, 56),
+            nb_snapshots: be_u32(&buf, 60),
+            incompatible_features: 0,
+            compatible_features: 0,
+            refcount_order: 4,
+            header_length: 72,
+            compression: Compression::Zlib,
+        };
+
+        if version >= 3 {
+            read_exact_at(r, 72, &mut buf[72..104])?;
+            header.incompatible_features = be_u64(&buf, 72);
+            header.compatible_features = be_u64(&buf, 80);
+            header.refcount_order = be_u32(&buf, 96);
+            header.header_length = be_u32(&buf, 100);
+            ensure!(
+                header.refcount_order <= 6,
+                "invalid qcow2 refcount_order {}",
+                header.refcount_order
+            );
+            if header.incompatible_features & INCOMPAT_COMPRESSION_TYPE != 0 {
+                ensure!(header.header_length > 104, "truncated qcow2 header");
+                read_exact_at(r, 104, &mut buf[104..105])?;
+                header.compression = match buf[104] {
+                    0 => Compression::Zlib,
+                    1 => Compression::Zstd,
+                    n => bail!("unsupported qcow2 compression type {n}"),
+                };
+            }
+        }
+
+        header.read_extensions(r)?;
+
+        let backing_offset = be_u64(&buf, 8);
+        let backing_len = be_u32(&buf, 16);
+        if backing_offset != 0 && backing_len != 0 {
+            ensure!(backing_len <= 1023, "qcow2 backing file name is too long");
+            let mut name = vec![0u8; backing_len as usize];
+            read_exact_at(r, backing_offset, &mut name)?;
+            header.backing_file = Some(String::from_utf8(name).context("invalid backing file")?);
+        }
+        Ok(header)
+    }
+
+    fn read_extensions<R: Read + Seek + ?Sized>(&mut self, r: &mut R) -> Result<()> {
+        let mut offset = u64::from(self.header_length);
+        let cluster_size = self.cluster_size();
+        while offset + 8 <= cluster_size {
+            let mut ext = [0u8; 8];
+            read_exact_at(r, offset, &mut ext)?;
+            let (kind, len) = (be_u32(&ext, 0), be_u32(&ext, 4));
+            if kind == EXT_END {
+                break;
+            }
+            if kind == EXT_BACKING_FORMAT {
+                let mut data = vec![0u8; len as usize];
+                read_exact_at(r, offset + 8, &mut data)?;
+                self.backing_format = Some(String::from_utf8_lossy(&data).into_owned());
+            }
+            offset += 8 + u64::from(len).next_multiple_of(8);
+        }
+        Ok(())
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn extended_l2(&self) -> bool {
+        self.incompatible_features & INCOMPAT_EXTENDED_L2 != 0
+    }
+}
+
+/// Where the guest data for an offset lives.
+#[derive(Debug, Clone, Copy)]
+enum Mapping {
+    Unallocated,
+    Zero,
+    Data(u64),
+    Compressed { host_offset: u64, len: usize },
+}
+
+/// A QCOW2 image exposed as a seekable stream of its guest-visible disk.
+pub struct Qcow2<R> {
+    inner: R,
+    header: Header,
+    l1: Vec<u64>,
+    l2_cache: HashMap<u64, Vec<u64>>,
+    // Host offset and contents of the most recently decompressed cluster.
+    compressed_cache: Option<(u64, Vec<u8>)>,
+    backing: Option<Backing>,
+    pos: u64,
+}
+
+/// The image that unallocated clusters are read from.
+struct Backing {
+    disk: Box<dyn ReadSeek>,
+    size: u64,
+}
+
+impl Qcow2<File> {
+    /// Open the QCOW2 image at `path`, along with its chain of backing files.
+    pub fn open_path(path: &Path) -> Result<Self> {
+        Self::open_chain(path, 0)
+    }
+
+    fn open_chain(path: &Path, depth: usize) -> Result<Self> {
+        ensure!(
+            depth < MAX_CHAIN_DEPTH,
+            "qcow2 backing chain is deeper than {MAX_CHAIN_DEPTH} images; is there a loop?"
+        );
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let header = Header::read(&mut &file)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let backing = match &header.backing_file {
+            Some(name) => {
+                let backing_path = resolve_backing_path(path, name);
+                let disk = open_backing(&backing_path, header.backing_format.as_deref(), depth)
+                    .with_context(|| {
+                        format!("failed to open backing file {}", backing_path.display())
+                    })?;
+                Some(disk)
+            }
+            None => None,
+        };
+        Self::open_with_backing(file, backing)
+    }
+}
+
+/// Resolve a backing file name the way QEMU does: relative names are relative to the
+/// directory of the overlay that references them.
+pub fn resolve_backing_path(overlay: &Path, backing: &str) -> PathBuf {
+    let backing = Path::new(backing);
+    if backing.is_absolute() {
+        return backing.to_path_buf();
+    }
+    match overlay.parent() {
+        Some(dir) => dir.join(backing),
+        None => backing.to_path_buf(),
+    }
+}
+
+/// Open a backing image, probing its format when the overlay does not record it.
+pub(crate) fn open_backing(
+    path: &Path,
+    format: Option<&str>,
+    depth: usize,
+) -> Result<Box<dyn ReadSeek>> {
+    let format = match format {
+        Some(format) => format,
+        None => probe_format(path)?,
+    };
+    match format {
+        "qcow2" => Ok(Box::new(Qcow2::open_chain(path, depth + 1)?)),
+        "raw" => Ok(Box::new(File::open(path)?)),
+        other => bail!("unsupported backing file format {other}"),
+    }
+}
+
+/// Tell a QCOW2 backing file from a raw one.
+pub(crate) fn probe_format(path: &Path) -> Result<&'static str> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut magic = [0u8; 4];
+    let is_qcow2 = file.read_exact(&mut magic).is_ok() && &magic == MAGIC;
+    Ok(if is_qcow2 { "qcow2" } else { "raw" })
+}
+
+impl<R: Read + Seek> Qcow2<R> {
+    /// Parse the header and L1 table of a standalone QCOW2 image.
+    pub fn open(inner: R) -> Result<Self> {
+        Self::open_with_backing(inner, None)
+    }
+
+    /// Parse a QCOW2 image whose unallocated clusters are read from `backing`.
+    pub fn open_with_backing(inner: R, backing: Option<Box<dyn ReadSeek>>) -> Result<Self> {
+        Self::open_inner(inner, backing, true)
+    }
+
+    /// Parse a QCOW2 image without its backing file; unallocated clusters read as zeroes.
+    fn open_detached(inner: R) -> Result<Self> {
+        Self::open_inner(inner, None, false)
+    }
+
+    fn open_inner(
+        mut inner: R,
+        backing: Option<Box<dyn ReadSeek>>,
+        require_backing: bool,
+    ) -> Result<Self> {
+        let header = Header::read(&mut inner)?;
+
+        let incompat = header.incompatible_features;
+        ensure!(
+            incompat & !INCOMPAT_KNOWN == 0,
+            "qcow2 image uses unknown incompatible features {:#x}",
+            incompat & !INCOMPAT_KNOWN
+        );
+        ensure!(
+            incompat & INCOMPAT_CORRUPT == 0,
+            "qcow2 image is marked corrupt; repair it with `qemu-img check -r all` first"
+        );
+        ensure!(
+            incompat & INCOMPAT_EXTERNAL_DATA == 0,
+            "qcow2 images with an external data file are not supported"
+        );
+        if incompat & INCOMPAT_DIRTY != 0 {
+            warn!("qcow2 image was not closed cleanly; its refcounts may be stale");
+        }
+        if let (Some(name), None, true) = (&header.backing_file, &backing, require_backing) {
+            bail!("qcow2 image needs its backing file {name}, open it with Qcow2::open_path");
+        }
+        let backing = match backing {
+            Some(mut disk) => {
+                let size = stream_len(&mut disk)?;
+                Some(Backing { disk, size })
+            }
+            None => None,
+        };
+
+        let mut l1_bytes = vec![0u8; header.l1_size as usize * 8];
+        read_exact_at(&mut inner, header.l1_table_offset, &mut l1_bytes)
+            .context("failed to read qcow2 L1 table")?;
+        let l1 = l1_bytes.chunks_exact(8).map(|c| be_u64(c, 0)).collect();
+
+        Ok(Qcow2 {
+            inner,
+            header,
+            l1,
+            l2_cache: HashMap::new(),
+            compressed_cache: None,
+            backing,
+            pos: 0,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Size of the guest-visible disk in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.header.size
+    }
+
+    /// Bytes of the image file in use according to its refcount table.
+    pub fn allocated_size(&mut self) -> Result<u64> {
+        let cluster_size = self.header.cluster_size();
+        let table_len = self.header.refcount_table_clusters as u64 * cluster_size;
+        let mut table = vec![0u8; table_len as usize];
+        read_exact_at(
+            &mut self.inner,
+            self.header.refcount_table_offset,
+            &mut table,
+        )
+        .context("failed to read qcow2 refcount table")?;
+
+        let bits = 1u64 << self.header.refcount_order;
+        let entries_per_block = cluster_size * 8 / bits;
+        let mut block = vec![0u8; cluster_size as usize];
+        let mut in_use = 0u64;
+        for entry in table.chunks_exact(8) {
+            let block_offset = be_u64(entry, 0) & !(cluster_size - 1);
+            if block_offset == 0 {
+                continue;
+            }
+            read_exact_at(&mut self.inner, block_offset, &mut block)?;
+            in_use += (0..entries_per_block)
+                .filter(|&i| refcount_at(&block, i, bits) != 0)
+                .count() as u64;
+        }
+        Ok(in_use * cluster_size)
+    }
+
+    fn l2_entries_per_table(&self) -> u64 {
+        let entry_size = if self.header.extended_l2() { 16 } else { 8 };
+        self.header.cluster_size() / entry_size
+    }
+
+    fn l2_table(&mut self, offset: u64) -> Result<&Vec<u64>> {
+        if !self.l2_cache.contains_key(&offset) {
+            if self.l2_cache.len() >= L2_CACHE_TABLES {
+                self.l2_cache.clear();
+            }
+            let mut bytes = vec![0u8; self.header.cluster_size() as usize];
+            read_exact_at(&mut self.inner, offset, &mut bytes)
+                .with_context(|| format!("failed to read qcow2 L2 table at {offset:#x}"))?;
+            let table = bytes.chunks_exact(8).map(|c| be_u64(c, 0)).collect();
+            self.l2_cache.insert(offset, table);
+        }
+        Ok(&self.l2_cache[&offset])
+    }
+
+    /// Whether the image itself, rather than its backing chain, defines any part of the
+    /// guest range `[offset, offset + len)`.
+    pub fn is_allocated(&mut self, offset: u64, len: u64) -> Result<bool> {
+        let end = (offset + len).min(self.header.size);
+        let mut pos = offset;
+        while pos < end {
+            let (mapping, avail) = self.map(pos)?;
+            if !matches!(mapping, Mapping::Unallocated) {
+                return Ok(true);
+            }
+            pos += avail;
+        }
+        Ok(false)
+    }
+
+    /// Resolve a guest offset, returning its mapping and how many bytes share that mapping.
+    fn map(&mut self, offset: u64) -> Result<(Mapping, u64)> {
+        let cluster_bits = self.header.cluster_bits;
+        let cluster_size = self.header.cluster_size();
+        let in_cluster = offset & (cluster_size - 1);
+        let cluster_index = offset >> cluster_bits;
+        let per_table = self.l2_entries_per_table();
+        let l1_index = (cluster_index / per_table) as usize;
+        let l2_index = (cluster_index % per_table) as usize;
+
+        let to_cluster_end = cluster_size - in_cluster;
+        let Some(&l1_entry) = self.l1.get(l1_index) else {
+            return Ok((Mapping::Unallocated, to_cluster_end));
+        };
+        let l2_offset = l1_entry & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok((Mapping::Unallocated, to_cluster_end));
+        }
+
+        let extended = self.header.extended_l2();
+        let table = self.l2_table(l2_offset)?;
+        let (entry, bitmap) = if extended {
+            (table[l2_index * 2], table[l2_index * 2 + 1])
+        } else {
+            (table[l2_index], 0)
+        };
+
+        if entry & L2_COMPRESSED != 0 {
+            // Compressed descriptors split the low 62 bits between offset and sector count.
+            let x = 62 - (cluster_bits - 8);
+            let host_offset = entry & ((1 << x) - 1);
+            let sectors = (entry & ((1 << 62) - 1)) >> x;
+            let len = ((sectors + 1) * 512 - (host_offset & 511)) as usize;
+            return Ok((Mapping::Compressed { host_offset, len }, to_cluster_end));
+        }
+
+        let host_cluster = entry & OFFSET_MASK;
+        if extended {
+            let subcluster_size = cluster_size / 32;
+            let sub = in_cluster / subcluster_size;
+            let to_sub_end = subcluster_size - in_cluster % subcluster_size;
+            let mapping = if bitmap & (1 << sub) != 0 {
+                Mapping::Data(host_cluster + in_cluster)
+            } else if bitmap & (1 << (32 + sub)) != 0 {
+                Mapping::Zero
+            } else {
+                Mapping::Unallocated
+            };
+            return Ok((mapping, to_sub_end));
+        }
+
+        let mapping = if self.header.version >= 3 && entry & L2_ZERO != 0 {
+            Mapping::Zero
+        } else if host_cluster == 0 {
+            Mapping::Unallocated
+        } else {
+            Mapping::Data(host_cluster + in_cluster)
+        };
+        Ok((mapping, to_cluster_end))
+    }
+
+    fn decompress(&mut self, host_offset: u64, len: usize) -> Result<&[u8]> {
+        let cached = matches!(&self.compressed_cache, Some((off, _)) if *off == host_offset);
+        if !cached {
+            let mut compressed = Vec::with_capacity(len);
+            self.inner.seek(SeekFrom::Start(host_offset))?;
+            // The final compressed cluster may be shorter than its sector-rounded length.
+            (&mut self.inner)
+                .take(len as u64)
+                .read_to_end(&mut compressed)?;
+
+            let mut cluster = vec![0u8; self.header.cluster_size() as usize];
+            match self.header.compression {
+                Compression::Zlib => {
+                    flate2::read::DeflateDecoder::new(&compressed[..]).read_exact(&mut cluster)
+                }
+                Compression::Zstd => zstd::stream::read::Decoder::with_buffer(&compressed[..])?
+                    .single_frame()
+                    .read_exact(&mut cluster),
+            }
+            .with_context(|| format!("failed to decompress qcow2 cluster at {host_offset:#x}"))?;
+            self.compressed_cache = Some((host_offset, cluster));
+        }
+        Ok(&self.compressed_cache.as_ref().unwrap().1)
+    }
+
+    fn read_mapped(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let size = self.header.size;
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let (mapping, avail) = self.map(self.pos)?;
+        let n = (buf.len() as u64).min(avail).min(size - self.pos) as usize;
+        let buf = &mut buf[..n];
+        match mapping {
+            Mapping::Unallocated => match &mut self.backing {
+                Some(backing) if self.pos < backing.size => {
+                    let n = n.min((backing.size - self.pos) as usize);
+                    read_exact_at(&mut backing.disk, self.pos, &mut buf[..n])?;
+                    buf[n..].fill(0);
+                }
+                _ => buf.fill(0),
+            },
+            Mapping::Zero => buf.fill(0),
+            Mapping::Data(host_offset) => read_exact_at(&mut self.inner, host_offset, buf)?,
+            Mapping::Compressed { host_offset, len } => {
+                let in_cluster = (self.pos & (self.header.cluster_size() - 1)) as usize;
+                let cluster = self.decompress(host_offset, len)?;
+                buf.copy_from_slice(&cluster[in_cluster..in_cluster + n]);
+            }
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Read for Qcow2<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_mapped(buf).map_err(io::Error::other)
+    }
+}
+
+impl<R: Read + Seek> Seek for Qcow2<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.header.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl<R: Read + Seek> VirtualDisk for Qcow2<R> {
+    fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    fn block_size(&self) -> u64 {
+        self.header.cluster_size()
+    }
+
+    /// Clusters left to the backing chain count as data.
+    fn extents(&mut self) -> Result<Vec<Extent>> {
+        let size = self.header.size;
+        let mut extents = Vec::new();
+        let mut pos = 0;
+        while pos < size {
+            let (mapping, avail) = self.map(pos)?;
+            let len = avail.min(size - pos);
+            let kind = match mapping {
+                Mapping::Zero => ExtentKind::Zero,
+                Mapping::Unallocated if self.backing.is_none() => ExtentKind::Zero,
+                _ => ExtentKind::Data,
+            };
+            push_extent(&mut extents, pos, len, kind);
+            pos += len;
+        }
+        Ok(extents)
+    }
+}
+
+/// Read entry `i` of a refcount block whose entries are `bits` wide.
+fn refcount_at(block: &[u8], i: u64, bits: u64) -> u64 {
+    if bits >= 8 {
+        let width = (bits / 8) as usize;
+        let start = i as usize * width;
+        block[start..start + width]
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | u64::from(b))
+    } else {
+        // Sub-byte entries are packed starting from the least significant bit.
+        let bit = i * bits;
+        (u64::from(block[(bit / 8) as usize]) >> (bit % 8)) & ((1 << bits) - 1)
+    }
+}
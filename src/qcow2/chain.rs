@@ -0,0 +1,106 @@
+//! Operations on QCOW2 backing chains.
+
+use std::fs::{self, File};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tracing::info;
+
+use super::writer::{BackingFile, Writer};
+use super::{open_backing, probe_format, resolve_backing_path, write_qcow2, Qcow2, Qcow2Options};
+use crate::util::{read_exact_at, stream_len, ReadSeek};
+
+/// Merge the overlay at `overlay` and its whole backing chain into a standalone image.
+pub fn flatten_qcow2(overlay: &Path, output: &Path, options: &Qcow2Options) -> Result<()> {
+    let mut image = Qcow2::open_path(overlay)?;
+    write_qcow2(&mut image, output, options)
+}
+
+/// Rewrite the overlay at `overlay` on top of `new_base`, or as a standalone image when
+/// `new_base` is `None`.
+///
+/// `new_base` is stored as given, so a relative path is relative to the overlay's directory.
+/// Clusters where the current chain and the new base differ are copied into the overlay so
+/// the guest sees the same disk afterwards. With `skip_compare` only the overlay's own
+/// clusters are kept, which is only correct when both bases hold identical data, but works
+/// when the old backing file is no longer available.
+pub fn rebase_qcow2(overlay: &Path, new_base: Option<&str>, skip_compare: bool) -> Result<()> {
+    let mut image = if skip_compare {
+        let file =
+            File::open(overlay).with_context(|| format!("failed to open {}", overlay.display()))?;
+        Qcow2::open_detached(file)?
+    } else {
+        Qcow2::open_path(overlay)?
+    };
+    let header = image.header().clone();
+    let size = header.size;
+    let cluster_size = header.cluster_size();
+
+    let (mut base, backing): (Option<(Box<dyn ReadSeek>, u64)>, _) = match new_base {
+        Some(name) => {
+            let path = resolve_backing_path(overlay, name);
+            let format = probe_format(&path)?;
+            let mut disk = open_backing(&path, Some(format), 0)
+                .with_context(|| format!("failed to open new base {}", path.display()))?;
+            let base_size = stream_len(&mut disk)?;
+            let backing = BackingFile {
+                name: name.to_string(),
+                format,
+            };
+            (Some((disk, base_size)), Some(backing))
+        }
+        None => (None, None),
+    };
+
+    info!(
+        "rebasing {} onto {}",
+        overlay.display(),
+        new_base.unwrap_or("nothing")
+    );
+    let tmp_path = overlay.with_extension("rebase.tmp");
+    let file = File::create(&tmp_path)
+        .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+    let options = Qcow2Options {
+        cluster_size,
+        compression: None,
+        lazy_refcounts: header.compatible_features & 1 != 0,
+    };
+    let mut writer = Writer::new(file, size, &options, backing);
+
+    let mut data = vec![0u8; cluster_size as usize];
+    let mut base_data = vec![0u8; cluster_size as usize];
+    for index in 0..size.div_ceil(cluster_size) {
+        let offset = index * cluster_size;
+        let len = (size - offset).min(cluster_size) as usize;
+        let allocated = image.is_allocated(offset, len as u64)?;
+        if skip_compare && !allocated {
+            continue;
+        }
+        data[len..].fill(0);
+        read_exact_at(&mut image, offset, &mut data[..len])?;
+
+        base_data.fill(0);
+        if let Some((disk, base_size)) = &mut base {
+            if offset < *base_size {
+                let n = (*base_size - offset).min(len as u64) as usize;
+                read_exact_at(disk, offset, &mut base_data[..n])?;
+            }
+        }
+        if !allocated && data == base_data {
+            continue;
+        }
+
+        if data.iter().all(|&b| b == 0) {
+            if base.is_some() {
+                writer.write_zero_cluster(index);
+            }
+        } else {
+            writer.write_cluster(index, &data)?;
+        }
+    }
+    writer.finish()?;
+
+    fs::rename(&tmp_path, overlay)
+        .with_context(|| format!("failed to replace {}", overlay.display()))?;
+    Ok(())
+}
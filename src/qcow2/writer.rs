@@ -0,0 +1,342 @@
+//! Writer producing QCOW2 version 3 images.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use flate2::{Compress, Compression as Level, FlushCompress, Status};
+use tracing::info;
+
+use super::{
+    Compression, EXT_BACKING_FORMAT, INCOMPAT_COMPRESSION_TYPE, L2_COMPRESSED, L2_ZERO, MAGIC,
+};
+use crate::disk::{DiskWriter, VirtualDisk};
+use crate::util::{human_size, stream_len};
+
+const L1_COPIED: u64 = 1 << 63;
+const COMPAT_LAZY_REFCOUNTS: u64 = 1 << 0;
+// 16-bit refcounts, the QEMU default.
+const REFCOUNT_ORDER: u32 = 4;
+
+/// Options controlling the layout of a written QCOW2 image.
+#[derive(Debug, Clone)]
+pub struct Qcow2Options {
+    /// Cluster size in bytes; a power of two between 512 B and 2 MiB.
+    pub cluster_size: u64,
+    /// Compress every data cluster that shrinks with the given algorithm.
+    pub compression: Option<Compression>,
+    /// Set the `lazy_refcounts` feature so QEMU defers refcount updates when it writes to
+    /// the image later.
+    pub lazy_refcounts: bool,
+}
+
+impl Default for Qcow2Options {
+    fn default() -> Self {
+        Qcow2Options {
+            cluster_size: 64 << 10,
+            compression: None,
+            lazy_refcounts: false,
+        }
+    }
+}
+
+impl DiskWriter for Qcow2Options {
+    fn write_disk(&self, disk: &mut dyn VirtualDisk, path: &Path) -> Result<()> {
+        write_qcow2(disk, path, self)
+    }
+}
+
+/// Write the guest disk exposed by `disk` to a new QCOW2 image at `path`.
+///
+/// Clusters that are entirely zero are left unallocated.
+pub fn write_qcow2<R: Read + Seek + ?Sized>(
+    disk: &mut R,
+    path: &Path,
+    options: &Qcow2Options,
+) -> Result<()> {
+    let cluster_size = options.cluster_size;
+    ensure!(
+        cluster_size.is_power_of_two() && (512..=2 << 20).contains(&cluster_size),
+        "qcow2 cluster size must be a power of two between 512 and 2097152 bytes"
+    );
+    let size = stream_len(disk)?;
+    info!(
+        "writing {} qcow2 image to {}",
+        human_size(size),
+        path.display()
+    );
+
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut writer = Writer::new(file, size, options, None);
+    let mut cluster = vec![0u8; cluster_size as usize];
+    for index in 0..size.div_ceil(cluster_size) {
+        let len = (size - index * cluster_size).min(cluster_size) as usize;
+        cluster[len..].fill(0);
+        disk.read_exact(&mut cluster[..len])
+            .with_context(|| format!("failed to read image at offset {}", index * cluster_size))?;
+        if cluster.iter().all(|&b| b == 0) {
+            continue;
+        }
+        writer.write_cluster(index, &cluster)?;
+    }
+    writer.finish()
+}
+
+/// The backing file recorded in an overlay's header.
+pub(super) struct BackingFile {
+    /// Path as stored in the image, relative to the overlay's directory unless absolute.
+    pub name: String,
+    /// `raw` or `qcow2`.
+    pub format: &'static str,
+}
+
+/// Lays out a QCOW2 image as clusters are appended in any order.
+pub(super) struct Writer {
+    out: BufWriter<File>,
+    cluster_bits: u32,
+    size: u64,
+    compression: Option<Compression>,
+    lazy_refcounts: bool,
+    backing: Option<BackingFile>,
+    l1_offset: u64,
+    l2_tables: Vec<Option<Vec<u64>>>,
+    // Reference count of every host cluster, indexed by host cluster number.
+    refcounts: Vec<u64>,
+    // Current end of the image file.
+    end: u64,
+}
+
+impl Writer {
+    pub(super) fn new(
+        file: File,
+        size: u64,
+        options: &Qcow2Options,
+        backing: Option<BackingFile>,
+    ) -> Self {
+        let cluster_size = options.cluster_size;
+        let l2_entries = cluster_size / 8;
+        let l1_size = size.div_ceil(cluster_size).div_ceil(l2_entries);
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+
+        // Cluster 0 holds the header, the L1 table follows directly.
+        let mut writer = Writer {
+            out: BufWriter::with_capacity(4 << 20, file),
+            cluster_bits: cluster_size.trailing_zeros(),
+            size,
+            compression: options.compression,
+            lazy_refcounts: options.lazy_refcounts,
+            backing,
+            l1_offset: cluster_size,
+            l2_tables: vec![None; l1_size as usize],
+            refcounts: Vec::new(),
+            end: 0,
+        };
+        writer.reference(0, (1 + l1_clusters) * cluster_size);
+        writer.end = (1 + l1_clusters) * cluster_size;
+        writer
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Count one more reference to every host cluster overlapping `[offset, offset + len)`.
+    fn reference(&mut self, offset: u64, len: u64) {
+        let first = offset >> self.cluster_bits;
+        let last = (offset + len - 1) >> self.cluster_bits;
+        if self.refcounts.len() <= last as usize {
+            self.refcounts.resize(last as usize + 1, 0);
+        }
+        for cluster in first..=last {
+            self.refcounts[cluster as usize] += 1;
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<u64> {
+        let offset = self.end;
+        self.out.seek(SeekFrom::Start(offset))?;
+        self.out.write_all(data)?;
+        self.end += data.len() as u64;
+        Ok(offset)
+    }
+
+    fn append_cluster(&mut self, data: &[u8]) -> Result<u64> {
+        self.end = self.end.next_multiple_of(self.cluster_size());
+        let offset = self.append(data)?;
+        self.reference(offset, data.len() as u64);
+        Ok(offset)
+    }
+
+    fn set_l2_entry(&mut self, index: u64, entry: u64) {
+        let l2_entries = self.cluster_size() / 8;
+        let table = self.l2_tables[(index / l2_entries) as usize]
+            .get_or_insert_with(|| vec![0; l2_entries as usize]);
+        table[(index % l2_entries) as usize] = entry;
+    }
+
+    /// Record guest cluster `index` as reading back as zeroes, hiding any backing data.
+    pub(super) fn write_zero_cluster(&mut self, index: u64) {
+        self.set_l2_entry(index, L2_ZERO);
+    }
+
+    /// Store the contents of guest cluster `index`.
+    pub(super) fn write_cluster(&mut self, index: u64, data: &[u8]) -> Result<()> {
+        if let Some(compression) = self.compression {
+            if let Some(compressed) = compress(compression, data)? {
+                // Compressed clusters are packed back to back at arbitrary byte offsets.
+                let offset = self.append(&compressed)?;
+                self.reference(offset, compressed.len() as u64);
+                let x = 62 - (self.cluster_bits - 8);
+                let sectors = ((offset + compressed.len() as u64 - 1) >> 9) - (offset >> 9);
+                self.set_l2_entry(index, L2_COMPRESSED | (sectors << x) | offset);
+                return Ok(());
+            }
+        }
+        let offset = self.append_cluster(data)?;
+        self.set_l2_entry(index, offset | L1_COPIED);
+        Ok(())
+    }
+
+    pub(super) fn finish(mut self) -> Result<()> {
+        let cluster_size = self.cluster_size();
+
+        let mut l1 = vec![0u8; self.l2_tables.len() * 8];
+        for (i, table) in std::mem::take(&mut self.l2_tables).into_iter().enumerate() {
+            let Some(table) = table else { continue };
+            let bytes: Vec<u8> = table.iter().flat_map(|e| e.to_be_bytes()).collect();
+            let offset = self.append_cluster(&bytes)?;
+            l1[i * 8..i * 8 + 8].copy_from_slice(&(offset | L1_COPIED).to_be_bytes());
+        }
+        self.out.seek(SeekFrom::Start(self.l1_offset))?;
+        self.out.write_all(&l1)?;
+
+        let (refcount_table_offset, refcount_table_clusters) = self.write_refcounts()?;
+
+        let compression_type = match self.compression {
+            Some(Compression::Zstd) => Some(1u8),
+            _ => None,
+        };
+        let mut header = Vec::with_capacity(cluster_size as usize);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&3u32.to_be_bytes());
+        header.extend_from_slice(&0u64.to_be_bytes()); // backing_file_offset, patched below
+        header.extend_from_slice(&0u32.to_be_bytes()); // backing_file_size, patched below
+        header.extend_from_slice(&self.cluster_bits.to_be_bytes());
+        header.extend_from_slice(&self.size.to_be_bytes());
+        header.extend_from_slice(&0u32.to_be_bytes()); // crypt_method
+        header.extend_from_slice(&(l1.len() as u32 / 8).to_be_bytes());
+        header.extend_from_slice(&self.l1_offset.to_be_bytes());
+        header.extend_from_slice(&refcount_table_offset.to_be_bytes());
+        header.extend_from_slice(&refcount_table_clusters.to_be_bytes());
+        header.extend_from_slice(&0u32.to_be_bytes()); // nb_snapshots
+        header.extend_from_slice(&0u64.to_be_bytes()); // snapshots_offset
+        let incompatible = if compression_type.is_some() {
+            INCOMPAT_COMPRESSION_TYPE
+        } else {
+            0
+        };
+        let compatible = if self.lazy_refcounts {
+            COMPAT_LAZY_REFCOUNTS
+        } else {
+            0
+        };
+        header.extend_from_slice(&incompatible.to_be_bytes());
+        header.extend_from_slice(&compatible.to_be_bytes());
+        header.extend_from_slice(&0u64.to_be_bytes()); // autoclear_features
+        header.extend_from_slice(&REFCOUNT_ORDER.to_be_bytes());
+        let header_length: u32 = if compression_type.is_some() { 112 } else { 104 };
+        header.extend_from_slice(&header_length.to_be_bytes());
+        if let Some(kind) = compression_type {
+            header.push(kind);
+            header.resize(112, 0);
+        }
+        if let Some(backing) = &self.backing {
+            let format = backing.format.as_bytes();
+            header.extend_from_slice(&EXT_BACKING_FORMAT.to_be_bytes());
+            header.extend_from_slice(&(format.len() as u32).to_be_bytes());
+            header.extend_from_slice(format);
+            header.resize(header.len().next_multiple_of(8), 0);
+            // End-of-extensions marker, followed by the backing file name.
+            header.extend_from_slice(&[0; 8]);
+            let name = backing.name.as_bytes();
+            let name_offset = header.len() as u64;
+            ensure!(
+                name.len() <= 1023 && name_offset + name.len() as u64 <= cluster_size,
+                "backing file name {} is too long for the qcow2 header",
+                backing.name
+            );
+            header.extend_from_slice(name);
+            header[8..16].copy_from_slice(&name_offset.to_be_bytes());
+            header[16..20].copy_from_slice(&(name.len() as u32).to_be_bytes());
+        }
+        // The rest of cluster 0 is zero, which doubles as the end-of-extensions marker.
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&header)?;
+
+        let file = self.out.into_inner().map_err(|e| e.into_error())?;
+        file.set_len(self.end.next_multiple_of(cluster_size))?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Append refcount blocks and the refcount table covering every cluster, including
+    /// themselves. Returns the table offset and length in clusters.
+    fn write_refcounts(&mut self) -> Result<(u64, u32)> {
+        let cluster_size = self.cluster_size();
+        let entries_per_block = cluster_size * 8 / (1 << REFCOUNT_ORDER);
+        self.end = self.end.next_multiple_of(cluster_size);
+        let first_new = self.end / cluster_size;
+
+        // The refcount structures must also count themselves, so grow them until they fit.
+        let (mut blocks, mut table_clusters) = (0u64, 0u64);
+        loop {
+            let total = first_new + blocks + table_clusters;
+            let needed_blocks = total.div_ceil(entries_per_block);
+            let needed_table = (needed_blocks * 8).div_ceil(cluster_size);
+            if needed_blocks == blocks && needed_table == table_clusters {
+                break;
+            }
+            (blocks, table_clusters) = (needed_blocks, needed_table);
+        }
+
+        let blocks_offset = self.end;
+        let table_offset = blocks_offset + blocks * cluster_size;
+        self.reference(blocks_offset, (blocks + table_clusters) * cluster_size);
+
+        let mut table = vec![0u8; (table_clusters * cluster_size) as usize];
+        let mut block = vec![0u8; cluster_size as usize];
+        for b in 0..blocks {
+            block.fill(0);
+            let start = (b * entries_per_block) as usize;
+            let end = (start + entries_per_block as usize).min(self.refcounts.len());
+            for (i, &count) in self.refcounts[start.min(end)..end].iter().enumerate() {
+                ensure!(count <= u64::from(u16::MAX), "qcow2 refcount overflow");
+                block[i * 2..i * 2 + 2].copy_from_slice(&(count as u16).to_be_bytes());
+            }
+            let offset = self.append(&block)?;
+            table[b as usize * 8..b as usize * 8 + 8].copy_from_slice(&offset.to_be_bytes());
+        }
+        self.append(&table)?;
+        Ok((table_offset, table_clusters as u32))
+    }
+}
+
+/// Compress one cluster, returning `None` when compression does not save space.
+fn compress(compression: Compression, data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let compressed = match compression {
+        Compression::Zlib => {
+            // QEMU inflates with a 4 KiB window, so the deflate stream must not use more.
+            let mut compressor = Compress::new_with_window_bits(Level::default(), false, 12);
+            let mut out = Vec::with_capacity(data.len());
+            let status = compressor.compress_vec(data, &mut out, FlushCompress::Finish)?;
+            if status != Status::StreamEnd {
+                return Ok(None);
+            }
+            out
+        }
+        Compression::Zstd => zstd::bulk::compress(data, 0)?,
+    };
+    Ok((compressed.len() < data.len()).then_some(compressed))
+}
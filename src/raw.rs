@@ -0,0 +1,87 @@
+//! Sparse raw image files.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tracing::info;
+
+use crate::disk::{DiskWriter, ExtentKind, VirtualDisk};
+use crate::util::human_size;
+
+const COPY_BUFFER_SIZE: u64 = 4 << 20;
+// Granularity at which zero runs of data extents are skipped, matching filesystem blocks.
+const ZERO_BLOCK: usize = 4096;
+
+/// A new raw image file as an output, with holes wherever the disk reads as zeroes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SparseFile;
+
+impl DiskWriter for SparseFile {
+    fn write_disk(&self, disk: &mut dyn VirtualDisk, path: &Path) -> Result<()> {
+        write_sparse_raw(disk, path)
+    }
+}
+
+/// Write the guest disk exposed by `disk` to a new sparse raw image at `path`.
+///
+/// Zero extents of the source are never read, and all-zero blocks of its data are not
+/// written, so only the data of the disk takes space on the host.
+pub fn write_sparse_raw<D: VirtualDisk + ?Sized>(disk: &mut D, path: &Path) -> Result<()> {
+    let size = disk.size();
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    info!(
+        "writing {} raw image to {}",
+        human_size(size),
+        path.display()
+    );
+
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE as usize];
+    let mut written = 0u64;
+    for extent in disk.extents()? {
+        if extent.kind == ExtentKind::Zero {
+            continue;
+        }
+        let mut offset = extent.offset;
+        let end = extent.offset + extent.len;
+        while offset < end {
+            let n = (end - offset).min(COPY_BUFFER_SIZE) as usize;
+            disk.read_at(offset, &mut buf[..n])
+                .with_context(|| format!("failed to read image at offset {offset}"))?;
+            written += write_nonzero(&file, offset, &buf[..n])?;
+            offset += n as u64;
+        }
+    }
+    // The holes up to the end of the disk are left to ftruncate.
+    file.set_len(size)?;
+    file.sync_all()?;
+    info!("{} of the disk holds data", human_size(written));
+    Ok(())
+}
+
+/// Write the blocks of `buf` that are not all zero to `offset` in `file`, returning the
+/// number of bytes written.
+fn write_nonzero(file: &File, offset: u64, buf: &[u8]) -> Result<u64> {
+    let mut written = 0;
+    let mut run: Option<usize> = None;
+    for (i, block) in buf.chunks(ZERO_BLOCK).enumerate() {
+        let start = i * ZERO_BLOCK;
+        let zero = block.iter().all(|&b| b == 0);
+        match (run, zero) {
+            (None, false) => run = Some(start),
+            (Some(from), true) => {
+                file.write_all_at(&buf[from..start], offset + from as u64)?;
+                written += (start - from) as u64;
+                run = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(from) = run {
+        file.write_all_at(&buf[from..], offset + from as u64)?;
+        written += (buf.len() - from) as u64;
+    }
+    Ok(written)
+}
@@ -0,0 +1,309 @@
+//! Reader for VirtualBox Disk Images (VDI).
+//!
+//! Supports version 1.1 images of the normal (dynamic), fixed and differencing types.
+//! VirtualBox keeps the parent of a differencing image in its media registry rather than in
+//! the image, so parents are found by UUID among the VDI files next to the child.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use tracing::warn;
+
+use crate::disk::{push_extent, Extent, ExtentKind, VirtualDisk};
+use crate::util::{format_guid, le_u32, le_u64, read_exact_at, trimmed_string};
+
+mod writer;
+
+pub use writer::{write_vdi, VdiOptions, VdiVariant};
+
+pub(crate) const SIGNATURE: u32 = 0xBEDA_107F;
+const VERSION_1_1: u32 = 0x0001_0001;
+const PRE_HEADER_SIZE: usize = 0x48;
+const HEADER_SIZE: u32 = 0x190;
+
+/// `image_type` of a dynamically allocated image.
+pub const TYPE_NORMAL: u32 = 1;
+/// `image_type` of a preallocated image.
+pub const TYPE_FIXED: u32 = 2;
+/// `image_type` of a differencing image.
+pub const TYPE_DIFF: u32 = 4;
+
+// Block map entries of blocks without data.
+const BLOCK_FREE: u32 = 0xFFFF_FFFF;
+const BLOCK_ZERO: u32 = 0xFFFF_FFFE;
+const MAX_CHAIN_DEPTH: usize = 64;
+
+/// The version 1.1 header following the pre-header.
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub image_type: u32,
+    pub flags: u32,
+    pub comment: Option<String>,
+    pub blocks_offset: u32,
+    pub data_offset: u32,
+    pub disk_size: u64,
+    pub block_size: u32,
+    /// Bytes of per-block metadata in front of every block's data.
+    pub block_extra: u32,
+    pub blocks: u32,
+    pub blocks_allocated: u32,
+    pub uuid_create: [u8; 16],
+    pub uuid_modify: [u8; 16],
+    /// Creation UUID of the parent of a differencing image.
+    pub uuid_linkage: [u8; 16],
+    /// Modification UUID the parent had when the differencing image was created.
+    pub uuid_parent_modify: [u8; 16],
+}
+
+impl Header {
+    fn read<R: Read + Seek + ?Sized>(r: &mut R) -> Result<Self> {
+        let mut b = [0u8; PRE_HEADER_SIZE + HEADER_SIZE as usize];
+        read_exact_at(r, 0, &mut b).context("file is too small to be a VDI")?;
+        ensure!(le_u32(&b, 0x40) == SIGNATURE, "not a VDI (bad signature)");
+        let version = le_u32(&b, 0x44);
+        ensure!(
+            version == VERSION_1_1,
+            "unsupported VDI version {}.{}",
+            version >> 16,
+            version & 0xFFFF
+        );
+        ensure!(le_u32(&b, 0x48) >= HEADER_SIZE, "VDI header is too small");
+        let header = Header {
+            image_type: le_u32(&b, 0x4C),
+            flags: le_u32(&b, 0x50),
+            comment: trimmed_string(&b[0x54..0x154]),
+            blocks_offset: le_u32(&b, 0x154),
+            data_offset: le_u32(&b, 0x158),
+            disk_size: le_u64(&b, 0x170),
+            block_size: le_u32(&b, 0x178),
+            block_extra: le_u32(&b, 0x17C),
+            blocks: le_u32(&b, 0x180),
+            blocks_allocated: le_u32(&b, 0x184),
+            uuid_create: b[0x188..0x198].try_into().unwrap(),
+            uuid_modify: b[0x198..0x1A8].try_into().unwrap(),
+            uuid_linkage: b[0x1A8..0x1B8].try_into().unwrap(),
+            uuid_parent_modify: b[0x1B8..0x1C8].try_into().unwrap(),
+        };
+        ensure!(
+            header.block_size.is_power_of_two() && header.block_size >= 512,
+            "invalid VDI block size {}",
+            header.block_size
+        );
+        ensure!(
+            u64::from(header.blocks) * u64::from(header.block_size) >= header.disk_size,
+            "VDI block map does not cover the disk"
+        );
+        Ok(header)
+    }
+}
+
+/// A VDI exposed as a seekable stream of its guest-visible contents.
+pub struct Vdi {
+    file: File,
+    header: Header,
+    blocks: Vec<u32>,
+    parent: Option<Box<Vdi>>,
+    parent_path: Option<PathBuf>,
+    pos: u64,
+}
+
+impl Vdi {
+    /// Open a VDI, resolving the parents of differencing images.
+    pub fn open_path(path: &Path) -> Result<Self> {
+        Self::open_chain(path, 0)
+    }
+
+    fn open_chain(path: &Path, depth: usize) -> Result<Self> {
+        ensure!(
+            depth < MAX_CHAIN_DEPTH,
+            "VDI parent chain is deeper than {MAX_CHAIN_DEPTH}"
+        );
+        let mut file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let header = Header::read(&mut file)?;
+        ensure!(
+            matches!(header.image_type, TYPE_NORMAL | TYPE_FIXED | TYPE_DIFF),
+            "unsupported VDI image type {}",
+            header.image_type
+        );
+        let mut bytes = vec![0u8; header.blocks as usize * 4];
+        read_exact_at(&mut file, u64::from(header.blocks_offset), &mut bytes)
+            .context("failed to read VDI block map")?;
+        let blocks = bytes.chunks_exact(4).map(|c| le_u32(c, 0)).collect();
+
+        let mut vdi = Vdi {
+            file,
+            header,
+            blocks,
+            parent: None,
+            parent_path: None,
+            pos: 0,
+        };
+        if vdi.header.image_type == TYPE_DIFF {
+            let parent_path = find_parent(path, &vdi.header.uuid_linkage)?;
+            let parent = Self::open_chain(&parent_path, depth + 1)
+                .with_context(|| format!("failed to open VDI parent {}", parent_path.display()))?;
+            if parent.header.uuid_modify != vdi.header.uuid_parent_modify {
+                warn!(
+                    "VDI parent {} was modified after {} was created from it",
+                    parent_path.display(),
+                    path.display()
+                );
+            }
+            vdi.parent = Some(Box::new(parent));
+            vdi.parent_path = Some(parent_path);
+        }
+        Ok(vdi)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Path of the parent of a differencing image.
+    pub fn parent_path(&self) -> Option<&Path> {
+        self.parent_path.as_deref()
+    }
+
+    /// Creation UUID of the image, as VirtualBox displays it.
+    pub fn uuid(&self) -> String {
+        format_guid(&self.header.uuid_create).to_lowercase()
+    }
+
+    /// Size of the guest-visible disk in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.header.disk_size
+    }
+
+    fn read_mapped_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let block_size = u64::from(self.header.block_size);
+        let block = offset / block_size;
+        let in_block = offset % block_size;
+        let n = buf.len().min((block_size - in_block) as usize);
+
+        match self
+            .blocks
+            .get(block as usize)
+            .copied()
+            .unwrap_or(BLOCK_FREE)
+        {
+            // Free blocks of a differencing image are inherited from the parent.
+            BLOCK_FREE if self.parent.is_some() => {
+                self.parent
+                    .as_mut()
+                    .unwrap()
+                    .read_mapped_at(offset, &mut buf[..n])?;
+            }
+            BLOCK_FREE | BLOCK_ZERO => buf[..n].fill(0),
+            index => {
+                let stride = block_size + u64::from(self.header.block_extra);
+                let data = u64::from(self.header.data_offset)
+                    + u64::from(index) * stride
+                    + u64::from(self.header.block_extra);
+                read_exact_at(&mut self.file, data + in_block, &mut buf[..n])?;
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Find the image whose creation UUID is `uuid` among the VDI files next to `child` and in
+/// the directory above, where VirtualBox keeps the base disk of a `Snapshots` folder.
+fn find_parent(child: &Path, uuid: &[u8; 16]) -> Result<PathBuf> {
+    let dir = match child.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    for dir in [dir.clone(), dir.join("..")] {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("vdi"))
+            {
+                continue;
+            }
+            let Ok(mut file) = File::open(&path) else {
+                continue;
+            };
+            if Header::read(&mut file).is_ok_and(|h| &h.uuid_create == uuid) {
+                return Ok(path);
+            }
+        }
+    }
+    bail!(
+        "parent {{{}}} of differencing VDI {} not found",
+        format_guid(uuid).to_lowercase(),
+        child.display()
+    )
+}
+
+impl VirtualDisk for Vdi {
+    fn size(&self) -> u64 {
+        self.virtual_size()
+    }
+
+    fn block_size(&self) -> u64 {
+        u64::from(self.header.block_size)
+    }
+
+    /// Blocks left to the parent of a differencing image count as data.
+    fn extents(&mut self) -> Result<Vec<Extent>> {
+        let size = self.virtual_size();
+        let block_size = u64::from(self.header.block_size);
+        let mut extents = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            let len = block_size.min(size - offset);
+            let entry = self.blocks.get((offset / block_size) as usize);
+            let kind = match entry.copied().unwrap_or(BLOCK_FREE) {
+                BLOCK_FREE if self.parent.is_some() => ExtentKind::Data,
+                BLOCK_FREE | BLOCK_ZERO => ExtentKind::Zero,
+                _ => ExtentKind::Data,
+            };
+            push_extent(&mut extents, offset, len, kind);
+            offset += len;
+        }
+        Ok(extents)
+    }
+}
+
+impl Read for Vdi {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.virtual_size();
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = (buf.len() as u64).min(size - self.pos) as usize;
+        let n = self
+            .read_mapped_at(self.pos, &mut buf[..len])
+            .map_err(io::Error::other)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Vdi {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.virtual_size().checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Writer producing VDI images.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use tracing::info;
+
+use super::{
+    BLOCK_FREE, HEADER_SIZE, PRE_HEADER_SIZE, SIGNATURE, TYPE_FIXED, TYPE_NORMAL, VERSION_1_1,
+};
+use crate::disk::{DiskWriter, VirtualDisk};
+use crate::util::{human_size, stream_len};
+
+const FILE_INFO: &[u8] = b"<<< Oracle VM VirtualBox Disk Image >>>\n";
+// 1 MiB blocks, with the block map and data aligned to 1 MiB as VirtualBox does.
+const BLOCK_SIZE: u64 = 1 << 20;
+const ALIGNMENT: u64 = 1 << 20;
+
+/// How the blocks of a written VDI are allocated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VdiVariant {
+    /// Only blocks holding data are stored.
+    #[default]
+    Dynamic,
+    /// Every block is preallocated, in guest order.
+    Static,
+}
+
+/// Options controlling a written VDI.
+#[derive(Debug, Clone, Default)]
+pub struct VdiOptions {
+    pub variant: VdiVariant,
+}
+
+impl DiskWriter for VdiOptions {
+    fn write_disk(&self, disk: &mut dyn VirtualDisk, path: &Path) -> Result<()> {
+        write_vdi(disk, path, self)
+    }
+}
+
+/// Write the guest disk exposed by `disk` to a new VDI at `path`.
+///
+/// The image gets fresh creation and modification UUIDs, so VirtualBox can register it next
+/// to the image it was converted from.
+pub fn write_vdi<R: Read + Seek + ?Sized>(
+    disk: &mut R,
+    path: &Path,
+    options: &VdiOptions,
+) -> Result<()> {
+    let size = stream_len(disk)?;
+    let disk_size = size.next_multiple_of(512);
+    let blocks = disk_size.div_ceil(BLOCK_SIZE);
+    ensure!(
+        blocks < u64::from(BLOCK_FREE) - 1,
+        "disk is too large for a VDI"
+    );
+    info!(
+        "writing {} {:?} vdi to {}",
+        human_size(disk_size),
+        options.variant,
+        path.display()
+    );
+
+    let blocks_offset =
+        (PRE_HEADER_SIZE as u64 + u64::from(HEADER_SIZE)).next_multiple_of(ALIGNMENT);
+    let data_offset = (blocks_offset + blocks * 4).next_multiple_of(ALIGNMENT);
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut out = BufWriter::with_capacity(4 << 20, file);
+
+    let mut map = vec![BLOCK_FREE; blocks as usize];
+    let mut allocated = 0u32;
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    for (index, entry) in map.iter_mut().enumerate() {
+        let offset = index as u64 * BLOCK_SIZE;
+        let len = (size.saturating_sub(offset)).min(BLOCK_SIZE) as usize;
+        block[len..].fill(0);
+        disk.read_exact(&mut block[..len])
+            .with_context(|| format!("failed to read image at offset {offset}"))?;
+        let zero = block.iter().all(|&b| b == 0);
+        if zero && options.variant == VdiVariant::Dynamic {
+            continue;
+        }
+        *entry = allocated;
+        let position = data_offset + u64::from(allocated) * BLOCK_SIZE;
+        // Zero blocks of a static image are left as holes.
+        if !zero {
+            out.seek(SeekFrom::Start(position))?;
+            out.write_all(&block)?;
+        }
+        allocated += 1;
+    }
+
+    let image_type = match options.variant {
+        VdiVariant::Dynamic => TYPE_NORMAL,
+        VdiVariant::Static => TYPE_FIXED,
+    };
+    let mut header = vec![0u8; PRE_HEADER_SIZE + HEADER_SIZE as usize];
+    header[..FILE_INFO.len()].copy_from_slice(FILE_INFO);
+    let mut put = |offset: usize, bytes: &[u8]| {
+        header[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    put(0x40, &SIGNATURE.to_le_bytes());
+    put(0x44, &VERSION_1_1.to_le_bytes());
+    put(0x48, &HEADER_SIZE.to_le_bytes());
+    put(0x4C, &image_type.to_le_bytes());
+    put(0x154, &(blocks_offset as u32).to_le_bytes());
+    put(0x158, &(data_offset as u32).to_le_bytes());
+    // Legacy geometry is left unset apart from its sector size.
+    put(0x168, &512u32.to_le_bytes());
+    put(0x170, &disk_size.to_le_bytes());
+    put(0x178, &(BLOCK_SIZE as u32).to_le_bytes());
+    put(0x180, &(blocks as u32).to_le_bytes());
+    put(0x184, &allocated.to_le_bytes());
+    put(0x188, &uuid::Uuid::new_v4().to_bytes_le());
+    put(0x198, &uuid::Uuid::new_v4().to_bytes_le());
+    put(0x1D4, &512u32.to_le_bytes());
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&header)?;
+    out.seek(SeekFrom::Start(blocks_offset))?;
+    let bytes: Vec<u8> = map.iter().flat_map(|e| e.to_le_bytes()).collect();
+    out.write_all(&bytes)?;
+
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.set_len(data_offset + u64::from(allocated) * BLOCK_SIZE)?;
+    file.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,467 @@
+//! Reader for Microsoft Virtual Hard Disks (VHD).
+//!
+//! Supports fixed, dynamic and differencing disks as described in the "Virtual Hard Disk
+//! Image Format Specification" version 1.0.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use tracing::warn;
+
+use crate::disk::{push_extent, Extent, ExtentKind, VirtualDisk};
+use crate::util::{be_u32, be_u64, format_uuid, read_exact_at, stream_len};
+
+mod writer;
+
+pub use writer::{write_vhd, FixedVhd};
+
+pub(crate) const FOOTER_COOKIE: &[u8; 8] = b"conectix";
+const DYNAMIC_COOKIE: &[u8; 8] = b"cxsparse";
+
+/// `disk_type` of a fixed disk.
+pub const DISK_FIXED: u32 = 2;
+/// `disk_type` of a dynamic disk.
+pub const DISK_DYNAMIC: u32 = 3;
+/// `disk_type` of a differencing disk.
+pub const DISK_DIFFERENCING: u32 = 4;
+
+const BAT_UNUSED: u32 = 0xFFFF_FFFF;
+const MAX_CHAIN_DEPTH: usize = 64;
+
+/// The 512-byte footer at the end (and, for dynamic disks, also the start) of a VHD.
+#[derive(Debug, Clone)]
+pub struct Footer {
+    pub features: u32,
+    pub version: u32,
+    /// Offset of the dynamic disk header, `u64::MAX` for fixed disks.
+    pub data_offset: u64,
+    /// Seconds since 2000-01-01 00:00:00 UTC.
+    pub timestamp: u32,
+    pub creator_app: [u8; 4],
+    pub creator_version: u32,
+    pub creator_host_os: [u8; 4],
+    pub original_size: u64,
+    pub current_size: u64,
+    /// Cylinders, heads and sectors per track.
+    pub geometry: (u16, u8, u8),
+    pub disk_type: u32,
+    pub unique_id: [u8; 16],
+    pub saved_state: bool,
+}
+
+impl Footer {
+    pub(crate) fn parse(b: &[u8]) -> Result<Self> {
+        ensure!(&b[0..8] == FOOTER_COOKIE, "not a VHD (bad footer cookie)");
+        let stored = be_u32(b, 64);
+        let computed = checksum(b, 64);
+        ensure!(
+            stored == computed,
+            "VHD footer checksum mismatch (stored {stored:#x}, computed {computed:#x})"
+        );
+        Ok(Footer {
+            features: be_u32(b, 8),
+            version: be_u32(b, 12),
+            data_offset: be_u64(b, 16),
+            timestamp: be_u32(b, 24),
+            creator_app: b[28..32].try_into().unwrap(),
+            creator_version: be_u32(b, 32),
+            creator_host_os: b[36..40].try_into().unwrap(),
+            original_size: be_u64(b, 40),
+            current_size: be_u64(b, 48),
+            geometry: (u16::from_be_bytes([b[56], b[57]]), b[58], b[59]),
+            disk_type: be_u32(b, 60),
+            unique_id: b[68..84].try_into().unwrap(),
+            saved_state: b[84] != 0,
+        })
+    }
+}
+
+/// One's complement of the byte sum of `b`, skipping the checksum field at `field`.
+pub(crate) fn checksum(b: &[u8], field: usize) -> u32 {
+    let sum = b
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(field..field + 4).contains(i))
+        .fold(0u32, |sum, (_, &byte)| sum.wrapping_add(u32::from(byte)));
+    !sum
+}
+
+/// Where a differencing disk's parent may be found.
+#[derive(Debug, Clone)]
+pub struct ParentLocator {
+    /// Platform code, e.g. `W2ku` or `W2ru`.
+    pub platform: String,
+    pub path: String,
+}
+
+/// The dynamic disk header of dynamic and differencing disks.
+#[derive(Debug, Clone)]
+pub struct DynamicHeader {
+    pub table_offset: u64,
+    pub max_table_entries: u32,
+    pub block_size: u32,
+    pub parent_unique_id: [u8; 16],
+    pub parent_name: Option<String>,
+    pub parent_locators: Vec<ParentLocator>,
+}
+
+impl DynamicHeader {
+    fn read<R: Read + Seek + ?Sized>(r: &mut R, offset: u64) -> Result<Self> {
+        let mut b = [0u8; 1024];
+        read_exact_at(r, offset, &mut b).context("failed to read VHD dynamic disk header")?;
+        ensure!(
+            &b[0..8] == DYNAMIC_COOKIE,
+            "bad VHD dynamic disk header cookie"
+        );
+        let stored = be_u32(&b, 36);
+        ensure!(
+            stored == checksum(&b, 36),
+            "VHD dynamic disk header checksum mismatch"
+        );
+        let block_size = be_u32(&b, 32);
+        ensure!(
+            block_size.is_power_of_two() && block_size >= 512,
+            "invalid VHD block size {block_size}"
+        );
+
+        let parent_name = utf16_string(&b[64..576], false);
+        let mut parent_locators = Vec::new();
+        for entry in b[576..768].chunks_exact(24) {
+            let code = &entry[0..4];
+            let length = be_u32(entry, 8);
+            let data_offset = be_u64(entry, 16);
+            if code == [0; 4] || length == 0 {
+                continue;
+            }
+            let mut data = vec![0u8; length.min(1 << 16) as usize];
+            read_exact_at(r, data_offset, &mut data)
+                .context("failed to read VHD parent locator")?;
+            let path = match code {
+                b"W2ku" | b"W2ru" => utf16_string(&data, true),
+                b"MacX" => Some(
+                    String::from_utf8_lossy(&data)
+                        .trim_end_matches('\0')
+                        .to_string(),
+                ),
+                _ => None,
+            };
+            if let Some(path) = path {
+                parent_locators.push(ParentLocator {
+                    platform: String::from_utf8_lossy(code).into_owned(),
+                    path,
+                });
+            }
+        }
+
+        Ok(DynamicHeader {
+            table_offset: be_u64(&b, 16),
+            max_table_entries: be_u32(&b, 28),
+            block_size,
+            parent_unique_id: b[40..56].try_into().unwrap(),
+            parent_name,
+            parent_locators,
+        })
+    }
+}
+
+fn utf16_string(b: &[u8], little_endian: bool) -> Option<String> {
+    let units: Vec<u16> = b
+        .chunks_exact(2)
+        .map(|c| {
+            if little_endian {
+                u16::from_le_bytes([c[0], c[1]])
+            } else {
+                u16::from_be_bytes([c[0], c[1]])
+            }
+        })
+        .take_while(|&u| u != 0)
+        .collect();
+    let s = String::from_utf16_lossy(&units);
+    (!s.is_empty()).then_some(s)
+}
+
+struct Dynamic {
+    header: DynamicHeader,
+    bat: Vec<u32>,
+    // Bytes of sector bitmap in front of every block.
+    bitmap_size: u64,
+    // Index and sector bitmap of the most recently used block of a differencing disk.
+    bitmap_cache: Option<(u64, Vec<u8>)>,
+    parent: Option<Box<Vhd>>,
+}
+
+/// A VHD exposed as a seekable stream of its guest-visible contents.
+pub struct Vhd {
+    file: File,
+    footer: Footer,
+    dynamic: Option<Dynamic>,
+    parent_path: Option<PathBuf>,
+    pos: u64,
+}
+
+impl Vhd {
+    /// Open a VHD, resolving the parents of differencing disks.
+    pub fn open_path(path: &Path) -> Result<Self> {
+        Self::open_chain(path, 0)
+    }
+
+    fn open_chain(path: &Path, depth: usize) -> Result<Self> {
+        ensure!(
+            depth < MAX_CHAIN_DEPTH,
+            "VHD parent chain is deeper than {MAX_CHAIN_DEPTH}"
+        );
+        let mut file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let len = stream_len(&mut file)?;
+        ensure!(len >= 512, "{} is too small to be a VHD", path.display());
+
+        let mut b = [0u8; 512];
+        read_exact_at(&mut file, len - 512, &mut b)?;
+        let footer = match Footer::parse(&b) {
+            Ok(footer) => footer,
+            Err(err) => {
+                // Dynamic disks keep a copy of the footer at the start of the file.
+                read_exact_at(&mut file, 0, &mut b)?;
+                let footer = Footer::parse(&b).with_context(|| format!("{err:#}"))?;
+                warn!("VHD footer is damaged, using the copy at the start of the file");
+                footer
+            }
+        };
+
+        let mut vhd = Vhd {
+            file,
+            footer,
+            dynamic: None,
+            parent_path: None,
+            pos: 0,
+        };
+        match vhd.footer.disk_type {
+            DISK_FIXED => ensure!(
+                vhd.footer.current_size <= len - 512,
+                "fixed VHD is shorter than its declared size"
+            ),
+            DISK_DYNAMIC | DISK_DIFFERENCING => {
+                let header = DynamicHeader::read(&mut vhd.file, vhd.footer.data_offset)?;
+                let mut bytes = vec![0u8; header.max_table_entries as usize * 4];
+                read_exact_at(&mut vhd.file, header.table_offset, &mut bytes)
+                    .context("failed to read VHD block allocation table")?;
+                let bat = bytes.chunks_exact(4).map(|c| be_u32(c, 0)).collect();
+                let sectors_per_block = u64::from(header.block_size) / 512;
+                let bitmap_size = sectors_per_block.div_ceil(8).next_multiple_of(512);
+
+                let parent = if vhd.footer.disk_type == DISK_DIFFERENCING {
+                    let parent_path = find_parent(path, &header)?;
+                    let parent = Self::open_chain(&parent_path, depth + 1).with_context(|| {
+                        format!("failed to open VHD parent {}", parent_path.display())
+                    })?;
+                    if parent.footer.unique_id != header.parent_unique_id {
+                        warn!(
+                            "VHD parent {} has id {}, expected {}",
+                            parent_path.display(),
+                            format_uuid(&parent.footer.unique_id),
+                            format_uuid(&header.parent_unique_id)
+                        );
+                    }
+                    vhd.parent_path = Some(parent_path);
+                    Some(Box::new(parent))
+                } else {
+                    None
+                };
+                vhd.dynamic = Some(Dynamic {
+                    header,
+                    bat,
+                    bitmap_size,
+                    bitmap_cache: None,
+                    parent,
+                });
+            }
+            other => bail!("unsupported VHD disk type {other}"),
+        }
+        Ok(vhd)
+    }
+
+    pub fn footer(&self) -> &Footer {
+        &self.footer
+    }
+
+    pub fn dynamic_header(&self) -> Option<&DynamicHeader> {
+        self.dynamic.as_ref().map(|d| &d.header)
+    }
+
+    /// Path of the parent of a differencing disk.
+    pub fn parent_path(&self) -> Option<&Path> {
+        self.parent_path.as_deref()
+    }
+
+    /// Size of the guest-visible disk in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.footer.current_size
+    }
+
+    fn read_mapped_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let Some(dynamic) = &mut self.dynamic else {
+            read_exact_at(&mut self.file, offset, buf)?;
+            return Ok(buf.len());
+        };
+        let block_size = u64::from(dynamic.header.block_size);
+        let block = offset / block_size;
+        let in_block = offset % block_size;
+        let mut n = buf.len().min((block_size - in_block) as usize);
+
+        let entry = dynamic
+            .bat
+            .get(block as usize)
+            .copied()
+            .unwrap_or(BAT_UNUSED);
+        if entry == BAT_UNUSED {
+            match &mut dynamic.parent {
+                Some(parent) => parent.read_mapped_at(offset, &mut buf[..n])?,
+                None => {
+                    buf[..n].fill(0);
+                    n
+                }
+            };
+            return Ok(n);
+        }
+        let block_start = u64::from(entry) * 512;
+        let data = block_start + dynamic.bitmap_size;
+        let Some(parent) = &mut dynamic.parent else {
+            read_exact_at(&mut self.file, data + in_block, &mut buf[..n])?;
+            return Ok(n);
+        };
+
+        // In a differencing disk, sectors whose bitmap bit is clear come from the parent.
+        if !matches!(&dynamic.bitmap_cache, Some((b, _)) if *b == block) {
+            let mut bitmap = vec![0u8; dynamic.bitmap_size as usize];
+            read_exact_at(&mut self.file, block_start, &mut bitmap)?;
+            dynamic.bitmap_cache = Some((block, bitmap));
+        }
+        let bitmap = &dynamic.bitmap_cache.as_ref().unwrap().1;
+        let sector = in_block / 512;
+        let present = |s: u64| bitmap[(s / 8) as usize] & (0x80 >> (s % 8)) != 0;
+        let first = present(sector);
+        // Read the run of sectors that come from the same place.
+        let mut end = sector + 1;
+        while end * 512 < in_block + n as u64 && present(end) == first {
+            end += 1;
+        }
+        n = n.min((end * 512 - in_block) as usize);
+        if first {
+            read_exact_at(&mut self.file, data + in_block, &mut buf[..n])?;
+        } else {
+            parent.read_mapped_at(offset, &mut buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+/// Resolve the parent of a differencing disk from its parent locators.
+fn find_parent(child: &Path, header: &DynamicHeader) -> Result<PathBuf> {
+    let dir = child.parent().unwrap_or(Path::new(""));
+    let mut candidates = Vec::new();
+    // Relative locators first, so that a moved disk chain still resolves.
+    for platform in ["W2ru", "W2ku", "MacX"] {
+        for locator in header
+            .parent_locators
+            .iter()
+            .filter(|l| l.platform == platform)
+        {
+            let path = locator
+                .path
+                .trim_start_matches("file://")
+                .replace('\\', "/");
+            let path = path.strip_prefix("./").unwrap_or(&path);
+            candidates.push(dir.join(path));
+            // An absolute Windows path can still name a file next to the child.
+            if let Some(name) = Path::new(path).file_name() {
+                candidates.push(dir.join(name));
+            }
+        }
+    }
+    if let Some(name) = &header.parent_name {
+        candidates.push(dir.join(name));
+    }
+    candidates
+        .into_iter()
+        .find(|p| p.is_file())
+        .with_context(|| {
+            format!(
+                "parent {} of differencing VHD {} not found",
+                header.parent_name.as_deref().unwrap_or("(unnamed)"),
+                child.display()
+            )
+        })
+}
+
+impl VirtualDisk for Vhd {
+    fn size(&self) -> u64 {
+        self.virtual_size()
+    }
+
+    fn block_size(&self) -> u64 {
+        self.dynamic
+            .as_ref()
+            .map_or(512, |d| u64::from(d.header.block_size))
+    }
+
+    /// Blocks left to the parent of a differencing disk count as data.
+    fn extents(&mut self) -> Result<Vec<Extent>> {
+        let size = self.virtual_size();
+        let mut extents = Vec::new();
+        let Some(dynamic) = &self.dynamic else {
+            push_extent(&mut extents, 0, size, ExtentKind::Data);
+            return Ok(extents);
+        };
+        let block_size = u64::from(dynamic.header.block_size);
+        let mut offset = 0;
+        while offset < size {
+            let len = block_size.min(size - offset);
+            let entry = dynamic.bat.get((offset / block_size) as usize);
+            let kind = match entry {
+                Some(&e) if e != BAT_UNUSED => ExtentKind::Data,
+                _ if dynamic.parent.is_some() => ExtentKind::Data,
+                _ => ExtentKind::Zero,
+            };
+            push_extent(&mut extents, offset, len, kind);
+            offset += len;
+        }
+        Ok(extents)
+    }
+}
+
+impl Read for Vhd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.virtual_size();
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = (buf.len() as u64).min(size - self.pos) as usize;
+        let n = self
+            .read_mapped_at(self.pos, &mut buf[..len])
+            .map_err(io::Error::other)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Vhd {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.virtual_size().checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Writer producing fixed VHDs.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use tracing::info;
+
+use super::{checksum, Footer, DISK_FIXED, FOOTER_COOKIE};
+use crate::disk::{DiskWriter, VirtualDisk};
+use crate::util::{human_size, stream_len};
+
+// Azure only accepts fixed VHDs whose virtual size is a whole number of MiB.
+const ALIGNMENT: u64 = 1 << 20;
+// Seconds between the Unix epoch and the VHD epoch, 2000-01-01 00:00:00 UTC.
+const VHD_EPOCH: u64 = 946_684_800;
+
+/// The fixed VHD output format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixedVhd;
+
+impl DiskWriter for FixedVhd {
+    fn write_disk(&self, disk: &mut dyn VirtualDisk, path: &Path) -> Result<()> {
+        write_vhd(disk, path)
+    }
+}
+
+/// Write the guest disk exposed by `disk` to a new fixed VHD at `path`.
+///
+/// The virtual size is rounded up to a multiple of 1 MiB, as Azure requires. All-zero
+/// chunks are skipped, leaving holes in the output file.
+pub fn write_vhd<R: Read + Seek + ?Sized>(disk: &mut R, path: &Path) -> Result<()> {
+    let size = stream_len(disk)?;
+    let vhd_size = size.next_multiple_of(ALIGNMENT);
+    info!(
+        "writing {} fixed vhd to {}",
+        human_size(vhd_size),
+        path.display()
+    );
+
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut out = BufWriter::with_capacity(4 << 20, file);
+    let mut chunk = vec![0u8; ALIGNMENT as usize];
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(ALIGNMENT) as usize;
+        disk.read_exact(&mut chunk[..len])
+            .with_context(|| format!("failed to read image at offset {offset}"))?;
+        if chunk[..len].iter().any(|&b| b != 0) {
+            out.seek(SeekFrom::Start(offset))?;
+            out.write_all(&chunk[..len])?;
+        }
+        offset += len as u64;
+    }
+
+    let footer = Footer {
+        features: 2,
+        version: 0x0001_0000,
+        data_offset: u64::MAX,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs().saturating_sub(VHD_EPOCH) as u32),
+        creator_app: *b"vmi ",
+        creator_version: 0x0001_0000,
+        creator_host_os: *b"Wi2k",
+        original_size: vhd_size,
+        current_size: vhd_size,
+        geometry: geometry(vhd_size),
+        disk_type: DISK_FIXED,
+        unique_id: *uuid::Uuid::new_v4().as_bytes(),
+        saved_state: false,
+    };
+    out.seek(SeekFrom::Start(vhd_size))?;
+    out.write_all(&encode_footer(&footer))?;
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.set_len(vhd_size + 512)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn encode_footer(footer: &Footer) -> [u8; 512] {
+    let mut b = [0u8; 512];
+    b[0..8].copy_from_slice(FOOTER_COOKIE);
+    b[8..12].copy_from_slice(&footer.features.to_be_bytes());
+    b[12..16].copy_from_slice(&footer.version.to_be_bytes());
+    b[16..24].copy_from_slice(&footer.data_offset.to_be_bytes());
+    b[24..28].copy_from_slice(&footer.timestamp.to_be_bytes());
+    b[28..32].copy_from_slice(&footer.creator_app);
+    b[32..36].copy_from_slice(&footer.creator_version.to_be_bytes());
+    b[36..40].copy_from_slice(&footer.creator_host_os);
+    b[40..48].copy_from_slice(&footer.original_size.to_be_bytes());
+    b[48..56].copy_from_slice(&footer.current_size.to_be_bytes());
+    let (cylinders, heads, sectors) = footer.geometry;
+    b[56..58].copy_from_slice(&cylinders.to_be_bytes());
+    b[58] = heads;
+    b[59] = sectors;
+    b[60..64].copy_from_slice(&footer.disk_type.to_be_bytes());
+    b[68..84].copy_from_slice(&footer.unique_id);
+    b[84] = u8::from(footer.saved_state);
+    let sum = checksum(&b, 64);
+    b[64..68].copy_from_slice(&sum.to_be_bytes());
+    b
+}
+
+/// CHS geometry of a disk of `size` bytes, per the algorithm in the VHD specification.
+fn geometry(size: u64) -> (u16, u8, u8) {
+    let total = (size / 512).min(65535 * 16 * 255);
+    let (sectors, heads, cylinder_heads) = if total >= 65535 * 16 * 63 {
+        (255, 16, total / 255)
+    } else {
+        let mut sectors = 17;
+        let mut cylinder_heads = total / sectors;
+        let mut heads = cylinder_heads.div_ceil(1024).max(4);
+        if cylinder_heads >= heads * 1024 || heads > 16 {
+            sectors = 31;
+            heads = 16;
+            cylinder_heads = total / sectors;
+        }
+        if cylinder_heads >= heads * 1024 {
+            sectors = 63;
+            heads = 16;
+            cylinder_heads = total / sectors;
+        }
+        (sectors, heads, cylinder_heads)
+    };
+    ((cylinder_heads / heads) as u16, heads as u8, sectors as u8)
+}
@@ -0,0 +1,580 @@
+//! Reader for Hyper-V Virtual Hard Disk v2 images (VHDX).
+//!
+//! Supports fixed, dynamic and differencing disks as described in the "VHDX Format
+//! Specification" version 1.0. Updates left in the log by a host that crashed are replayed in
+//! memory, so such images read as Hyper-V would see them after recovery.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use tracing::{info, warn};
+
+use crate::disk::{push_extent, Extent, ExtentKind, VirtualDisk};
+use crate::util::{format_guid, le_u16, le_u32, le_u64, read_exact_at};
+
+mod log;
+
+use log::HostFile;
+
+pub(crate) const FILE_SIGNATURE: &[u8; 8] = b"vhdxfile";
+const HEADER_SIGNATURE: &[u8; 4] = b"head";
+const REGION_SIGNATURE: &[u8; 4] = b"regi";
+const METADATA_SIGNATURE: &[u8; 8] = b"metadata";
+
+const KIB: u64 = 1 << 10;
+const MIB: u64 = 1 << 20;
+const HEADER_OFFSETS: [u64; 2] = [64 * KIB, 128 * KIB];
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 * KIB, 256 * KIB];
+const REGION_TABLE_SIZE: usize = 64 * KIB as usize;
+const METADATA_TABLE_SIZE: usize = 64 * KIB as usize;
+const SECTOR_BITMAP_SIZE: u64 = MIB;
+const MAX_CHAIN_DEPTH: usize = 64;
+
+const BAT_REGION: &str = "2DC27766-F623-4200-9D64-115E9BFD4A08";
+const METADATA_REGION: &str = "8B7CA206-4790-4B9A-B8FE-575F050F886E";
+
+const FILE_PARAMETERS: &str = "CAA16737-FA36-4D43-B3B6-33F0AA44E76B";
+const VIRTUAL_DISK_SIZE: &str = "2FA54224-CD1B-4876-B211-5DBED83BF4B8";
+const VIRTUAL_DISK_ID: &str = "BECA12AB-B2E6-4523-93EF-C309E000C746";
+const LOGICAL_SECTOR_SIZE: &str = "8141BF1D-A96F-4709-BA47-F233A8FAAB5F";
+const PHYSICAL_SECTOR_SIZE: &str = "CDA348C7-445D-4471-9CC9-E9885251C556";
+const PARENT_LOCATOR: &str = "A8D35F2D-B30B-454D-ABF7-D3D84834AB0C";
+const VHDX_PARENT_LOCATOR_TYPE: &str = "B04AEFB7-D19E-4A81-B789-25B8E9445913";
+
+// Payload block states in the low three bits of a BAT entry.
+const PAYLOAD_NOT_PRESENT: u64 = 0;
+const PAYLOAD_FULLY_PRESENT: u64 = 6;
+const PAYLOAD_PARTIALLY_PRESENT: u64 = 7;
+const SB_BLOCK_PRESENT: u64 = 6;
+
+/// CRC-32C of `b` with the four-byte checksum field at `field` taken as zero.
+fn crc32c_checksum(b: &[u8], field: usize) -> u32 {
+    let crc = crc32c::crc32c(&b[..field]);
+    let crc = crc32c::crc32c_append(crc, &[0; 4]);
+    crc32c::crc32c_append(crc, &b[field + 4..])
+}
+
+/// One of the two VHDX headers; the valid one with the highest sequence number is current.
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub sequence_number: u64,
+    pub file_write_guid: [u8; 16],
+    pub data_write_guid: [u8; 16],
+    /// All zeroes when the log is empty.
+    pub log_guid: [u8; 16],
+    pub log_version: u16,
+    pub version: u16,
+    pub log_length: u32,
+    pub log_offset: u64,
+}
+
+impl Header {
+    fn parse(b: &[u8]) -> Option<Self> {
+        if &b[0..4] != HEADER_SIGNATURE || le_u32(b, 4) != crc32c_checksum(b, 4) {
+            return None;
+        }
+        Some(Header {
+            sequence_number: le_u64(b, 8),
+            file_write_guid: b[16..32].try_into().unwrap(),
+            data_write_guid: b[32..48].try_into().unwrap(),
+            log_guid: b[48..64].try_into().unwrap(),
+            log_version: le_u16(b, 64),
+            version: le_u16(b, 66),
+            log_length: le_u32(b, 68),
+            log_offset: le_u64(b, 72),
+        })
+    }
+}
+
+/// Key-value pairs of a differencing disk's parent locator.
+#[derive(Debug, Clone)]
+pub struct ParentLocator {
+    pub locator_type: String,
+    /// Keys such as `parent_linkage`, `relative_path` and `absolute_win32_path`.
+    pub entries: BTreeMap<String, String>,
+}
+
+/// The known items of the metadata region.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub block_size: u32,
+    pub leave_blocks_allocated: bool,
+    pub has_parent: bool,
+    pub virtual_size: u64,
+    pub virtual_disk_id: [u8; 16],
+    pub logical_sector_size: u32,
+    pub physical_sector_size: u32,
+    pub parent_locator: Option<ParentLocator>,
+}
+
+impl Metadata {
+    fn read(host: &mut HostFile, offset: u64, length: u32) -> Result<Self> {
+        ensure!(
+            length as usize >= METADATA_TABLE_SIZE,
+            "VHDX metadata region is too small"
+        );
+        let mut table = vec![0u8; METADATA_TABLE_SIZE];
+        host.read_at(offset, &mut table)
+            .context("failed to read VHDX metadata table")?;
+        ensure!(
+            &table[0..8] == METADATA_SIGNATURE,
+            "bad VHDX metadata table signature"
+        );
+        let count = le_u16(&table, 10) as usize;
+        ensure!(count <= 2047, "too many VHDX metadata entries ({count})");
+
+        let mut items = BTreeMap::new();
+        for entry in table[32..32 + 32 * count].chunks_exact(32) {
+            let id = format_guid(entry[0..16].try_into().unwrap());
+            let item_offset = le_u32(entry, 16);
+            let item_length = le_u32(entry, 20);
+            let required = le_u32(entry, 24) & 4 != 0;
+            ensure!(
+                u64::from(item_offset) + u64::from(item_length) <= u64::from(length),
+                "VHDX metadata item {id} lies outside the metadata region"
+            );
+            let mut data = vec![0u8; item_length as usize];
+            host.read_at(offset + u64::from(item_offset), &mut data)?;
+            if required
+                && ![
+                    FILE_PARAMETERS,
+                    VIRTUAL_DISK_SIZE,
+                    VIRTUAL_DISK_ID,
+                    LOGICAL_SECTOR_SIZE,
+                    PHYSICAL_SECTOR_SIZE,
+                    PARENT_LOCATOR,
+                ]
+                .contains(&id.as_str())
+            {
+                bail!("unsupported required VHDX metadata item {id}");
+            }
+            items.insert(id, data);
+        }
+
+        let item = |id: &str, min_len: usize| -> Result<&Vec<u8>> {
+            let data = items
+                .get(id)
+                .with_context(|| format!("VHDX metadata item {id} is missing"))?;
+            ensure!(
+                data.len() >= min_len,
+                "VHDX metadata item {id} is truncated"
+            );
+            Ok(data)
+        };
+        let parameters = item(FILE_PARAMETERS, 8)?;
+        let block_size = le_u32(parameters, 0);
+        let flags = le_u32(parameters, 4);
+        ensure!(
+            block_size.is_power_of_two() && (MIB..=256 * MIB).contains(&u64::from(block_size)),
+            "invalid VHDX block size {block_size}"
+        );
+        let logical_sector_size = le_u32(item(LOGICAL_SECTOR_SIZE, 4)?, 0);
+        ensure!(
+            matches!(logical_sector_size, 512 | 4096),
+            "invalid VHDX logical sector size {logical_sector_size}"
+        );
+        let has_parent = flags & 2 != 0;
+        let parent_locator = if has_parent {
+            Some(ParentLocator::parse(item(PARENT_LOCATOR, 20)?)?)
+        } else {
+            None
+        };
+
+        Ok(Metadata {
+            block_size,
+            leave_blocks_allocated: flags & 1 != 0,
+            has_parent,
+            virtual_size: le_u64(item(VIRTUAL_DISK_SIZE, 8)?, 0),
+            virtual_disk_id: item(VIRTUAL_DISK_ID, 16)?[0..16].try_into().unwrap(),
+            logical_sector_size,
+            physical_sector_size: le_u32(item(PHYSICAL_SECTOR_SIZE, 4)?, 0),
+            parent_locator,
+        })
+    }
+}
+
+impl ParentLocator {
+    fn parse(b: &[u8]) -> Result<Self> {
+        let locator_type = format_guid(b[0..16].try_into().unwrap());
+        let count = le_u16(b, 18) as usize;
+        ensure!(
+            b.len() >= 20 + 12 * count,
+            "VHDX parent locator is truncated"
+        );
+        let string = |offset: u32, length: u16| -> Result<String> {
+            let data = b
+                .get(offset as usize..offset as usize + length as usize)
+                .context("VHDX parent locator entry lies outside the locator")?;
+            let units: Vec<u16> = data
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            Ok(String::from_utf16_lossy(&units))
+        };
+        let mut entries = BTreeMap::new();
+        for entry in b[20..20 + 12 * count].chunks_exact(12) {
+            let key = string(le_u32(entry, 0), le_u16(entry, 8))?;
+            let value = string(le_u32(entry, 4), le_u16(entry, 10))?;
+            entries.insert(key, value);
+        }
+        Ok(ParentLocator {
+            locator_type,
+            entries,
+        })
+    }
+}
+
+/// A VHDX exposed as a seekable stream of its guest-visible contents.
+pub struct Vhdx {
+    host: HostFile,
+    header: Header,
+    metadata: Metadata,
+    bat: Vec<u64>,
+    // Payload blocks per sector bitmap block.
+    chunk_ratio: u64,
+    // Chunk index and sector bitmap of the most recently used chunk of a differencing disk.
+    bitmap_cache: Option<(u64, Vec<u8>)>,
+    parent: Option<Box<Vhdx>>,
+    parent_path: Option<PathBuf>,
+    pos: u64,
+}
+
+impl Vhdx {
+    /// Open a VHDX, replaying its log and resolving the parents of differencing disks.
+    pub fn open_path(path: &Path) -> Result<Self> {
+        Self::open_chain(path, 0)
+    }
+
+    fn open_chain(path: &Path, depth: usize) -> Result<Self> {
+        ensure!(
+            depth < MAX_CHAIN_DEPTH,
+            "VHDX parent chain is deeper than {MAX_CHAIN_DEPTH}"
+        );
+        let mut file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mut signature = [0u8; 8];
+        read_exact_at(&mut file, 0, &mut signature)
+            .with_context(|| format!("{} is too small to be a VHDX", path.display()))?;
+        ensure!(
+            &signature == FILE_SIGNATURE,
+            "not a VHDX (bad file signature)"
+        );
+
+        let mut headers = Vec::new();
+        for offset in HEADER_OFFSETS {
+            let mut b = vec![0u8; 4 * KIB as usize];
+            read_exact_at(&mut file, offset, &mut b)?;
+            match Header::parse(&b) {
+                Some(header) => headers.push(header),
+                None => warn!("VHDX header at {offset:#x} is damaged"),
+            }
+        }
+        let header = headers
+            .into_iter()
+            .max_by_key(|h| h.sequence_number)
+            .context("both VHDX headers are damaged")?;
+        ensure!(
+            header.version == 1,
+            "unsupported VHDX version {}",
+            header.version
+        );
+
+        // Everything past the headers may have pending updates in the log.
+        let mut host = HostFile::open(file, &header)?;
+        if host.replayed() {
+            warn!(
+                "{} was not closed cleanly, replaying its log",
+                path.display()
+            );
+        }
+        let (bat_region, metadata_region) = read_region_table(&mut host)?;
+        let metadata = Metadata::read(&mut host, metadata_region.0, metadata_region.1)?;
+
+        let block_size = u64::from(metadata.block_size);
+        let chunk_ratio = (1u64 << 23) * u64::from(metadata.logical_sector_size) / block_size;
+        let data_blocks = metadata.virtual_size.div_ceil(block_size);
+        let entries = if metadata.has_parent {
+            data_blocks.div_ceil(chunk_ratio) * (chunk_ratio + 1)
+        } else {
+            data_blocks + (data_blocks.saturating_sub(1)) / chunk_ratio
+        };
+        ensure!(
+            entries * 8 <= u64::from(bat_region.1),
+            "VHDX block allocation table is too small for the disk"
+        );
+        let mut bytes = vec![0u8; entries as usize * 8];
+        host.read_at(bat_region.0, &mut bytes)
+            .context("failed to read VHDX block allocation table")?;
+        let bat = bytes.chunks_exact(8).map(|c| le_u64(c, 0)).collect();
+
+        let mut vhdx = Vhdx {
+            host,
+            header,
+            metadata,
+            bat,
+            chunk_ratio,
+            bitmap_cache: None,
+            parent: None,
+            parent_path: None,
+            pos: 0,
+        };
+        if let Some(locator) = &vhdx.metadata.parent_locator {
+            ensure!(
+                locator.locator_type == VHDX_PARENT_LOCATOR_TYPE,
+                "unsupported VHDX parent locator type {}",
+                locator.locator_type
+            );
+            let parent_path = find_parent(path, locator)?;
+            let parent = Self::open_chain(&parent_path, depth + 1)
+                .with_context(|| format!("failed to open VHDX parent {}", parent_path.display()))?;
+            let linkage = format!("{{{}}}", format_guid(&parent.header.data_write_guid));
+            if let Some(expected) = locator.entries.get("parent_linkage") {
+                if !expected.eq_ignore_ascii_case(&linkage) {
+                    warn!(
+                        "VHDX parent {} has data write guid {linkage}, expected {expected}",
+                        parent_path.display()
+                    );
+                }
+            }
+            ensure!(
+                parent.virtual_size() >= vhdx.virtual_size(),
+                "VHDX parent {} is smaller than its child",
+                parent_path.display()
+            );
+            vhdx.parent = Some(Box::new(parent));
+            vhdx.parent_path = Some(parent_path);
+        }
+        Ok(vhdx)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Whether the log held updates that had not been applied to the file.
+    pub fn log_replayed(&self) -> bool {
+        self.host.replayed()
+    }
+
+    /// Path of the parent of a differencing disk.
+    pub fn parent_path(&self) -> Option<&Path> {
+        self.parent_path.as_deref()
+    }
+
+    /// Size of the guest-visible disk in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.metadata.virtual_size
+    }
+
+    fn read_mapped_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let block_size = u64::from(self.metadata.block_size);
+        let block = offset / block_size;
+        let in_block = offset % block_size;
+        let mut n = buf.len().min((block_size - in_block) as usize);
+
+        let index = block + block / self.chunk_ratio;
+        let entry = self.bat.get(index as usize).copied().unwrap_or(0);
+        let state = entry & 7;
+        let file_offset = entry & !(MIB - 1);
+        match state {
+            PAYLOAD_FULLY_PRESENT => {
+                self.host.read_at(file_offset + in_block, &mut buf[..n])?;
+                return Ok(n);
+            }
+            PAYLOAD_PARTIALLY_PRESENT if self.parent.is_some() => {}
+            PAYLOAD_PARTIALLY_PRESENT => bail!("partially present VHDX block without a parent"),
+            PAYLOAD_NOT_PRESENT if self.parent.is_some() => {
+                let parent = self.parent.as_mut().unwrap();
+                parent.read_mapped_at(offset, &mut buf[..n])?;
+                return Ok(n);
+            }
+            // Not present without a parent, undefined, zero and unmapped all read as zeroes.
+            _ => {
+                buf[..n].fill(0);
+                return Ok(n);
+            }
+        }
+
+        // Sectors whose bitmap bit is clear come from the parent.
+        let chunk = block / self.chunk_ratio;
+        if !matches!(&self.bitmap_cache, Some((c, _)) if *c == chunk) {
+            let index = chunk * (self.chunk_ratio + 1) + self.chunk_ratio;
+            let entry = self.bat.get(index as usize).copied().unwrap_or(0);
+            ensure!(
+                entry & 7 == SB_BLOCK_PRESENT,
+                "VHDX sector bitmap for chunk {chunk} is missing"
+            );
+            let mut bitmap = vec![0u8; SECTOR_BITMAP_SIZE as usize];
+            self.host.read_at(entry & !(MIB - 1), &mut bitmap)?;
+            self.bitmap_cache = Some((chunk, bitmap));
+        }
+        let bitmap = &self.bitmap_cache.as_ref().unwrap().1;
+        let sector_size = u64::from(self.metadata.logical_sector_size);
+        let sector = (offset % (block_size * self.chunk_ratio)) / sector_size;
+        let present = |s: u64| bitmap[(s / 8) as usize] & (1 << (s % 8)) != 0;
+        let first = present(sector);
+        // Read the run of sectors that come from the same place.
+        let block_sector = sector - in_block / sector_size;
+        let mut end = sector + 1;
+        while (end - block_sector) * sector_size < in_block + n as u64 && present(end) == first {
+            end += 1;
+        }
+        n = n.min(((end - block_sector) * sector_size - in_block) as usize);
+        if first {
+            self.host.read_at(file_offset + in_block, &mut buf[..n])?;
+        } else {
+            self.parent
+                .as_mut()
+                .unwrap()
+                .read_mapped_at(offset, &mut buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+/// Read the current region table, returning the offset and length of the BAT and metadata
+/// regions.
+fn read_region_table(host: &mut HostFile) -> Result<((u64, u32), (u64, u32))> {
+    let mut last_err = None;
+    for offset in REGION_TABLE_OFFSETS {
+        let mut b = vec![0u8; REGION_TABLE_SIZE];
+        host.read_at(offset, &mut b)?;
+        if &b[0..4] != REGION_SIGNATURE || le_u32(&b, 4) != crc32c_checksum(&b, 4) {
+            warn!("VHDX region table at {offset:#x} is damaged");
+            continue;
+        }
+        match parse_region_table(&b) {
+            Ok(regions) => return Ok(regions),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("both VHDX region tables are damaged")))
+}
+
+fn parse_region_table(b: &[u8]) -> Result<((u64, u32), (u64, u32))> {
+    let count = le_u32(b, 8) as usize;
+    ensure!(count <= 2047, "too many VHDX regions ({count})");
+    let mut bat = None;
+    let mut metadata = None;
+    for entry in b[16..16 + 32 * count].chunks_exact(32) {
+        let id = format_guid(entry[0..16].try_into().unwrap());
+        let region = (le_u64(entry, 16), le_u32(entry, 24));
+        let required = le_u32(entry, 28) & 1 != 0;
+        match id.as_str() {
+            BAT_REGION => bat = Some(region),
+            METADATA_REGION => metadata = Some(region),
+            _ if required => bail!("unsupported required VHDX region {id}"),
+            _ => info!("ignoring unknown VHDX region {id}"),
+        }
+    }
+    Ok((
+        bat.context("VHDX has no block allocation table region")?,
+        metadata.context("VHDX has no metadata region")?,
+    ))
+}
+
+/// Resolve the parent of a differencing disk from its parent locator.
+fn find_parent(child: &Path, locator: &ParentLocator) -> Result<PathBuf> {
+    let dir = child.parent().unwrap_or(Path::new(""));
+    let mut candidates = Vec::new();
+    // The relative path first, so that a moved disk chain still resolves.
+    for key in ["relative_path", "volume_path", "absolute_win32_path"] {
+        let Some(path) = locator.entries.get(key) else {
+            continue;
+        };
+        let path = path.replace('\\', "/");
+        let path = path.strip_prefix("./").unwrap_or(&path);
+        candidates.push(dir.join(path));
+        // An absolute Windows path can still name a file next to the child.
+        if let Some(name) = Path::new(path).file_name() {
+            candidates.push(dir.join(name));
+        }
+    }
+    candidates
+        .into_iter()
+        .find(|p| p.is_file())
+        .with_context(|| {
+            format!(
+                "parent {} of differencing VHDX {} not found",
+                locator
+                    .entries
+                    .get("relative_path")
+                    .map_or("(unnamed)", String::as_str),
+                child.display()
+            )
+        })
+}
+
+impl VirtualDisk for Vhdx {
+    fn size(&self) -> u64 {
+        self.virtual_size()
+    }
+
+    fn block_size(&self) -> u64 {
+        u64::from(self.metadata.block_size)
+    }
+
+    /// Blocks left to the parent of a differencing disk count as data.
+    fn extents(&mut self) -> Result<Vec<Extent>> {
+        let size = self.virtual_size();
+        let block_size = u64::from(self.metadata.block_size);
+        let mut extents = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            let len = block_size.min(size - offset);
+            let block = offset / block_size;
+            let index = block + block / self.chunk_ratio;
+            let state = self.bat.get(index as usize).map_or(0, |e| e & 7);
+            let kind = match state {
+                PAYLOAD_FULLY_PRESENT | PAYLOAD_PARTIALLY_PRESENT => ExtentKind::Data,
+                PAYLOAD_NOT_PRESENT if self.parent.is_some() => ExtentKind::Data,
+                _ => ExtentKind::Zero,
+            };
+            push_extent(&mut extents, offset, len, kind);
+            offset += len;
+        }
+        Ok(extents)
+    }
+}
+
+impl Read for Vhdx {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.virtual_size();
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = (buf.len() as u64).min(size - self.pos) as usize;
+        let n = self
+            .read_mapped_at(self.pos, &mut buf[..len])
+            .map_err(io::Error::other)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Vhdx {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.virtual_size().checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Replay of the VHDX metadata log.
+//!
+//! A VHDX host writes metadata changes to a circular log before applying them in place. When
+//! a host crashes the log may hold updates that never reached the BAT or metadata region.
+//! Rather than modifying the image, the active log sequence is applied as an overlay on every
+//! read of the file.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{ensure, Result};
+use tracing::{info, warn};
+
+use super::{crc32c_checksum, Header};
+use crate::util::{le_u32, le_u64, read_exact_at};
+
+const ENTRY_SIGNATURE: &[u8; 4] = b"loge";
+const DATA_DESCRIPTOR: &[u8; 4] = b"desc";
+const ZERO_DESCRIPTOR: &[u8; 4] = b"zero";
+const DATA_SECTOR: &[u8; 4] = b"data";
+const SECTOR: u64 = 4096;
+
+/// A write recorded in the log: `data`, or `len` zero bytes when `data` is `None`.
+#[derive(Debug, Clone)]
+pub(super) struct LogWrite {
+    offset: u64,
+    len: u64,
+    data: Option<Vec<u8>>,
+}
+
+/// A valid log entry and the writes it describes.
+struct Entry {
+    len: u64,
+    tail: u64,
+    sequence: u64,
+    writes: Vec<LogWrite>,
+}
+
+/// The image file with the active log sequence applied on top.
+pub(super) struct HostFile {
+    file: File,
+    writes: Vec<LogWrite>,
+}
+
+impl HostFile {
+    /// Open `file` for reading, replaying the log referenced by `header` if it has one.
+    pub(super) fn open(mut file: File, header: &Header) -> Result<Self> {
+        let writes = if header.log_guid == [0; 16] || header.log_length == 0 {
+            Vec::new()
+        } else {
+            replay(&mut file, header)?
+        };
+        Ok(HostFile { file, writes })
+    }
+
+    /// Whether the log held updates that had not been applied to the file.
+    pub(super) fn replayed(&self) -> bool {
+        !self.writes.is_empty()
+    }
+
+    /// Fill `buf` from `offset`; bytes past the end of the file read as zeroes, since the log
+    /// may extend the file.
+    pub(super) fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = self.file.read(&mut buf[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        buf[filled..].fill(0);
+
+        let end = offset + buf.len() as u64;
+        for write in &self.writes {
+            let start = write.offset.max(offset);
+            let stop = (write.offset + write.len).min(end);
+            if start >= stop {
+                continue;
+            }
+            let dst = &mut buf[(start - offset) as usize..(stop - offset) as usize];
+            match &write.data {
+                Some(data) => dst.copy_from_slice(
+                    &data[(start - write.offset) as usize..(stop - write.offset) as usize],
+                ),
+                None => dst.fill(0),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Find the active log sequence and return its writes in the order they must be applied.
+fn replay(file: &mut File, header: &Header) -> Result<Vec<LogWrite>> {
+    let log_len = u64::from(header.log_length);
+    ensure!(
+        log_len % SECTOR == 0 && header.log_offset % SECTOR == 0,
+        "VHDX log is not 4 KiB aligned"
+    );
+    let mut log = vec![0u8; log_len as usize];
+    read_exact_at(file, header.log_offset, &mut log)?;
+
+    // Every valid sequence ends in a head entry whose tail points back into the sequence. The
+    // active sequence is the one with the highest head sequence number.
+    let mut best: Option<Vec<Entry>> = None;
+    for start in (0..log_len).step_by(SECTOR as usize) {
+        let mut chain: Vec<(u64, Entry)> = Vec::new();
+        let mut offset = start;
+        while chain.len() as u64 <= log_len / SECTOR {
+            let Some(entry) = parse_entry(&log, offset, header) else {
+                break;
+            };
+            if let Some((_, last)) = chain.last() {
+                if entry.sequence != last.sequence + 1 {
+                    break;
+                }
+            }
+            let next = (offset + entry.len) % log_len;
+            chain.push((offset, entry));
+            offset = next;
+        }
+        let Some((_, head)) = chain.last() else {
+            continue;
+        };
+        let Some(tail) = chain.iter().position(|(o, _)| *o == head.tail) else {
+            continue;
+        };
+        let better = match &best {
+            Some(b) => head.sequence > b.last().map_or(0, |e| e.sequence),
+            None => true,
+        };
+        if better {
+            best = Some(chain.into_iter().skip(tail).map(|(_, e)| e).collect());
+        }
+    }
+
+    let Some(sequence) = best else {
+        warn!("VHDX log is marked active but has no valid entries");
+        return Ok(Vec::new());
+    };
+    let writes: Vec<LogWrite> = sequence.into_iter().flat_map(|e| e.writes).collect();
+    info!("replaying {} VHDX log writes", writes.len());
+    Ok(writes)
+}
+
+/// Parse and validate the log entry at `offset`, wrapping around the end of the log.
+fn parse_entry(log: &[u8], offset: u64, header: &Header) -> Option<Entry> {
+    let log_len = log.len() as u64;
+    let read = |at: u64, len: u64| -> Vec<u8> {
+        (0..len)
+            .map(|i| log[((at + i) % log_len) as usize])
+            .collect()
+    };
+    let head = read(offset, SECTOR);
+    if &head[0..4] != ENTRY_SIGNATURE || head[32..48] != header.log_guid {
+        return None;
+    }
+    let len = u64::from(le_u32(&head, 8));
+    if len == 0 || len % SECTOR != 0 || len > log_len {
+        return None;
+    }
+    let entry = read(offset, len);
+    if le_u32(&entry, 4) != crc32c_checksum(&entry, 4) {
+        return None;
+    }
+    let sequence = le_u64(&entry, 16);
+    let count = le_u32(&entry, 24) as u64;
+    let descriptors_len = (64 + 32 * count).next_multiple_of(SECTOR);
+    if descriptors_len > len {
+        return None;
+    }
+
+    let mut writes = Vec::new();
+    let mut data_sector = descriptors_len;
+    for i in 0..count as usize {
+        let d = &entry[64 + 32 * i..96 + 32 * i];
+        if le_u64(d, 24) != sequence {
+            return None;
+        }
+        let file_offset = le_u64(d, 16);
+        match &d[0..4] {
+            sig if sig == DATA_DESCRIPTOR => {
+                if data_sector + SECTOR > len {
+                    return None;
+                }
+                let s = &entry[data_sector as usize..(data_sector + SECTOR) as usize];
+                let sector_sequence = (u64::from(le_u32(s, 4)) << 32) | u64::from(le_u32(s, 4092));
+                if &s[0..4] != DATA_SECTOR || sector_sequence != sequence {
+                    return None;
+                }
+                // The first 8 and last 4 bytes of the sector are kept in the descriptor.
+                let mut data = Vec::with_capacity(SECTOR as usize);
+                data.extend_from_slice(&d[8..16]);
+                data.extend_from_slice(&s[8..4092]);
+                data.extend_from_slice(&d[4..8]);
+                writes.push(LogWrite {
+                    offset: file_offset,
+                    len: SECTOR,
+                    data: Some(data),
+                });
+                data_sector += SECTOR;
+            }
+            sig if sig == ZERO_DESCRIPTOR => writes.push(LogWrite {
+                offset: file_offset,
+                len: le_u64(d, 8),
+                data: None,
+            }),
+            _ => return None,
+        }
+    }
+    Some(Entry {
+        len,
+        tail: u64::from(le_u32(&entry, 12)),
+        sequence,
+        writes,
+    })
+}
@@ -0,0 +1,509 @@
+//! Reader and writer for VMware virtual disks (VMDK).
+//!
+//! Reads monolithic and split flat and sparse disks as well as the compressed
+//! streamOptimized variant, and writes streamOptimized disks. See the "Virtual Disk Format 5.0" specification from VMware.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use flate2::read::ZlibDecoder;
+use tracing::warn;
+
+use crate::disk::VirtualDisk;
+use crate::util::{allocated_size, le_u16, le_u32, le_u64, read_exact_at, stream_len, ReadSeek};
+
+mod descriptor;
+mod writer;
+
+pub use descriptor::{Descriptor, ExtentDescriptor, ExtentType, NO_PARENT_CID};
+pub(crate) use writer::write_stream;
+pub use writer::{write_vmdk, AdapterType, VmdkOptions};
+
+pub(crate) const SPARSE_MAGIC: &[u8; 4] = b"KDMV";
+
+const FLAG_NEWLINE_DETECT: u32 = 1 << 0;
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const FLAG_MARKERS: u32 = 1 << 17;
+const COMPRESSION_DEFLATE: u16 = 1;
+// A grain directory offset meaning "see the footer at the end of the file".
+const GD_AT_END: u64 = u64::MAX;
+
+const MARKER_EOS: u32 = 0;
+const MARKER_GT: u32 = 1;
+const MARKER_GD: u32 = 2;
+const MARKER_FOOTER: u32 = 3;
+
+// Grain table entry for a grain that reads as zeroes (sparse version 2 and later).
+const GTE_ZERO: u32 = 1;
+
+// Number of grain tables kept in memory while reading.
+const GT_CACHE_TABLES: usize = 256;
+
+/// The binary header at the start (and, for streamOptimized disks, the end) of a sparse extent.
+#[derive(Debug, Clone)]
+pub struct SparseHeader {
+    pub version: u32,
+    pub flags: u32,
+    /// Extent capacity in sectors.
+    pub capacity: u64,
+    /// Grain size in sectors.
+    pub grain_size: u64,
+    pub descriptor_offset: u64,
+    pub descriptor_size: u64,
+    pub num_gtes_per_gt: u32,
+    pub rgd_offset: u64,
+    pub gd_offset: u64,
+    pub overhead: u64,
+    pub unclean_shutdown: bool,
+    pub compress_algorithm: u16,
+}
+
+impl SparseHeader {
+    fn parse(b: &[u8; 512]) -> Result<Self> {
+        ensure!(
+            &b[0..4] == SPARSE_MAGIC,
+            "not a VMDK sparse extent (bad magic)"
+        );
+        let header = SparseHeader {
+            version: le_u32(b, 4),
+            flags: le_u32(b, 8),
+            capacity: le_u64(b, 12),
+            grain_size: le_u64(b, 20),
+            descriptor_offset: le_u64(b, 28),
+            descriptor_size: le_u64(b, 36),
+            num_gtes_per_gt: le_u32(b, 44),
+            rgd_offset: le_u64(b, 48),
+            gd_offset: le_u64(b, 56),
+            overhead: le_u64(b, 64),
+            unclean_shutdown: b[72] != 0,
+            compress_algorithm: le_u16(b, 77),
+        };
+        ensure!(
+            (1..=3).contains(&header.version),
+            "unsupported VMDK sparse extent version {}",
+            header.version
+        );
+        ensure!(
+            header.grain_size.is_power_of_two() && header.grain_size >= 8,
+            "invalid VMDK grain size {}",
+            header.grain_size
+        );
+        ensure!(header.num_gtes_per_gt > 0, "invalid VMDK grain table size");
+        Ok(header)
+    }
+
+    fn compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    fn grain_bytes(&self) -> u64 {
+        self.grain_size * 512
+    }
+}
+
+/// Where the data of a grain lives.
+#[derive(Debug, Clone, Copy)]
+enum Grain {
+    Unallocated,
+    Zero,
+    /// Byte offset of uncompressed grain data.
+    Data(u64),
+    /// Byte offset of a compressed grain marker.
+    Compressed(u64),
+}
+
+/// A sparse extent with its grain directory loaded.
+struct SparseExtent {
+    file: Box<dyn ReadSeek>,
+    header: SparseHeader,
+    /// Grain directory: sector offsets of grain tables. Empty when `grain_map` is used.
+    gd: Vec<u32>,
+    gt_cache: HashMap<u32, Vec<u32>>,
+    /// Grain locations found by scanning a streamOptimized extent without a usable directory.
+    grain_map: Option<HashMap<u64, u64>>,
+    // Index and contents of the most recently decompressed grain.
+    grain_cache: Option<(u64, Vec<u8>)>,
+}
+
+impl SparseExtent {
+    fn open(mut file: Box<dyn ReadSeek>) -> Result<Self> {
+        let mut buf = [0u8; 512];
+        read_exact_at(&mut file, 0, &mut buf)?;
+        let mut header = SparseHeader::parse(&buf)?;
+        if header.unclean_shutdown {
+            warn!("VMDK extent was not closed cleanly");
+        }
+
+        if header.gd_offset == GD_AT_END {
+            // streamOptimized: the real header is in the footer, before the end-of-stream marker.
+            let len = stream_len(&mut file)?;
+            ensure!(len >= 1536, "truncated streamOptimized VMDK");
+            read_exact_at(&mut file, len - 1024, &mut buf)?;
+            let footer = SparseHeader::parse(&buf).ok();
+            match footer {
+                Some(footer) if footer.gd_offset != GD_AT_END => header = footer,
+                _ => {
+                    warn!("streamOptimized VMDK has no usable footer, scanning its grains");
+                    let grain_map = scan_grains(&mut file, &header)?;
+                    return Ok(SparseExtent {
+                        file,
+                        header,
+                        gd: Vec::new(),
+                        gt_cache: HashMap::new(),
+                        grain_map: Some(grain_map),
+                        grain_cache: None,
+                    });
+                }
+            }
+        }
+        ensure!(
+            !header.compressed() || header.compress_algorithm == COMPRESSION_DEFLATE,
+            "unsupported VMDK compression algorithm {}",
+            header.compress_algorithm
+        );
+
+        let grains = header.capacity.div_ceil(header.grain_size);
+        let gd_entries = grains.div_ceil(u64::from(header.num_gtes_per_gt));
+        let mut gd_bytes = vec![0u8; gd_entries as usize * 4];
+        read_exact_at(&mut file, header.gd_offset * 512, &mut gd_bytes)
+            .context("failed to read VMDK grain directory")?;
+        let gd = gd_bytes.chunks_exact(4).map(|c| le_u32(c, 0)).collect();
+        Ok(SparseExtent {
+            file,
+            header,
+            gd,
+            gt_cache: HashMap::new(),
+            grain_map: None,
+            grain_cache: None,
+        })
+    }
+
+    fn grain(&mut self, index: u64) -> Result<Grain> {
+        if let Some(map) = &self.grain_map {
+            return Ok(map
+                .get(&index)
+                .map_or(Grain::Unallocated, |&offset| Grain::Compressed(offset)));
+        }
+        let per_gt = u64::from(self.header.num_gtes_per_gt);
+        let Some(&gt_sector) = self.gd.get((index / per_gt) as usize) else {
+            return Ok(Grain::Unallocated);
+        };
+        if gt_sector == 0 {
+            return Ok(Grain::Unallocated);
+        }
+        if !self.gt_cache.contains_key(&gt_sector) {
+            if self.gt_cache.len() >= GT_CACHE_TABLES {
+                self.gt_cache.clear();
+            }
+            let mut bytes = vec![0u8; per_gt as usize * 4];
+            read_exact_at(&mut self.file, u64::from(gt_sector) * 512, &mut bytes)
+                .context("failed to read VMDK grain table")?;
+            let table = bytes.chunks_exact(4).map(|c| le_u32(c, 0)).collect();
+            self.gt_cache.insert(gt_sector, table);
+        }
+        let entry = self.gt_cache[&gt_sector][(index % per_gt) as usize];
+        Ok(match entry {
+            0 => Grain::Unallocated,
+            GTE_ZERO if self.header.version >= 2 => Grain::Zero,
+            sector if self.header.compressed() => Grain::Compressed(u64::from(sector) * 512),
+            sector => Grain::Data(u64::from(sector) * 512),
+        })
+    }
+
+    fn decompress(&mut self, index: u64, offset: u64) -> Result<&[u8]> {
+        let cached = matches!(&self.grain_cache, Some((i, _)) if *i == index);
+        if !cached {
+            let mut marker = [0u8; 12];
+            read_exact_at(&mut self.file, offset, &mut marker)?;
+            let size = le_u32(&marker, 8);
+            let mut compressed = vec![0u8; size as usize];
+            self.file.read_exact(&mut compressed)?;
+
+            let mut grain = Vec::with_capacity(self.header.grain_bytes() as usize);
+            ZlibDecoder::new(&compressed[..])
+                .take(self.header.grain_bytes())
+                .read_to_end(&mut grain)
+                .with_context(|| format!("failed to decompress VMDK grain at {offset:#x}"))?;
+            // The last grain of a disk may be stored short.
+            grain.resize(self.header.grain_bytes() as usize, 0);
+            self.grain_cache = Some((index, grain));
+        }
+        Ok(&self.grain_cache.as_ref().unwrap().1)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let grain_bytes = self.header.grain_bytes();
+        let index = offset / grain_bytes;
+        let in_grain = offset % grain_bytes;
+        let n = buf.len().min((grain_bytes - in_grain) as usize);
+        let buf = &mut buf[..n];
+        match self.grain(index)? {
+            Grain::Unallocated | Grain::Zero => buf.fill(0),
+            Grain::Data(data) => read_exact_at(&mut self.file, data + in_grain, buf)?,
+            Grain::Compressed(marker) => {
+                let grain = self.decompress(index, marker)?;
+                buf.copy_from_slice(&grain[in_grain as usize..in_grain as usize + n]);
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Walk the markers of a streamOptimized extent, recording where each grain is stored.
+fn scan_grains(file: &mut dyn ReadSeek, header: &SparseHeader) -> Result<HashMap<u64, u64>> {
+    ensure!(
+        header.flags & FLAG_MARKERS != 0,
+        "VMDK extent has neither a grain directory nor markers"
+    );
+    let len = stream_len(file)?;
+    let mut grains = HashMap::new();
+    let mut offset = header.overhead * 512;
+    let mut marker = [0u8; 16];
+    while offset + 16 <= len {
+        read_exact_at(file, offset, &mut marker)?;
+        let value = le_u64(&marker, 0);
+        let size = le_u32(&marker, 8);
+        if size != 0 {
+            grains.insert(value / header.grain_size, offset);
+            offset = (offset + 12 + u64::from(size)).next_multiple_of(512);
+            continue;
+        }
+        match le_u32(&marker, 12) {
+            MARKER_EOS => break,
+            // Metadata markers are followed by `value` sectors of grain table, directory or footer.
+            kind if kind <= MARKER_FOOTER => offset += 512 + value * 512,
+            kind => bail!("unknown VMDK marker type {kind} at {offset:#x}"),
+        }
+    }
+    Ok(grains)
+}
+
+enum ExtentData {
+    Flat { file: File, offset: u64 },
+    Sparse(Box<SparseExtent>),
+    Zero,
+}
+
+struct Extent {
+    /// Guest byte offset of the extent's first sector.
+    start: u64,
+    len: u64,
+    data: ExtentData,
+}
+
+/// A VMDK disk exposed as a seekable stream of its guest-visible contents.
+pub struct Vmdk {
+    descriptor: Descriptor,
+    extents: Vec<Extent>,
+    size: u64,
+    // Bytes allocated on the host for the extent files.
+    allocated: u64,
+    pos: u64,
+}
+
+impl Vmdk {
+    /// Open a VMDK from either a descriptor file or a sparse extent with an embedded descriptor.
+    pub fn open_path(path: &Path) -> Result<Self> {
+        let mut file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mut magic = [0u8; 4];
+        let is_sparse = file.read_exact(&mut magic).is_ok() && &magic == SPARSE_MAGIC;
+        file.rewind()?;
+
+        if !is_sparse {
+            let mut text = String::new();
+            file.take(1 << 20)
+                .read_to_string(&mut text)
+                .context("VMDK descriptor is not valid text")?;
+            let descriptor = Descriptor::parse(&text)?;
+            return Self::from_descriptor(descriptor, path.parent(), None);
+        }
+
+        let allocated = allocated_size(&file)?.unwrap_or(0);
+        Self::open_monolithic(Box::new(file), allocated, path.parent())
+    }
+
+    /// Open a monolithic sparse VMDK with an embedded descriptor from a stream, such as a
+    /// streamOptimized disk stored inside an OVA.
+    pub fn open(mut inner: Box<dyn ReadSeek>) -> Result<Self> {
+        let len = stream_len(&mut inner)?;
+        Self::open_monolithic(inner, len, None)
+    }
+
+    fn open_monolithic(
+        inner: Box<dyn ReadSeek>,
+        allocated: u64,
+        dir: Option<&Path>,
+    ) -> Result<Self> {
+        let mut extent = SparseExtent::open(inner)?;
+        let header = extent.header.clone();
+        let descriptor = if header.descriptor_offset != 0 && header.descriptor_size != 0 {
+            let mut text = vec![0u8; header.descriptor_size as usize * 512];
+            read_exact_at(&mut extent.file, header.descriptor_offset * 512, &mut text)?;
+            let end = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+            Descriptor::parse(&String::from_utf8_lossy(&text[..end]))?
+        } else {
+            // A bare sparse extent without descriptor, e.g. one of a split disk's files.
+            Descriptor {
+                version: 1,
+                cid: 0,
+                parent_cid: NO_PARENT_CID,
+                create_type: "monolithicSparse".to_string(),
+                extents: vec![ExtentDescriptor {
+                    access: "RW".to_string(),
+                    sectors: header.capacity,
+                    kind: ExtentType::Sparse,
+                    file: None,
+                    offset: 0,
+                }],
+                ddb: Default::default(),
+            }
+        };
+        ensure!(
+            descriptor.extents.len() == 1,
+            "monolithic VMDK with an embedded descriptor must have exactly one extent"
+        );
+        Self::from_descriptor(descriptor, dir, Some((extent, allocated)))
+    }
+
+    fn from_descriptor(
+        descriptor: Descriptor,
+        dir: Option<&Path>,
+        mut embedded: Option<(SparseExtent, u64)>,
+    ) -> Result<Self> {
+        ensure!(
+            descriptor.parent_cid == NO_PARENT_CID,
+            "VMDK delta disks with a parent are not supported"
+        );
+        let resolve = |name: &str| -> PathBuf {
+            match dir {
+                Some(dir) => dir.join(name),
+                None => PathBuf::from(name),
+            }
+        };
+
+        let mut extents = Vec::new();
+        let mut start = 0;
+        let mut allocated = 0;
+        for e in &descriptor.extents {
+            let len = e.sectors * 512;
+            let data = match e.kind {
+                ExtentType::Zero => ExtentData::Zero,
+                ExtentType::Flat | ExtentType::Vmfs => {
+                    let path = resolve(e.file.as_deref().unwrap_or_default());
+                    let file = File::open(&path)
+                        .with_context(|| format!("failed to open extent {}", path.display()))?;
+                    allocated += allocated_size(&file)?.unwrap_or(0);
+                    ExtentData::Flat {
+                        file,
+                        offset: e.offset * 512,
+                    }
+                }
+                ExtentType::Sparse => {
+                    let extent = match embedded.take() {
+                        Some((extent, size)) => {
+                            allocated += size;
+                            extent
+                        }
+                        None => {
+                            let path = resolve(e.file.as_deref().unwrap_or_default());
+                            let file = File::open(&path).with_context(|| {
+                                format!("failed to open extent {}", path.display())
+                            })?;
+                            allocated += allocated_size(&file)?.unwrap_or(0);
+                            SparseExtent::open(Box::new(file))
+                                .with_context(|| format!("failed to read {}", path.display()))?
+                        }
+                    };
+                    ExtentData::Sparse(Box::new(extent))
+                }
+            };
+            extents.push(Extent { start, len, data });
+            start += len;
+        }
+        Ok(Vmdk {
+            descriptor,
+            extents,
+            size: start,
+            allocated,
+            pos: 0,
+        })
+    }
+
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+
+    /// Size of the guest-visible disk in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.size
+    }
+
+    /// Bytes allocated on the host for all of the disk's extent files.
+    pub fn allocated_size(&self) -> u64 {
+        self.allocated
+    }
+
+    fn read_extent(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let pos = self.pos;
+        let i = self
+            .extents
+            .partition_point(|e| e.start + e.len <= pos)
+            .min(self.extents.len() - 1);
+        let extent = &mut self.extents[i];
+        let in_extent = pos - extent.start;
+        let n = (buf.len() as u64).min(extent.len - in_extent) as usize;
+        let buf = &mut buf[..n];
+        let n = match &mut extent.data {
+            ExtentData::Zero => {
+                buf.fill(0);
+                n
+            }
+            ExtentData::Flat { file, offset } => {
+                read_exact_at(file, *offset + in_extent, buf)?;
+                n
+            }
+            ExtentData::Sparse(sparse) => sparse.read_at(in_extent, buf)?,
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl VirtualDisk for Vmdk {
+    fn size(&self) -> u64 {
+        self.virtual_size()
+    }
+}
+
+impl Read for Vmdk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_extent(buf).map_err(io::Error::other)
+    }
+}
+
+impl Seek for Vmdk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.size.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
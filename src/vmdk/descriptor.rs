@@ -0,0 +1,178 @@
+//! The text descriptor that describes a VMDK disk's extents and properties.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+
+/// CID value meaning "no parent disk".
+pub const NO_PARENT_CID: u32 = 0xFFFF_FFFF;
+
+/// How an extent stores its part of the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentType {
+    Flat,
+    Sparse,
+    Zero,
+    Vmfs,
+}
+
+/// One line of the extent description.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtentDescriptor {
+    /// `RW`, `RDONLY` or `NOACCESS`.
+    pub access: String,
+    /// Size of the extent in 512-byte sectors.
+    pub sectors: u64,
+    pub kind: ExtentType,
+    /// File holding the extent, relative to the descriptor's directory.
+    pub file: Option<String>,
+    /// Sector offset of the extent's data within a flat file.
+    pub offset: u64,
+}
+
+/// A parsed VMDK descriptor file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Descriptor {
+    pub version: u32,
+    pub cid: u32,
+    pub parent_cid: u32,
+    /// E.g. `monolithicSparse`, `streamOptimized` or `twoGbMaxExtentFlat`.
+    pub create_type: String,
+    pub extents: Vec<ExtentDescriptor>,
+    /// Disk database entries such as `ddb.adapterType`, without their quotes.
+    pub ddb: BTreeMap<String, String>,
+}
+
+impl Descriptor {
+    /// Parse the text of a descriptor.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut descriptor = Descriptor {
+            version: 1,
+            cid: 0,
+            parent_cid: NO_PARENT_CID,
+            create_type: String::new(),
+            extents: Vec::new(),
+            ddb: BTreeMap::new(),
+        };
+        for line in text.lines() {
+            let line = line.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let access = line.split_whitespace().next().unwrap_or_default();
+            if matches!(access, "RW" | "RDONLY" | "NOACCESS") {
+                descriptor.extents.push(
+                    parse_extent(line)
+                        .with_context(|| format!("invalid VMDK extent line {line:?}"))?,
+                );
+            } else if let Some((key, value)) = line.split_once('=') {
+                let key = key.trim();
+                let value = value.trim().trim_matches('"');
+                match key {
+                    "version" => descriptor.version = value.parse().context("invalid version")?,
+                    "CID" => descriptor.cid = parse_cid(value)?,
+                    "parentCID" => descriptor.parent_cid = parse_cid(value)?,
+                    "createType" => descriptor.create_type = value.to_string(),
+                    k if k.starts_with("ddb.") => {
+                        descriptor.ddb.insert(k.to_string(), value.to_string());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if descriptor.extents.is_empty() {
+            bail!("VMDK descriptor has no extents");
+        }
+        Ok(descriptor)
+    }
+
+    /// Total size of the disk in bytes.
+    pub fn capacity(&self) -> u64 {
+        self.extents.iter().map(|e| e.sectors * 512).sum()
+    }
+}
+
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# Disk DescriptorFile")?;
+        writeln!(f, "version={}", self.version)?;
+        writeln!(f, "CID={:08x}", self.cid)?;
+        writeln!(f, "parentCID={:08x}", self.parent_cid)?;
+        writeln!(f, "createType=\"{}\"", self.create_type)?;
+        writeln!(f)?;
+        writeln!(f, "# Extent description")?;
+        for extent in &self.extents {
+            writeln!(f, "{extent}")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "# The Disk Data Base")?;
+        writeln!(f, "#DDB")?;
+        writeln!(f)?;
+        for (key, value) in &self.ddb {
+            writeln!(f, "{key} = \"{value}\"")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ExtentDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ExtentType::Flat => "FLAT",
+            ExtentType::Sparse => "SPARSE",
+            ExtentType::Zero => "ZERO",
+            ExtentType::Vmfs => "VMFS",
+        };
+        write!(f, "{} {} {kind}", self.access, self.sectors)?;
+        if let Some(file) = &self.file {
+            write!(f, " \"{file}\"")?;
+        }
+        if self.kind == ExtentType::Flat {
+            write!(f, " {}", self.offset)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_cid(value: &str) -> Result<u32> {
+    u32::from_str_radix(value, 16).with_context(|| format!("invalid CID {value}"))
+}
+
+fn parse_extent(line: &str) -> Result<ExtentDescriptor> {
+    let mut parts = line.splitn(3, char::is_whitespace);
+    let access = parts.next().unwrap_or_default().to_string();
+    let sectors = parts.next().unwrap_or_default().parse()?;
+    let rest = parts.next().unwrap_or_default().trim_start();
+    let (kind, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let kind = match kind {
+        "FLAT" => ExtentType::Flat,
+        "SPARSE" => ExtentType::Sparse,
+        "ZERO" => ExtentType::Zero,
+        "VMFS" => ExtentType::Vmfs,
+        other => bail!("unsupported extent type {other}"),
+    };
+
+    let rest = rest.trim();
+    let (file, rest) = match rest.strip_prefix('"') {
+        Some(quoted) => {
+            let end = quoted.find('"').context("unterminated file name")?;
+            (Some(quoted[..end].to_string()), &quoted[end + 1..])
+        }
+        None => (None, rest),
+    };
+    let offset = match rest.split_whitespace().next() {
+        Some(offset) => offset.parse()?,
+        None => 0,
+    };
+    if kind != ExtentType::Zero && file.is_none() {
+        bail!("extent has no file name");
+    }
+    Ok(ExtentDescriptor {
+        access,
+        sectors,
+        kind,
+        file,
+        offset,
+    })
+}
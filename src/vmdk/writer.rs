@@ -0,0 +1,278 @@
+//! Writer producing streamOptimized VMDK disks.
+//!
+//! The output is written strictly sequentially: header and descriptor, compressed grains
+//! with a grain table after every `GTES_PER_GT` grains, then the grain directory, a footer
+//! repeating the header and the end-of-stream marker. This is the layout vSphere, OVF
+//! appliances and AWS VM Import expect.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Context, Result};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use tracing::info;
+
+use super::{
+    Descriptor, ExtentDescriptor, ExtentType, SparseHeader, COMPRESSION_DEFLATE, FLAG_COMPRESSED,
+    FLAG_MARKERS, FLAG_NEWLINE_DETECT, GD_AT_END, MARKER_FOOTER, MARKER_GD, MARKER_GT,
+    NO_PARENT_CID, SPARSE_MAGIC,
+};
+use crate::disk::{DiskWriter, VirtualDisk};
+use crate::util::{human_size, stream_len};
+
+// 64 KiB grains and 512-entry grain tables, as written by VMware.
+const GRAIN_SECTORS: u64 = 128;
+const GTES_PER_GT: u64 = 512;
+// Sectors reserved for the embedded descriptor.
+const DESCRIPTOR_SECTORS: u64 = 20;
+
+/// Virtual disk controller recorded as `ddb.adapterType`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AdapterType {
+    Ide,
+    #[default]
+    LsiLogic,
+    BusLogic,
+    LegacyEsx,
+}
+
+impl AdapterType {
+    /// Name of the controller as used in VMDK descriptors and OVF hardware items.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AdapterType::Ide => "ide",
+            AdapterType::LsiLogic => "lsilogic",
+            AdapterType::BusLogic => "buslogic",
+            AdapterType::LegacyEsx => "legacyESX",
+        }
+    }
+
+    /// Heads and sectors per track of the BIOS geometry reported for this controller.
+    fn geometry(self) -> (u64, u64) {
+        match self {
+            AdapterType::Ide => (16, 63),
+            _ => (255, 63),
+        }
+    }
+}
+
+/// Options controlling a written VMDK.
+#[derive(Debug, Clone, Default)]
+pub struct VmdkOptions {
+    pub adapter_type: AdapterType,
+}
+
+impl DiskWriter for VmdkOptions {
+    fn write_disk(&self, disk: &mut dyn VirtualDisk, path: &Path) -> Result<()> {
+        write_vmdk(disk, path, self)
+    }
+}
+
+/// Write the guest disk exposed by `disk` to a new streamOptimized VMDK at `path`.
+///
+/// Grains that are entirely zero are left out.
+pub fn write_vmdk<R: Read + Seek + ?Sized>(
+    disk: &mut R,
+    path: &Path,
+    options: &VmdkOptions,
+) -> Result<()> {
+    let size = stream_len(disk)?;
+    info!(
+        "writing {} streamOptimized vmdk to {}",
+        human_size(size),
+        path.display()
+    );
+    let file_name = path
+        .file_name()
+        .context("vmdk path has no file name")?
+        .to_string_lossy();
+
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut out = BufWriter::with_capacity(4 << 20, file);
+    write_stream(disk, &mut out, size, &file_name, options)?;
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Write a streamOptimized VMDK of the `size`-byte disk read from `disk` to `out`, naming the
+/// extent `file_name` in its descriptor.
+pub(crate) fn write_stream<R: Read + ?Sized, W: Write>(
+    disk: &mut R,
+    out: &mut W,
+    size: u64,
+    file_name: &str,
+    options: &VmdkOptions,
+) -> Result<()> {
+    let capacity = size.div_ceil(512);
+    let descriptor = stream_descriptor(capacity, file_name, options.adapter_type);
+    let mut text = descriptor.to_string().into_bytes();
+    ensure!(
+        text.len() as u64 <= DESCRIPTOR_SECTORS * 512,
+        "vmdk descriptor is too long"
+    );
+    text.resize((DESCRIPTOR_SECTORS * 512) as usize, 0);
+
+    let overhead = (1 + DESCRIPTOR_SECTORS).next_multiple_of(GRAIN_SECTORS);
+    let mut header = SparseHeader {
+        version: 3,
+        flags: FLAG_NEWLINE_DETECT | FLAG_COMPRESSED | FLAG_MARKERS,
+        capacity,
+        grain_size: GRAIN_SECTORS,
+        descriptor_offset: 1,
+        descriptor_size: DESCRIPTOR_SECTORS,
+        num_gtes_per_gt: GTES_PER_GT as u32,
+        rgd_offset: 0,
+        gd_offset: GD_AT_END,
+        overhead,
+        unclean_shutdown: false,
+        compress_algorithm: COMPRESSION_DEFLATE,
+    };
+    let mut stream = Stream { out, sector: 0 };
+    stream.write(&encode_header(&header))?;
+    stream.write(&text)?;
+    stream.pad_to(overhead)?;
+
+    let grain_bytes = GRAIN_SECTORS * 512;
+    let grains = capacity.div_ceil(GRAIN_SECTORS);
+    let mut gd = vec![0u32; grains.div_ceil(GTES_PER_GT) as usize];
+    let mut gt = vec![0u32; GTES_PER_GT as usize];
+    let mut grain = vec![0u8; grain_bytes as usize];
+    for index in 0..grains {
+        let len = (size - index * grain_bytes).min(grain_bytes) as usize;
+        grain[len..].fill(0);
+        disk.read_exact(&mut grain[..len])
+            .with_context(|| format!("failed to read image at offset {}", index * grain_bytes))?;
+        if grain.iter().any(|&b| b != 0) {
+            gt[(index % GTES_PER_GT) as usize] = sector_u32(stream.sector)?;
+            stream.write_grain(index * GRAIN_SECTORS, &grain)?;
+        }
+        if index % GTES_PER_GT == GTES_PER_GT - 1 || index == grains - 1 {
+            if gt.iter().any(|&e| e != 0) {
+                let bytes: Vec<u8> = gt.iter().flat_map(|e| e.to_le_bytes()).collect();
+                gd[(index / GTES_PER_GT) as usize] = sector_u32(stream.sector + 1)?;
+                stream.write_metadata(MARKER_GT, &bytes)?;
+            }
+            gt.fill(0);
+        }
+    }
+
+    let bytes: Vec<u8> = gd.iter().flat_map(|e| e.to_le_bytes()).collect();
+    header.gd_offset = stream.sector + 1;
+    stream.write_metadata(MARKER_GD, &bytes)?;
+    stream.write_metadata(MARKER_FOOTER, &encode_header(&header))?;
+    // End-of-stream marker.
+    stream.write(&[0; 512])?;
+    stream.out.flush()?;
+    Ok(())
+}
+
+/// Sector-granular writer tracking the current output position.
+struct Stream<'a, W: Write> {
+    out: &'a mut W,
+    sector: u64,
+}
+
+impl<W: Write> Stream<'_, W> {
+    /// Write `data`, zero-padded to a whole number of sectors.
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.out.write_all(data)?;
+        let padding = data.len().next_multiple_of(512) - data.len();
+        self.out.write_all(&vec![0; padding])?;
+        self.sector += data.len().div_ceil(512) as u64;
+        Ok(())
+    }
+
+    fn pad_to(&mut self, sector: u64) -> Result<()> {
+        let sectors = sector.saturating_sub(self.sector);
+        self.write(&vec![0; (sectors * 512) as usize])
+    }
+
+    /// Write a grain marker holding the compressed contents of the grain at guest `lba`.
+    fn write_grain(&mut self, lba: u64, grain: &[u8]) -> Result<()> {
+        let mut encoder = ZlibEncoder::new(Vec::with_capacity(grain.len()), Compression::default());
+        encoder.write_all(grain)?;
+        let compressed = encoder.finish()?;
+        let mut record = Vec::with_capacity(12 + compressed.len());
+        record.extend_from_slice(&lba.to_le_bytes());
+        record.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        record.extend_from_slice(&compressed);
+        self.write(&record)
+    }
+
+    /// Write a metadata marker of the given type followed by its sector-aligned payload.
+    fn write_metadata(&mut self, kind: u32, data: &[u8]) -> Result<()> {
+        let mut marker = [0u8; 512];
+        marker[0..8].copy_from_slice(&(data.len().div_ceil(512) as u64).to_le_bytes());
+        marker[12..16].copy_from_slice(&kind.to_le_bytes());
+        self.write(&marker)?;
+        self.write(data)
+    }
+}
+
+fn sector_u32(sector: u64) -> Result<u32> {
+    u32::try_from(sector).context("vmdk is too large for 32-bit grain table entries")
+}
+
+fn encode_header(header: &SparseHeader) -> [u8; 512] {
+    let mut b = [0u8; 512];
+    b[0..4].copy_from_slice(SPARSE_MAGIC);
+    b[4..8].copy_from_slice(&header.version.to_le_bytes());
+    b[8..12].copy_from_slice(&header.flags.to_le_bytes());
+    b[12..20].copy_from_slice(&header.capacity.to_le_bytes());
+    b[20..28].copy_from_slice(&header.grain_size.to_le_bytes());
+    b[28..36].copy_from_slice(&header.descriptor_offset.to_le_bytes());
+    b[36..44].copy_from_slice(&header.descriptor_size.to_le_bytes());
+    b[44..48].copy_from_slice(&header.num_gtes_per_gt.to_le_bytes());
+    b[48..56].copy_from_slice(&header.rgd_offset.to_le_bytes());
+    b[56..64].copy_from_slice(&header.gd_offset.to_le_bytes());
+    b[64..72].copy_from_slice(&header.overhead.to_le_bytes());
+    b[72] = u8::from(header.unclean_shutdown);
+    // Characters used to detect line-ending corruption in FTP-style transfers.
+    b[73..77].copy_from_slice(b"\n \r\n");
+    b[77..79].copy_from_slice(&header.compress_algorithm.to_le_bytes());
+    b
+}
+
+fn stream_descriptor(capacity: u64, file_name: &str, adapter: AdapterType) -> Descriptor {
+    let (heads, sectors) = adapter.geometry();
+    let max_cylinders = if adapter == AdapterType::Ide {
+        16383
+    } else {
+        65535
+    };
+    let cylinders = (capacity / (heads * sectors)).clamp(1, max_cylinders);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let ddb: BTreeMap<String, String> = [
+        ("ddb.adapterType", adapter.as_str().to_string()),
+        ("ddb.geometry.cylinders", cylinders.to_string()),
+        ("ddb.geometry.heads", heads.to_string()),
+        ("ddb.geometry.sectors", sectors.to_string()),
+        ("ddb.virtualHWVersion", "4".to_string()),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect();
+    Descriptor {
+        version: 1,
+        cid: (now.as_secs() as u32) ^ now.subsec_nanos(),
+        parent_cid: NO_PARENT_CID,
+        create_type: "streamOptimized".to_string(),
+        extents: vec![ExtentDescriptor {
+            access: "RW".to_string(),
+            sectors: capacity,
+            kind: ExtentType::Sparse,
+            file: Some(file_name.to_string()),
+            offset: 0,
+        }],
+        ddb,
+    }
+}
@@ -0,0 +1,92 @@
+//! Reports the metadata of described AMIs.
+
+use aws_sdk_ec2::types::{
+    ArchitectureValues, BlockDeviceMapping, BootModeValues, EbsBlockDevice, Image, ImageState, Tag,
+    VolumeType,
+};
+
+use vmi::ami::validate_ami_id;
+use vmi::inspect::{ami_metadata, DiskReport, SCHEMA_VERSION};
+
+fn image() -> Image {
+    Image::builder()
+        .image_id("ami-0123456789abcdef0")
+        .name("debian-12")
+        .owner_id("136693071363")
+        .creation_date("2024-06-01T00:00:00.000Z")
+        .state(ImageState::Available)
+        .architecture(ArchitectureValues::Arm64)
+        .boot_mode(BootModeValues::Uefi)
+        .ena_support(true)
+        .root_device_name("/dev/xvda")
+        .block_device_mappings(
+            BlockDeviceMapping::builder()
+                .device_name("/dev/xvda")
+                .ebs(
+                    EbsBlockDevice::builder()
+                        .snapshot_id("snap-0123456789abcdef0")
+                        .volume_size(8)
+                        .volume_type(VolumeType::Gp3)
+                        .encrypted(true)
+                        .build(),
+                )
+                .build(),
+        )
+        .block_device_mappings(
+            BlockDeviceMapping::builder()
+                .device_name("/dev/sdb")
+                .virtual_name("ephemeral0")
+                .build(),
+        )
+        .tags(Tag::builder().key("team").value("images").build())
+        .build()
+}
+
+#[test]
+fn validates_ami_ids() {
+    assert!(validate_ami_id("ami-12345678").is_ok());
+    assert!(validate_ami_id("ami-0123456789abcdef0").is_ok());
+    for invalid in [
+        "ami-1234",
+        "ami-0123456789ABCDEF0",
+        "snap-12345678",
+        "ami-1234567g",
+    ] {
+        assert!(validate_ami_id(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn reports_image_and_mappings() {
+    let cloud = ami_metadata(&image(), Some("eu-west-1".to_string()));
+    assert_eq!(cloud.provider, "aws");
+    assert_eq!(cloud.architecture.as_deref(), Some("arm64"));
+    assert_eq!(cloud.boot_mode.as_deref(), Some("uefi"));
+    assert_eq!(cloud.tags["team"], "images");
+    assert_eq!(cloud.block_devices.len(), 2);
+    let root = &cloud.block_devices[0];
+    assert_eq!(root.volume_size, Some(8 << 30));
+    assert_eq!(root.volume_type.as_deref(), Some("gp3"));
+    assert_eq!(root.encrypted, Some(true));
+    assert_eq!(
+        cloud.block_devices[1].virtual_name.as_deref(),
+        Some("ephemeral0")
+    );
+
+    let report = DiskReport {
+        schema_version: SCHEMA_VERSION,
+        format: "ami".to_string(),
+        virtual_size: 8 << 30,
+        allocated_size: None,
+        backing_file: None,
+        partition_table: None,
+        filesystem: None,
+        cloud: Some(cloud),
+        appliance: None,
+    };
+    let text = report.to_string();
+    assert!(text.contains("Image:          ami-0123456789abcdef0 (debian-12)"));
+    assert!(text.contains("/dev/xvda snap-0123456789abcdef0 8.0 GiB gp3 encrypted"));
+    assert!(text.contains("/dev/sdb instance store ephemeral0"));
+    assert!(!text.contains("Partition table"));
+}
@@ -0,0 +1,70 @@
+//! Picks the EBS volumes of AMIs out of their block device mappings.
+
+use aws_sdk_ec2::types::{BlockDeviceMapping, EbsBlockDevice, Image};
+
+use vmi::ami::{image_volumes, select_volume, AmiVolume, VolumeSelector};
+
+fn ebs(device_name: &str, snapshot_id: &str) -> BlockDeviceMapping {
+    BlockDeviceMapping::builder()
+        .device_name(device_name)
+        .ebs(EbsBlockDevice::builder().snapshot_id(snapshot_id).build())
+        .build()
+}
+
+/// An AMI whose root volume is mapped after a data volume, with instance store and
+/// suppressed mappings in between.
+fn image() -> Image {
+    Image::builder()
+        .root_device_name("/dev/xvda")
+        .block_device_mappings(ebs("/dev/sdb", "snap-data"))
+        .block_device_mappings(
+            BlockDeviceMapping::builder()
+                .device_name("/dev/sdc")
+                .virtual_name("ephemeral0")
+                .build(),
+        )
+        .block_device_mappings(
+            BlockDeviceMapping::builder()
+                .device_name("/dev/sdd")
+                .no_device("")
+                .build(),
+        )
+        .block_device_mappings(ebs("/dev/xvda", "snap-root"))
+        .build()
+}
+
+fn volume(device_name: &str, snapshot_id: &str, root: bool) -> AmiVolume {
+    AmiVolume {
+        device_name: device_name.to_string(),
+        snapshot_id: snapshot_id.to_string(),
+        root,
+    }
+}
+
+#[test]
+fn skips_mappings_without_snapshots() {
+    assert_eq!(
+        image_volumes(&image()),
+        [
+            volume("/dev/sdb", "snap-data", false),
+            volume("/dev/xvda", "snap-root", true),
+        ]
+    );
+}
+
+#[test]
+fn selects_root_index_or_device() {
+    let volumes = image_volumes(&image());
+    let select = |selector: Option<&str>| {
+        let selector = selector.map(|s| s.parse::<VolumeSelector>().unwrap());
+        select_volume(&volumes, selector.as_ref()).map(|v| v.snapshot_id.as_str())
+    };
+    assert_eq!(select(None).unwrap(), "snap-root");
+    assert_eq!(select(Some("0")).unwrap(), "snap-data");
+    assert_eq!(select(Some("/dev/xvda")).unwrap(), "snap-root");
+    assert_eq!(select(Some("sdb")).unwrap(), "snap-data");
+    let err = select(Some("/dev/sdc")).unwrap_err().to_string();
+    assert_eq!(err, "device /dev/sdc not found among /dev/sdb, /dev/xvda");
+    assert!(select(Some("2")).is_err());
+    assert!(select_volume(&[], None).is_err());
+}
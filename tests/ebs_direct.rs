@@ -0,0 +1,363 @@
+//! Reads and writes snapshots through a local stand-in for the EBS direct APIs.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use aws_credential_types::provider::SharedCredentialsProvider;
+use aws_credential_types::Credentials;
+use aws_smithy_types::base64;
+use sha2::{Digest, Sha256};
+use vmi::disk::{Extent, ExtentKind, RawDisk, VirtualDisk};
+use vmi::ebs::{write_snapshot, EbsClient, EbsSnapshot, SnapshotOptions};
+use vmi::raw::write_sparse_raw;
+
+const SNAPSHOT_ID: &str = "snap-0123456789abcdef0";
+const BLOCK_SIZE: u64 = 512 << 10;
+const VOLUME_GIB: u64 = 1;
+
+/// An EBS direct APIs stand-in holding one snapshot.
+#[derive(Default)]
+struct StandIn {
+    blocks: Mutex<BTreeMap<u64, Vec<u8>>>,
+    page_size: usize,
+    corrupt: Option<u64>,
+    throttled: AtomicBool,
+    completed: AtomicBool,
+}
+
+struct Request {
+    method: String,
+    target: String,
+    headers: BTreeMap<String, String>,
+    body: Vec<u8>,
+}
+
+type Response = (u16, Vec<(&'static str, String)>, Vec<u8>);
+
+fn error(status: u16, message: &str) -> Response {
+    let body = format!("{{\"Message\":\"{message}\"}}");
+    (status, Vec::new(), body.into_bytes())
+}
+
+fn block_data(index: u64) -> Vec<u8> {
+    (0..BLOCK_SIZE)
+        .map(|i| (i * 7 + index * 13 + 1) as u8)
+        .collect()
+}
+
+impl StandIn {
+    fn new(indices: &[u64]) -> Self {
+        let blocks = indices.iter().map(|&i| (i, block_data(i))).collect();
+        StandIn {
+            blocks: Mutex::new(blocks),
+            page_size: 2,
+            ..Default::default()
+        }
+    }
+
+    /// Serve on a local port, returning its endpoint.
+    fn serve(self: &Arc<Self>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let stand_in = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stand_in = stand_in.clone();
+                thread::spawn(move || stand_in.handle(stream.unwrap()));
+            }
+        });
+        endpoint
+    }
+
+    fn handle(&self, stream: TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = request_line.split(' ');
+            let mut request = Request {
+                method: parts.next().unwrap().to_string(),
+                target: parts.next().unwrap().to_string(),
+                headers: BTreeMap::new(),
+                body: Vec::new(),
+            };
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim_end().is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_once(':').unwrap();
+                request
+                    .headers
+                    .insert(name.to_ascii_lowercase(), value.trim().to_string());
+            }
+            if let Some(len) = request.headers.get("content-length") {
+                request.body = vec![0; len.parse().unwrap()];
+                reader.read_exact(&mut request.body).unwrap();
+            }
+
+            let signed = request
+                .headers
+                .get("authorization")
+                .is_some_and(|a| a.contains("/us-east-1/ebs/aws4_request"));
+            let (status, headers, body) = if signed {
+                self.respond(&request)
+            } else {
+                error(403, "unsigned")
+            };
+            let mut response = format!("HTTP/1.1 {status} X\r\nContent-Length: {}\r\n", body.len());
+            for (name, value) in headers {
+                response.push_str(&format!("{name}: {value}\r\n"));
+            }
+            response.push_str("\r\n");
+            stream.write_all(response.as_bytes()).unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    }
+
+    fn respond(&self, request: &Request) -> Response {
+        let target = request.target.as_str();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let params: BTreeMap<&str, &str> =
+            query.split('&').filter_map(|p| p.split_once('=')).collect();
+        if (request.method.as_str(), path) == ("POST", "/snapshots") {
+            return self.start_snapshot(request);
+        }
+        if path == format!("/snapshots/completion/{SNAPSHOT_ID}") {
+            return self.complete_snapshot(request);
+        }
+        let prefix = format!("/snapshots/{SNAPSHOT_ID}/blocks");
+        let Some(rest) = path.strip_prefix(&prefix) else {
+            return error(404, "no such snapshot");
+        };
+        if rest.is_empty() {
+            return self.list_snapshot_blocks(&params);
+        }
+        let index: u64 = rest.trim_start_matches('/').parse().unwrap();
+        if request.method == "PUT" {
+            return self.put_snapshot_block(index, request);
+        }
+
+        // GetSnapshotBlock, throttled once.
+        if !self.throttled.swap(true, Ordering::SeqCst) {
+            let headers = vec![("x-amzn-ErrorType", "ThrottlingException".to_string())];
+            return (400, headers, b"{}".to_vec());
+        }
+        let token = format!("tok%2B{index}%2F%3D");
+        let blocks = self.blocks.lock().unwrap();
+        let data = match blocks.get(&index) {
+            Some(data) if params.get("blockToken") == Some(&token.as_str()) => data,
+            _ => return error(400, "bad token"),
+        };
+        let mut checksum = Sha256::digest(data).to_vec();
+        if self.corrupt == Some(index) {
+            checksum[0] ^= 1;
+        }
+        let headers = vec![
+            ("x-amz-Data-Length", data.len().to_string()),
+            ("x-amz-Checksum", base64::encode(checksum)),
+            ("x-amz-Checksum-Algorithm", "SHA256".to_string()),
+        ];
+        (200, headers, data.clone())
+    }
+
+    /// ListSnapshotBlocks, paged to exercise continuation tokens.
+    fn list_snapshot_blocks(&self, params: &BTreeMap<&str, &str>) -> Response {
+        let blocks = self.blocks.lock().unwrap();
+        let start: usize = params
+            .get("pageToken")
+            .map_or(0, |t| t.strip_prefix("page-").unwrap().parse().unwrap());
+        let page: Vec<_> = blocks
+            .keys()
+            .skip(start)
+            .take(self.page_size)
+            .map(|i| format!("{{\"BlockIndex\":{i},\"BlockToken\":\"tok+{i}/=\"}}"))
+            .collect();
+        let next = start + self.page_size;
+        let next_token = if next < blocks.len() {
+            format!(",\"NextToken\":\"page-{next}\"")
+        } else {
+            String::new()
+        };
+        let body = format!(
+            "{{\"Blocks\":[{}],\"BlockSize\":{BLOCK_SIZE},\"VolumeSize\":{VOLUME_GIB},\"ExpiryTime\":1.7e9{next_token}}}",
+            page.join(",")
+        );
+        (200, Vec::new(), body.into_bytes())
+    }
+
+    fn start_snapshot(&self, request: &Request) -> Response {
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        if body["VolumeSize"] != VOLUME_GIB
+            || !body["ClientToken"].is_string()
+            || body["Tags"] != serde_json::json!([{"Key": "vmi", "Value": "test"}])
+        {
+            return error(400, "bad StartSnapshot request");
+        }
+        let body = format!(
+            "{{\"SnapshotId\":\"{SNAPSHOT_ID}\",\"BlockSize\":{BLOCK_SIZE},\"Status\":\"pending\"}}"
+        );
+        (201, Vec::new(), body.into_bytes())
+    }
+
+    fn put_snapshot_block(&self, index: u64, request: &Request) -> Response {
+        let header = |name: &str| request.headers.get(&name.to_ascii_lowercase()).cloned();
+        if header("x-amz-Data-Length") != Some(request.body.len().to_string())
+            || header("x-amz-Checksum") != Some(base64::encode(Sha256::digest(&request.body)))
+            || header("x-amz-Checksum-Algorithm").as_deref() != Some("SHA256")
+        {
+            return error(400, "bad checksum");
+        }
+        self.blocks
+            .lock()
+            .unwrap()
+            .insert(index, request.body.clone());
+        (201, Vec::new(), b"{}".to_vec())
+    }
+
+    fn complete_snapshot(&self, request: &Request) -> Response {
+        let blocks = self.blocks.lock().unwrap();
+        let mut linear = Sha256::new();
+        for data in blocks.values() {
+            linear.update(Sha256::digest(data));
+        }
+        let header = |name: &str| request.headers.get(&name.to_ascii_lowercase()).cloned();
+        if header("x-amz-ChangedBlocksCount") != Some(blocks.len().to_string())
+            || header("x-amz-Checksum") != Some(base64::encode(linear.finalize()))
+            || header("x-amz-Checksum-Aggregation-Method").as_deref() != Some("LINEAR")
+        {
+            return error(400, "bad linear checksum");
+        }
+        self.completed.store(true, Ordering::SeqCst);
+        (202, Vec::new(), b"{\"Status\":\"completed\"}".to_vec())
+    }
+}
+
+fn client(endpoint: &str) -> EbsClient {
+    let credentials = Credentials::new("AKIDEXAMPLE", "secret", None, None, "test");
+    EbsClient::new(
+        endpoint,
+        "us-east-1",
+        SharedCredentialsProvider::new(credentials),
+    )
+}
+
+fn scratch_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("vmi-{}-{name}", std::process::id()))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn converts_snapshot_to_sparse_raw() {
+    let indices = [0, 1, 5, 2047];
+    let stand_in = Arc::new(StandIn::new(&indices));
+    let endpoint = stand_in.serve();
+
+    let snapshot = EbsSnapshot::open(client(&endpoint), SNAPSHOT_ID)
+        .await
+        .unwrap();
+    let mut snapshot = snapshot.with_concurrency(3);
+    assert_eq!(snapshot.size(), VOLUME_GIB << 30);
+    assert_eq!(snapshot.stored_size(), 4 * BLOCK_SIZE);
+    let extent = |block: u64, blocks: u64, kind| Extent {
+        offset: block * BLOCK_SIZE,
+        len: blocks * BLOCK_SIZE,
+        kind,
+    };
+    assert_eq!(
+        snapshot.extents().unwrap(),
+        vec![
+            extent(0, 2, ExtentKind::Data),
+            extent(2, 3, ExtentKind::Zero),
+            extent(5, 1, ExtentKind::Data),
+            extent(6, 2041, ExtentKind::Zero),
+            extent(2047, 1, ExtentKind::Data),
+        ]
+    );
+
+    let path = scratch_path("snapshot.raw");
+    tokio::task::block_in_place(|| write_sparse_raw(&mut snapshot, &path)).unwrap();
+    let out = File::open(&path).unwrap();
+    assert_eq!(out.metadata().unwrap().len(), VOLUME_GIB << 30);
+    let mut buf = vec![0u8; BLOCK_SIZE as usize];
+    for block in [0, 1, 2, 4, 5, 6, 1000, 2047] {
+        out.read_exact_at(&mut buf, block * BLOCK_SIZE).unwrap();
+        match indices.contains(&block) {
+            true => assert!(buf == block_data(block), "block {block} differs"),
+            false => assert!(buf.iter().all(|&b| b == 0), "block {block} is not zero"),
+        }
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rejects_blocks_with_bad_checksums() {
+    let mut stand_in = StandIn::new(&[3, 4]);
+    stand_in.corrupt = Some(4);
+    let endpoint = Arc::new(stand_in).serve();
+
+    let mut snapshot = EbsSnapshot::open(client(&endpoint), SNAPSHOT_ID)
+        .await
+        .unwrap();
+    let path = scratch_path("corrupt.raw");
+    let err = tokio::task::block_in_place(|| write_sparse_raw(&mut snapshot, &path)).unwrap_err();
+    assert!(
+        format!("{err:#}").contains("checksum mismatch for block 4"),
+        "{err:#}"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uploads_snapshot_with_linear_checksum() {
+    let stand_in = Arc::new(StandIn {
+        page_size: 100,
+        // Reads of the uploaded snapshot are throttled once too.
+        ..Default::default()
+    });
+    let endpoint = stand_in.serve();
+
+    // Blocks 0 and 3 hold data, block 1 is written but all zero, and the last block of the
+    // file is partial.
+    let path = scratch_path("upload.raw");
+    let file = File::create(&path).unwrap();
+    file.write_all_at(&block_data(0), 0).unwrap();
+    file.write_all_at(&vec![0; BLOCK_SIZE as usize], BLOCK_SIZE)
+        .unwrap();
+    file.write_all_at(&block_data(3)[..1000], 3 * BLOCK_SIZE + 10)
+        .unwrap();
+    drop(file);
+
+    let mut disk = RawDisk::open_path(&path).unwrap();
+    let options = SnapshotOptions {
+        description: Some("test".to_string()),
+        tags: vec![("vmi".to_string(), "test".to_string())],
+        concurrency: 2,
+    };
+    let client = client(&endpoint);
+    let snapshot_id =
+        tokio::task::block_in_place(|| write_snapshot(&mut disk, &client, &options)).unwrap();
+    assert_eq!(snapshot_id, SNAPSHOT_ID);
+    assert!(stand_in.completed.load(Ordering::SeqCst));
+    assert_eq!(
+        stand_in.blocks.lock().unwrap().keys().collect::<Vec<_>>(),
+        vec![&0, &3]
+    );
+
+    let mut snapshot = EbsSnapshot::open(client, SNAPSHOT_ID).await.unwrap();
+    let expected = std::fs::read(&path).unwrap();
+    let mut actual = vec![0; expected.len()];
+    tokio::task::block_in_place(|| snapshot.read_at(0, &mut actual)).unwrap();
+    assert!(actual == expected);
+    std::fs::remove_file(&path).unwrap();
+}
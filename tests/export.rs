@@ -0,0 +1,21 @@
+//! Parses the S3 destinations of exported AMIs.
+
+use vmi::export::S3Location;
+
+fn parse(url: &str) -> (String, String) {
+    let location: S3Location = url.parse().unwrap();
+    (location.bucket, location.prefix)
+}
+
+#[test]
+fn parses_bucket_and_prefix() {
+    let pair = |bucket: &str, prefix: &str| (bucket.to_string(), prefix.to_string());
+    assert_eq!(parse("s3://images"), pair("images", ""));
+    assert_eq!(parse("s3://images/"), pair("images", ""));
+    assert_eq!(parse("s3://images/exports"), pair("images", "exports/"));
+    assert_eq!(parse("s3://images/a/b/"), pair("images", "a/b/"));
+    assert!("https://images.s3.amazonaws.com/a"
+        .parse::<S3Location>()
+        .is_err());
+    assert!("s3:///prefix".parse::<S3Location>().is_err());
+}
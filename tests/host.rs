@@ -0,0 +1,87 @@
+//! Tells EC2 instances from other hosts by a fake sysfs.
+
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+
+use vmi::host::{dmi_evidence, ensure_ec2_host, DmiEvidence};
+use vmi::imds::ImdsClient;
+
+/// An empty filesystem root for one test.
+fn fake_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("vmi-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("sys/class/dmi/id")).unwrap();
+    root
+}
+
+fn write(root: &Path, path: &str, value: &str) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, format!("{value}\n")).unwrap();
+}
+
+/// A client of a local port nothing listens on.
+fn unreachable_imds() -> ImdsClient {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    ImdsClient::new(&address.to_string())
+}
+
+#[test]
+fn detects_nitro_and_xen_instances() {
+    let root = fake_root("host-dmi");
+    assert_eq!(dmi_evidence(&root), DmiEvidence::Unknown);
+
+    write(&root, "sys/class/dmi/id/sys_vendor", "QEMU");
+    write(
+        &root,
+        "sys/class/dmi/id/product_uuid",
+        "4c4c4544-0042-3010-8052-b4c04f4e4d32",
+    );
+    assert_eq!(dmi_evidence(&root), DmiEvidence::NotEc2);
+
+    // Little-endian Xen UUID.
+    write(
+        &root,
+        "sys/class/dmi/id/product_uuid",
+        "45E12AEC-DCD1-B213-94ED-012345ABCDEF",
+    );
+    assert_eq!(
+        dmi_evidence(&root),
+        DmiEvidence::Ec2("sys/class/dmi/id/product_uuid")
+    );
+
+    write(
+        &root,
+        "sys/hypervisor/uuid",
+        "ec2e1916-9099-7caf-fd21-012345abcdef",
+    );
+    assert_eq!(dmi_evidence(&root), DmiEvidence::Ec2("sys/hypervisor/uuid"));
+
+    write(&root, "sys/class/dmi/id/board_vendor", "Amazon EC2");
+    assert_eq!(
+        dmi_evidence(&root),
+        DmiEvidence::Ec2("sys/class/dmi/id/board_vendor")
+    );
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn refuses_other_hosts() {
+    let root = fake_root("host-other");
+    write(&root, "sys/class/dmi/id/sys_vendor", "QEMU");
+    let err = ensure_ec2_host(&root, &unreachable_imds())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("not an EC2 instance"), "{err:#}");
+
+    write(&root, "sys/class/dmi/id/sys_vendor", "Amazon EC2");
+    let err = ensure_ec2_host(&root, &unreachable_imds())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("does not answer"), "{err:#}");
+    fs::remove_dir_all(&root).unwrap();
+}
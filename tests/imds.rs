@@ -0,0 +1,164 @@
+//! Reads instance metadata from a local fake instance metadata service.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use vmi::imds::ImdsClient;
+
+const TOKEN: &str = "token-1";
+const DOCUMENT: &str = r#"{
+  "accountId" : "123456789012",
+  "architecture" : "x86_64",
+  "availabilityZone" : "eu-west-1b",
+  "imageId" : "ami-0123456789abcdef0",
+  "instanceId" : "i-0123456789abcdef0",
+  "instanceType" : "m6i.large",
+  "pendingTime" : "2024-01-01T00:00:00Z",
+  "region" : "eu-west-1"
+}"#;
+
+/// A fake instance metadata service.
+#[derive(Default)]
+struct FakeImds {
+    /// Answer token requests with 404, like an IMDSv1-only service.
+    v1_only: bool,
+    /// Number of requests failing with 503 before the service recovers.
+    unavailable: AtomicUsize,
+    tokens_issued: AtomicUsize,
+}
+
+impl FakeImds {
+    /// Serve on a local port, returning its address.
+    fn serve(self: &Arc<Self>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let imds = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let imds = imds.clone();
+                thread::spawn(move || imds.handle(stream.unwrap()));
+            }
+        });
+        address
+    }
+
+    fn handle(&self, mut stream: TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut parts = request_line.split(' ');
+        let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+        let mut headers = BTreeMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let Some((name, value)) = line.split_once(':') else {
+                break;
+            };
+            headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+        }
+
+        let (status, body) = self.respond(method, path, &headers);
+        let response = format!(
+            "HTTP/1.1 {status} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).unwrap();
+    }
+
+    fn respond(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &BTreeMap<String, String>,
+    ) -> (u16, &'static str) {
+        let unavailable = self
+            .unavailable
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if unavailable.is_ok() {
+            return (503, "");
+        }
+        if (method, path) == ("PUT", "/latest/api/token") {
+            if self.v1_only {
+                return (404, "");
+            }
+            assert_eq!(headers["x-aws-ec2-metadata-token-ttl-seconds"], "21600");
+            self.tokens_issued.fetch_add(1, Ordering::SeqCst);
+            return (200, TOKEN);
+        }
+        let authorized = headers.get("x-aws-ec2-metadata-token").map(String::as_str) == Some(TOKEN);
+        if !self.v1_only && !authorized {
+            return (401, "");
+        }
+        match path {
+            "/latest/meta-data/instance-id" => (200, "i-0123456789abcdef0"),
+            "/latest/meta-data/placement/availability-zone" => (200, "eu-west-1b"),
+            "/latest/dynamic/instance-identity/document" => (200, DOCUMENT),
+            _ => (404, ""),
+        }
+    }
+}
+
+fn client(address: &str) -> ImdsClient {
+    ImdsClient::new(address).with_timeout(Duration::from_secs(5))
+}
+
+#[tokio::test]
+async fn caches_session_tokens() {
+    let imds = Arc::new(FakeImds::default());
+    let client = client(&imds.serve());
+    assert_eq!(client.instance_id().await.unwrap(), "i-0123456789abcdef0");
+    assert_eq!(client.availability_zone().await.unwrap(), "eu-west-1b");
+    let document = client.identity_document().await.unwrap();
+    assert_eq!(document.account_id, "123456789012");
+    assert_eq!(document.instance_type, "m6i.large");
+    assert_eq!(client.region().await.unwrap(), "eu-west-1");
+    assert_eq!(imds.tokens_issued.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn falls_back_to_imdsv1() {
+    let imds = Arc::new(FakeImds {
+        v1_only: true,
+        ..Default::default()
+    });
+    let client = client(&imds.serve());
+    assert_eq!(client.instance_id().await.unwrap(), "i-0123456789abcdef0");
+    assert_eq!(imds.tokens_issued.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn retries_unavailable_service() {
+    let imds = Arc::new(FakeImds {
+        unavailable: AtomicUsize::new(2),
+        ..Default::default()
+    });
+    let client = client(&imds.serve());
+    assert_eq!(client.instance_id().await.unwrap(), "i-0123456789abcdef0");
+    assert_eq!(imds.tokens_issued.load(Ordering::SeqCst), 1);
+
+    imds.unavailable.store(3, Ordering::SeqCst);
+    let err = client.availability_zone().await.unwrap_err();
+    assert!(format!("{err:#}").contains("503"), "{err:#}");
+}
+
+#[test]
+fn normalizes_endpoints() {
+    assert_eq!(
+        ImdsClient::new("fd00:ec2::254").endpoint(),
+        "http://[fd00:ec2::254]"
+    );
+    assert_eq!(
+        ImdsClient::new("http://169.254.169.254/").endpoint(),
+        "http://169.254.169.254"
+    );
+    assert_eq!(
+        ImdsClient::new("localhost:1338").endpoint(),
+        "http://localhost:1338"
+    );
+}
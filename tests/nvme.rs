@@ -0,0 +1,72 @@
+//! Finds attached EBS volumes in a fake sysfs and devfs.
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use vmi::nvme::{ebs_serial, find_attached_device, EBS_MODEL};
+
+const VOLUME_ID: &str = "vol-0123456789abcdef0";
+
+/// An empty filesystem root for one test.
+fn fake_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("vmi-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("dev")).unwrap();
+    root
+}
+
+/// Add an NVMe namespace whose controller has the given serial and model.
+fn add_nvme(root: &Path, name: &str, serial: &str, model: &str) {
+    let controller = root.join("sys/class/nvme").join(&name[..name.len() - 2]);
+    fs::create_dir_all(&controller).unwrap();
+    // sysfs pads both fields with spaces.
+    fs::write(controller.join("serial"), format!("{serial:<20}\n")).unwrap();
+    fs::write(controller.join("model"), format!("{model:<40}\n")).unwrap();
+    let namespace = root.join("sys/block").join(name);
+    fs::create_dir_all(&namespace).unwrap();
+    symlink(&controller, namespace.join("device")).unwrap();
+    fs::write(root.join("dev").join(name), "").unwrap();
+}
+
+fn find(root: &Path, requested: &str) -> Option<PathBuf> {
+    find_attached_device(root, VOLUME_ID, Path::new(requested)).unwrap()
+}
+
+#[test]
+fn finds_nvme_namespace_by_serial() {
+    let root = fake_root("nvme-sysfs");
+    add_nvme(&root, "nvme0n1", "vol0fffffffffffffff0", EBS_MODEL);
+    add_nvme(&root, "nvme1n1", &ebs_serial(VOLUME_ID), "Some Other SSD");
+    add_nvme(&root, "nvme2n1", &ebs_serial(VOLUME_ID), EBS_MODEL);
+    assert_eq!(find(&root, "/dev/sdf"), Some(root.join("dev/nvme2n1")));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn falls_back_to_disk_by_id() {
+    let root = fake_root("nvme-by-id");
+    fs::write(root.join("dev/nvme3n1"), "").unwrap();
+    fs::create_dir_all(root.join("dev/disk/by-id")).unwrap();
+    symlink(
+        "../../nvme3n1",
+        root.join("dev/disk/by-id/nvme-Amazon_Elastic_Block_Store_vol0123456789abcdef0"),
+    )
+    .unwrap();
+    assert_eq!(
+        find(&root, "/dev/sdf"),
+        Some(fs::canonicalize(root.join("dev/nvme3n1")).unwrap())
+    );
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn prefers_requested_then_xen_names() {
+    let root = fake_root("nvme-names");
+    assert_eq!(find(&root, "/dev/sdf"), None);
+    fs::write(root.join("dev/xvdf"), "").unwrap();
+    assert_eq!(find(&root, "/dev/sdf"), Some(root.join("dev/xvdf")));
+    fs::write(root.join("dev/sdf"), "").unwrap();
+    assert_eq!(find(&root, "/dev/sdf"), Some(root.join("dev/sdf")));
+    fs::remove_dir_all(&root).unwrap();
+}